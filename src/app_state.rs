@@ -1,119 +1,409 @@
 use std::{
     fs,
-    future::Future,
     num::NonZeroUsize,
-    path::PathBuf,
-    sync::Arc,
-    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock as StdRwLock,
+    },
+    time::Duration,
 };
 
-use std::env;
-use handlebars::Handlebars;
-use lru::LruCache;
-use notify::{Config, Event, RecursiveMode, RecommendedWatcher, Result as NotifyResult, Watcher, EventKind};
+use handlebars::{template::TemplateElement, Context, Handlebars, RenderContext, RenderError, Renderable, StringOutput, Template};
+use mrml::mjml::Mjml;
+use mrml::prelude::parser::ParserOptions;
+use mrml::prelude::render::RenderOptions;
+use notify::{Config, Event, RecursiveMode, RecommendedWatcher, Watcher};
+use serde::Serialize;
+use serde_json::Value;
 
-use tokio::sync::RwLock;
 use tokio::time::interval;
 use tokio::sync::mpsc::channel;
+use tokio::sync::{Mutex as AsyncMutex, MutexGuard};
 use tokio::fs::read_to_string;
 
-use tracing::{info, debug, error};
+use tracing::{info, error};
 
+use crate::config::ServerConfig;
+use crate::dependency_graph::DependencyGraph;
+use crate::escaping;
+use crate::helpers::{self, timezone::ZoneLoader};
+use crate::includes::Includes;
+use crate::locales::{catalog_locale, Catalogs, LocaleTag, Translate};
+use crate::missing_variables::{self, MissingVariables};
+use crate::partials::{load_partial, partial_name, record_partials, register_partials};
+use crate::render_options::resolve_render_options;
+use crate::schema::{load_schema, schema_id, Schema};
+use crate::source_map::{self, SourceLocation};
+use crate::template_cache::TemplateCache;
+use crate::template_id::TemplateId;
 use crate::template_watcher::watch_templates;
+use crate::variables::{template_variables, TemplateVariables};
 
 #[derive(Clone)]
 pub struct AppState {
-    pub handlebars: Handlebars<'static>,
-    template_cache: Arc<RwLock<LruCache<String, CachedTemplate>>>,
+    /// Shared registry for helpers, settings and the partials and layouts of
+    /// the template directory. Cached templates are not registered in it,
+    /// they render against it from `CompiledTemplate`.
+    pub handlebars: Arc<StdRwLock<Handlebars<'static>>>,
+    /// Same registry in strict mode, for `MissingVariables::Strict`.
+    pub strict_handlebars: Arc<StdRwLock<Handlebars<'static>>>,
+    template_cache: Arc<TemplateCache<Arc<CompiledTemplate>>>,
+    /// Names [`has_template`](AppState::has_template) found no file for, so
+    /// a locale without a variant doesn't cost a disk lookup per request.
+    missing_templates: Arc<TemplateCache<()>>,
+    /// Bumped by every [`file_changed`](AppState::file_changed), so a lookup
+    /// that raced with a new file isn't remembered as missing.
+    file_changes: Arc<AtomicU64>,
+    /// Files read through `<mj-include>`.
+    pub includes: Arc<Includes>,
+    /// Which partials and includes each template used, for invalidation.
+    pub dependencies: Arc<DependencyGraph>,
+    /// Message catalogs of `locales/`, used by `{{t}}`.
+    pub catalogs: Arc<Catalogs>,
+    /// Held while the template API checks and writes a file, so concurrent
+    /// writers can't both pass the same `If-Match`.
+    template_writes: Arc<AsyncMutex<()>>,
     pub template_dir: PathBuf, // Store the template directory
+    pub config: Arc<ServerConfig>, // Server-wide defaults loaded at startup
 }
 
-struct CachedTemplate {
-    pub content: String,
-    pub last_accessed: Instant,
+/// Where [`AppState::fetch_template`] found a template.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheLayer {
+    /// Already compiled in the template cache.
+    Memory,
+    /// Read from the template directory and compiled.
+    Disk,
+}
+
+/// A template ready to render, shared by every request that hits the cache.
+pub struct CompiledTemplate {
+    template: Template,
+    /// What `template` was compiled from, to point MJML errors into it.
+    source: String,
+    /// Resolves `<mj-include>` when the expanded MJML is parsed.
+    pub parser_options: ParserOptions,
+    /// Sidecar `<template>.schema.json` payloads are validated against.
+    pub schema: Option<Schema>,
+    /// Set when the template has no Handlebars expressions, so every render
+    /// produces the same output.
+    pub static_render: Option<StaticRender>,
+}
+
+/// Artifacts of a template without dynamic content, built once at load time.
+pub struct StaticRender {
+    pub mjml: Arc<Mjml>,
+    /// HTML rendered with the server-wide render options.
+    pub html: String,
+}
+
+impl CompiledTemplate {
+    /// Expands the Handlebars template with `data`, using the helpers and
+    /// settings of `handlebars`.
+    pub fn render_handlebars(&self, handlebars: &Handlebars<'static>, data: &Value) -> Result<String, RenderError> {
+        let context = Context::wraps(data)?;
+        let mut render_context = RenderContext::new(self.template.name.as_ref());
+        let mut output = StringOutput::new();
+        self.template.render(handlebars, &context, &mut render_context, &mut output)?;
+        output.into_string().map_err(RenderError::from)
+    }
+
+    /// What the template was compiled from.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The payload paths the template and its partials use.
+    pub fn variables(&self, handlebars: &Handlebars<'static>) -> TemplateVariables {
+        template_variables(handlebars, &self.template)
+    }
+
+    /// Where in the template, or in one of its partials, byte `offset` of
+    /// the MJML it expanded to with `data` comes from.
+    pub fn locate(&self, handlebars: &Handlebars<'static>, template_dir: &Path, data: &Value, offset: usize) -> Option<SourceLocation> {
+        source_map::locate(handlebars, template_dir, &self.template, &self.source, data, offset)
+    }
 }
 
 impl AppState {
+    #[cfg(test)]
     pub fn new(cache_capacity: usize, template_dir: PathBuf) -> Self {
+        Self::with_config(cache_capacity, template_dir, ServerConfig::default())
+    }
+
+    pub fn with_config(cache_capacity: usize, template_dir: PathBuf, config: ServerConfig) -> Self {
+        let capacity = NonZeroUsize::new(cache_capacity).unwrap();
+        let dependencies = Arc::new(DependencyGraph::default());
+        let mut handlebars = Handlebars::new();
+        let mut strict_handlebars = Handlebars::new();
+        missing_variables::configure(&mut handlebars, &mut strict_handlebars);
+        escaping::configure(&mut handlebars);
+        escaping::configure(&mut strict_handlebars);
+        let zones = Arc::new(ZoneLoader::new(config.helpers.zoneinfo_dir.clone()));
+        helpers::register(&mut handlebars, &config.helpers, &zones);
+        helpers::register(&mut strict_handlebars, &config.helpers, &zones);
+        let default_locale = LocaleTag::new(&config.helpers.default_locale).ok();
+        let catalogs = Arc::new(Catalogs::new(template_dir.clone(), default_locale));
+        for registry in [&mut handlebars, &mut strict_handlebars] {
+            registry.register_helper("t", Box::new(Translate { catalogs: catalogs.clone() }));
+        }
         AppState {
-            handlebars: Handlebars::new(),
-            template_cache: Arc::new(RwLock::new(LruCache::new(NonZeroUsize::new(cache_capacity).unwrap()))),
+            handlebars: Arc::new(StdRwLock::new(handlebars)),
+            strict_handlebars: Arc::new(StdRwLock::new(strict_handlebars)),
+            template_cache: Arc::new(TemplateCache::new(capacity)),
+            missing_templates: Arc::new(TemplateCache::new(capacity)),
+            file_changes: Arc::new(AtomicU64::new(0)),
+            includes: Arc::new(Includes::new(template_dir.clone(), capacity, dependencies.clone())),
+            dependencies,
+            catalogs,
+            template_writes: Arc::new(AsyncMutex::new(())),
             template_dir,
+            config: Arc::new(config),
         }
     }
 
-    pub async fn get_template(&self, path: &str) -> Result<String, String> {
-        let mut cache = self.template_cache.write().await;
-        if let Some(cached) = cache.get_mut(path) {
-            cached.last_accessed = Instant::now();
-            Ok(cached.content.clone())
-        } else {
-            // Load the template from disk
-            let template_content = read_to_string(path).await
-                .map_err(|e| format!("Failed to read template file {}: {}", path, e))?;
-            // Store the template in the cache
-            cache.put(path.to_string(), CachedTemplate {
-                content: template_content.clone(), // Store a clone of the content
-                last_accessed: Instant::now(),
-            });
-            info!("New Template cached.  {} templates cached.", cache.len());
-            Ok(template_content) // Return the template content
-        }
-    }
-
-    pub async fn insert_template(&self, path: String, content: String) {
-        let mut cache = self.template_cache.write().await;
-        cache.put(path, CachedTemplate {
-            content,
-            last_accessed: Instant::now(),
-        });
+    /// Whether `id` is compiled in the template cache. Cached templates were
+    /// checked to be inside the template directory when they were loaded,
+    /// and leave the cache when their file changes.
+    pub fn is_cached(&self, id: &TemplateId) -> bool {
+        self.template_cache.contains_key(id)
     }
 
-    pub async fn clean_old_templates(&self, max_age: Duration) {
-        let mut cache = self.template_cache.write().await;
-        let now = Instant::now(); // Capture the current time
-
-        let keys_to_remove: Vec<String> = cache
-            .iter()
-            .filter_map(|(key, template)| {
-                if now.duration_since(template.last_accessed) >= max_age {
-                    Some(key.clone())
-                } else {
-                    None
-                }
-            })
-            .collect();
+    /// Whether `id` is cached or exists on disk, without compiling it. Names
+    /// without a file are remembered until a file changes under them.
+    pub async fn has_template(&self, id: &TemplateId) -> bool {
+        if self.template_cache.get(id).is_some() {
+            return true;
+        }
+        if self.missing_templates.get(id).is_some() {
+            return false;
+        }
+        let changes = self.file_changes.load(Ordering::Acquire);
+        let exists = match id.resolve_in(&self.template_dir) {
+            Ok(path) => tokio::fs::metadata(path).await.is_ok_and(|metadata| metadata.is_file()),
+            Err(_) => false,
+        };
+        if !exists && self.file_changes.load(Ordering::Acquire) == changes {
+            self.missing_templates.insert(id.clone(), ());
+        }
+        exists
+    }
 
-        for key in keys_to_remove {
-            cache.pop(&key);
+    /// Fetches a compiled template from the cache, loading it on a miss, and
+    /// tells which of the two it came from.
+    pub async fn fetch_template(&self, id: &TemplateId) -> Result<(Arc<CompiledTemplate>, CacheLayer), String> {
+        if let Some(template) = self.template_cache.get(id) {
+            return Ok((template, CacheLayer::Memory));
         }
-        info!("Template cache cleaned.  {} templates cached.", cache.len());
+        // Load the template from disk
+        let path = id.resolve_in(&self.template_dir)?;
+        let template_content = read_to_string(&path).await
+            .map_err(|e| format!("Failed to read template file {}: {}", path.display(), e))?;
+        let template = Arc::new(self.compile_template(id, &template_content)?);
+        // Store the template in the cache
+        self.template_cache.insert(id.clone(), template.clone());
+        info!("New Template cached.  {} templates cached.", self.template_cache.len());
+        Ok((template, CacheLayer::Disk))
+    }
+
+    pub async fn insert_template(&self, id: TemplateId, content: String) -> Result<(), String> {
+        let template = Arc::new(self.compile_template(&id, &content)?);
+        self.template_cache.insert(id, template);
+        Ok(())
     }
 
-    pub async fn reload_template(&self, path: &str) -> Result<(), String> {
+    fn compile_template(&self, id: &TemplateId, source: &str) -> Result<CompiledTemplate, String> {
+        let default_options = resolve_render_options(&self.config.render_options, None);
+        // Static templates record their includes while being compiled.
+        self.dependencies.clear(id);
+        let mut compiled = compile_template(id.as_str(), source, &default_options, self.includes.parser_options(Some(id.clone())))?;
+        record_partials(&self.dependencies, id, &compiled.template);
+        // Depend on the schema even while it doesn't exist, so adding one takes effect.
+        self.dependencies.record(id, &schema_id(id));
+        compiled.schema = load_schema(&self.template_dir, id)?;
+        Ok(compiled)
+    }
+
+    /// Compiles an old revision of `id`, from its history, without caching
+    /// it or recording what it depends on.
+    pub fn compile_revision(&self, id: &TemplateId, source: &str) -> Result<CompiledTemplate, String> {
+        let default_options = resolve_render_options(&self.config.render_options, None);
+        let mut compiled = compile_template(id.as_str(), source, &default_options, self.includes.parser_options(None))?;
+        compiled.schema = load_schema(&self.template_dir, id)?;
+        Ok(compiled)
+    }
+
+    pub async fn clean_old_templates(&self, max_age: Duration) {
+        self.template_cache.remove_idle(max_age);
+        self.missing_templates.remove_idle(max_age);
+        info!("Template cache cleaned.  {} templates cached.", self.template_cache.len());
+    }
+
+    pub async fn reload_template(&self, id: &TemplateId) -> Result<(), String> {
+        let path = match id.resolve_in(&self.template_dir) {
+            Ok(path) => path,
+            Err(e) => {
+                // The file became a symlink out of the directory.
+                self.remove_template_from_cache(id).await?;
+                return Err(e);
+            }
+        };
         if let Ok(template_content) = read_to_string(path).await {
-            self.insert_template(path.to_string(), template_content).await;
-            info!("Template reloaded: {}", path);
+            if let Err(e) = self.insert_template(id.clone(), template_content).await {
+                // Don't keep serving the previous version of a broken template.
+                self.remove_template_from_cache(id).await?;
+                return Err(e);
+            }
+            info!("Template reloaded: {}", id);
             Ok(())
         } else {
-            Err(format!("Failed to reload template: {}", path))
+            Err(format!("Failed to reload template: {}", id))
+        }
+    }
+
+
+    /// The registry templates render against with `mode`.
+    pub fn registry(&self, mode: MissingVariables) -> &Arc<StdRwLock<Handlebars<'static>>> {
+        match mode {
+            MissingVariables::Strict => &self.strict_handlebars,
+            MissingVariables::Ignore | MissingVariables::Report => &self.handlebars,
+        }
+    }
+
+    /// Applies a change to both registries, which must stay identical apart
+    /// from strict mode.
+    fn with_registries<R>(&self, change: impl FnOnce(&mut [&mut Handlebars<'static>]) -> R) -> R {
+        let mut handlebars = self.handlebars.write().unwrap();
+        let mut strict_handlebars = self.strict_handlebars.write().unwrap();
+        change(&mut [&mut *handlebars, &mut *strict_handlebars])
+    }
+
+    /// Registers every partial and layout of the template directory.
+    pub fn register_partials(&self) -> usize {
+        self.with_registries(|registries| register_partials(registries, &self.template_dir, &self.dependencies))
+    }
+
+    /// Re-registers a partial or layout after its file changed. Templates
+    /// look partials up when they render, so the next render picks it up.
+    pub fn reload_partial(&self, id: &TemplateId, name: &str) -> Result<(), String> {
+        self.with_registries(|registries| {
+            let loaded = load_partial(registries, &self.template_dir, &self.dependencies, id, name);
+            if loaded.is_err() {
+                // Don't keep rendering the previous version of a broken partial.
+                for handlebars in registries.iter_mut() {
+                    handlebars.unregister_template(name);
+                }
+            }
+            loaded
+        })?;
+        info!("Partial reloaded: {}", id);
+        Ok(())
+    }
+
+    pub fn remove_partial(&self, id: &TemplateId, name: &str) {
+        self.with_registries(|registries| {
+            for handlebars in registries.iter_mut() {
+                handlebars.unregister_template(name);
+            }
+        });
+        info!("Partial {} unregistered.", id);
+    }
+
+    /// Serializes changes made to the template directory through the API.
+    pub async fn lock_writes(&self) -> MutexGuard<'_, ()> {
+        self.template_writes.lock().await
+    }
+
+    /// Brings everything built from the file `id` up to date after it was
+    /// written or deleted. Cached templates built from it are dropped and
+    /// compiled again on their next use. Partials and message catalogs,
+    /// which aren't cached, are reloaded. A removed file no longer uses
+    /// anything, so its own edges leave the dependency graph.
+    pub fn file_changed(&self, id: &TemplateId, removed: bool) -> Result<(), String> {
+        self.file_changes.fetch_add(1, Ordering::AcqRel);
+        self.missing_templates.remove(id);
+        self.invalidate_dependents(id);
+        if removed {
+            self.dependencies.clear(id);
+        }
+        if self.template_cache.remove(id).is_some() {
+            info!("Template {} removed from cache.", id);
+        }
+        if let Some(locale) = catalog_locale(id) {
+            if removed {
+                self.catalogs.remove(&locale);
+                return Ok(());
+            }
+            self.catalogs.reload(id, locale)?;
+            info!("Catalog reloaded: {}", id);
+            return Ok(());
         }
+        match partial_name(id) {
+            Some(name) if removed => self.remove_partial(id, name),
+            Some(name) => self.reload_partial(id, name)?,
+            None => {}
+        }
+        Ok(())
     }
 
+    /// Drops every cached template built from the file `id`, directly or
+    /// through other files, along with its cached `<mj-include>` content.
+    pub fn invalidate_dependents(&self, id: &TemplateId) {
+        self.includes.forget(id);
+        for dependent in self.dependencies.transitive_dependents(id) {
+            if self.template_cache.remove(&dependent).is_some() {
+                info!("Template {} removed from cache, it depends on {}.", dependent, id);
+            }
+        }
+    }
 
-    pub async fn remove_template_from_cache(&self, relative_path: &str) -> Result<(), String> {
-        let mut cache = self.template_cache.write().await;
-        cache.pop(relative_path);
-        info!("Template {} removed from cache.", relative_path);
+    pub async fn remove_template_from_cache(&self, id: &TemplateId) -> Result<(), String> {
+        self.template_cache.remove(id);
+        info!("Template {} removed from cache.", id);
         Ok(())
     }
 }
 
-pub async fn initialize_state(relative_path: &str) -> Result<AppState, Box<dyn std::error::Error + Send + Sync>> {
+/// Compiles `source` under `name`. Templates without any Handlebars
+/// expression are also parsed and rendered with `default_options` right away.
+pub fn compile_template(
+    name: &str,
+    source: &str,
+    default_options: &RenderOptions,
+    parser_options: ParserOptions,
+) -> Result<CompiledTemplate, String> {
+    let mut template = Template::compile(source)
+        .map_err(|e| format!("Handlebars template error in {}: {}", name, e))?;
+    template.name = Some(name.to_string());
+
+    // Only raw text and comments: the output is the text itself, the
+    // compiler has already applied escapes and whitespace control.
+    let static_content: Option<String> = template
+        .elements
+        .iter()
+        .map(|element| match element {
+            TemplateElement::RawString(text) => Some(text.as_str()),
+            TemplateElement::Comment(_) => Some(""),
+            _ => None,
+        })
+        .collect::<Option<Vec<&str>>>()
+        .map(|parts| parts.concat());
+    let static_render = static_content
+        .and_then(|mjml_content| mrml::parse_with_options(mjml_content, &parser_options).ok())
+        .and_then(|mjml| {
+            let html = mjml.render(default_options).ok()?;
+            Some(StaticRender { mjml: Arc::new(mjml), html })
+        });
+    Ok(CompiledTemplate { template, source: source.to_string(), parser_options, schema: None, static_render })
+}
+
+pub async fn initialize_state(relative_path: &str, config: ServerConfig) -> Result<AppState, Box<dyn std::error::Error + Send + Sync>> {
 
     // 1. Define templates dir
     // Create a 'templates' directory in your project
-    let template_dir = PathBuf::from(format!("{}", relative_path));
+    let template_dir = PathBuf::from(relative_path);
 
     // Create the templates directory if it doesn't exist
     if !template_dir.exists() {
@@ -125,7 +415,9 @@ pub async fn initialize_state(relative_path: &str) -> Result<AppState, Box<dyn s
     }
 
     // 2. Construct the AppState
-    let app_state = AppState::new(100, template_dir.clone());
+    let app_state = AppState::with_config(100, template_dir.clone(), config);
+    app_state.register_partials();
+    app_state.catalogs.load_all();
 
     // 3. Spawn a background task to clean the cache periodically
     let app_state_clone_0 = app_state.clone(); // Clone for the background task
@@ -0,0 +1,64 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::{fs::File, io::AsyncWriteExt};
+
+/// Tells apart the temporary files of writes running at the same time.
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+/// Content written next to its destination and not visible there until
+/// [`commit`](StagedFile::commit) renames it over the destination, so
+/// readers and the file watcher see the old file or the new one, never part
+/// of it. Dropped uncommitted, the temporary file is removed.
+pub struct StagedFile {
+    temp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl StagedFile {
+    /// Writes `content` to a hidden file in the directory of `path`, which
+    /// keeps the rename on one filesystem.
+    pub async fn write(path: &Path, content: &[u8]) -> io::Result<Self> {
+        let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".{}-{}.tmp", process::id(), NEXT_TEMP.fetch_add(1, Ordering::Relaxed)));
+        let staged = StagedFile { temp: path.with_file_name(temp_name), path: path.to_path_buf(), committed: false };
+
+        let mut file = File::create(&staged.temp).await?;
+        file.write_all(content).await?;
+        // On disk before the rename makes it the file, so a crash can't
+        // leave it empty.
+        file.sync_all().await?;
+        Ok(staged)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replaces the destination with the staged content.
+    pub async fn commit(mut self) -> io::Result<()> {
+        tokio::fs::rename(&self.temp, &self.path).await?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+/// Replaces `path` with `content` in one step.
+pub async fn write_atomically(path: &Path, content: &[u8]) -> io::Result<()> {
+    StagedFile::write(path, content).await?.commit().await
+}
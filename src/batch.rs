@@ -0,0 +1,182 @@
+use std::convert::Infallible;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::http::StatusCode;
+use bytes::Bytes;
+use futures_util::{future, stream, Stream, StreamExt};
+use mrml::prelude::render::RenderOptions;
+use serde_json::Value;
+
+use crate::app_state::{compile_template, AppState, CompiledTemplate};
+use crate::handlers::{render_compiled, RenderFailure};
+use crate::locales::LocaleTag;
+use crate::missing_variables::MissingVariables;
+use crate::models::{BatchLine, ConvertResponse};
+use crate::render_options::resolve_render_options;
+
+/// Media type of the batch response, and of streamed batch requests.
+pub const NDJSON: &str = "application/x-ndjson";
+
+/// Name given to inline templates in Handlebars error messages and MJML
+/// error locations.
+pub const INLINE_TEMPLATE: &str = "inline";
+
+/// One template compiled once and rendered against every payload of a batch.
+pub struct BatchRenderer {
+    app_state: AppState,
+    template: Arc<CompiledTemplate>,
+    render_options: RenderOptions,
+    default_options: bool,
+    mode: MissingVariables,
+    locale: Option<LocaleTag>,
+    /// Template name reported in each result, `None` for inline MJML.
+    template_name: Option<String>,
+}
+
+impl BatchRenderer {
+    /// Renders a cached template through the shared registry.
+    pub fn new(
+        app_state: &AppState,
+        template: Arc<CompiledTemplate>,
+        template_name: String,
+        render_options: RenderOptions,
+        default_options: bool,
+        mode: MissingVariables,
+        locale: Option<LocaleTag>,
+    ) -> Self {
+        let template_name = Some(template_name);
+        BatchRenderer { app_state: app_state.clone(), template, render_options, default_options, mode, locale, template_name }
+    }
+
+    /// Compiles inline MJML once for the whole batch.
+    pub fn inline(
+        app_state: &AppState,
+        source: String,
+        render_options: RenderOptions,
+        default_options: bool,
+        mode: MissingVariables,
+        locale: Option<LocaleTag>,
+    ) -> Result<Self, String> {
+        let server_options = resolve_render_options(&app_state.config.render_options, None);
+        let template = compile_template(INLINE_TEMPLATE, &source, &server_options, app_state.includes.parser_options(None))?;
+        Ok(BatchRenderer {
+            app_state: app_state.clone(),
+            template: Arc::new(template),
+            render_options,
+            default_options,
+            mode,
+            locale,
+            template_name: None,
+        })
+    }
+
+    pub fn render(&self, payload: &Value) -> Result<ConvertResponse, RenderFailure> {
+        let started = Instant::now();
+        if let Some(schema) = &self.template.schema {
+            let violations = schema.validate(payload);
+            if !violations.is_empty() {
+                let violations: Vec<String> = violations.iter().map(ToString::to_string).collect();
+                let message = format!("Payload does not match the template schema: {}", violations.join("; "));
+                return Err((StatusCode::UNPROCESSABLE_ENTITY, message).into());
+            }
+        }
+        let (parsed, rendered, unresolved) = render_compiled(
+            &self.app_state,
+            &self.template,
+            payload,
+            &self.render_options,
+            self.default_options,
+            self.mode,
+            self.locale.as_ref(),
+        )?;
+        let mut response = ConvertResponse::new(&parsed, rendered, self.template_name.clone(), started);
+        response.add_unresolved(&unresolved);
+        Ok(response)
+    }
+}
+
+/// Renders every payload on the blocking pool, at most `concurrency` at a
+/// time, and yields one NDJSON line per payload in input order. A failing
+/// payload produces an error line and doesn't stop the batch.
+pub fn render_lines<S>(
+    renderer: Arc<BatchRenderer>,
+    payloads: S,
+    concurrency: usize,
+) -> impl Stream<Item = Result<Bytes, Infallible>>
+where
+    S: Stream<Item = Result<Value, String>> + Send + 'static,
+{
+    payloads
+        .enumerate()
+        .map(move |(index, payload)| {
+            let renderer = renderer.clone();
+            async move {
+                let outcome = match payload {
+                    Ok(payload) => tokio::task::spawn_blocking(move || renderer.render(&payload))
+                        .await
+                        .unwrap_or_else(|e| Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Render task failed: {}", e)).into())),
+                    Err(e) => Err((StatusCode::BAD_REQUEST, e).into()),
+                };
+                BatchLine::new(index, outcome.map_err(RenderFailure::into_parts))
+            }
+        })
+        .buffered(concurrency)
+        .map(|line| {
+            let mut bytes = serde_json::to_vec(&line).expect("batch lines always serialize");
+            bytes.push(b'\n');
+            Ok(Bytes::from(bytes))
+        })
+}
+
+/// Splits a byte stream into its non-blank lines. A line longer than
+/// `max_line_bytes` fails with a 413 and ends the stream, since the rest of
+/// it can't be told apart from the next line.
+pub fn ndjson_lines<S, E>(body: S, max_line_bytes: usize) -> impl Stream<Item = Result<String, (StatusCode, String)>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Display,
+{
+    stream::unfold((body, Vec::new(), false), move |(mut body, mut buffer, mut done)| async move {
+        loop {
+            if let Some(position) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=position).collect();
+                if line.len() > max_line_bytes + 1 {
+                    buffer.clear();
+                    return Some((Err(line_too_long(max_line_bytes)), (body, buffer, true)));
+                }
+                return Some((decode_line(line), (body, buffer, done)));
+            }
+            if buffer.len() > max_line_bytes {
+                buffer.clear();
+                return Some((Err(line_too_long(max_line_bytes)), (body, buffer, true)));
+            }
+            if done {
+                if buffer.is_empty() {
+                    return None;
+                }
+                let line = std::mem::take(&mut buffer);
+                return Some((decode_line(line), (body, buffer, done)));
+            }
+            match body.next().await {
+                Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    buffer.clear();
+                    let error = (StatusCode::BAD_REQUEST, format!("Failed to read request body: {}", e));
+                    return Some((Err(error), (body, buffer, true)));
+                }
+                None => done = true,
+            }
+        }
+    })
+    .filter(|line| future::ready(!matches!(line, Ok(line) if line.trim().is_empty())))
+}
+
+fn decode_line(line: Vec<u8>) -> Result<String, (StatusCode, String)> {
+    String::from_utf8(line).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid UTF-8 in line: {}", e)))
+}
+
+fn line_too_long(max_line_bytes: usize) -> (StatusCode, String) {
+    (StatusCode::PAYLOAD_TOO_LARGE, format!("A line is larger than the limit of {} bytes", max_line_bytes))
+}
@@ -0,0 +1,85 @@
+use std::{fs, num::NonZeroUsize, path::Path, thread};
+
+use serde::Deserialize;
+
+use crate::helpers::HelpersConfig;
+use crate::missing_variables::MissingVariables;
+use crate::models::RenderOptionsInput;
+
+/// Server-wide settings loaded from the JSON file passed with `--config`.
+/// Every field is optional; anything left out keeps its built-in default.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Defaults applied to every render before the per-request `render_options`.
+    pub render_options: RenderOptionsInput,
+    /// How many payloads of a `/convert/batch` request render in parallel.
+    /// Defaults to the number of CPUs.
+    pub batch_concurrency: Option<usize>,
+    /// Handling of unresolved `{{values}}` when a request doesn't choose.
+    pub missing_variables: MissingVariables,
+    /// Bearer token for the `/admin` endpoints. They answer 404 while unset.
+    pub admin_token: Option<String>,
+    /// Which built-in helper groups are registered, and their defaults.
+    pub helpers: HelpersConfig,
+    /// Largest file the template API accepts, in bytes. Defaults to 1 MiB.
+    pub max_file_bytes: Option<usize>,
+    /// Largest `POST /templates` request, all files together, in bytes.
+    /// Defaults to 10 MiB.
+    pub max_upload_bytes: Option<usize>,
+    /// Largest JSON `/convert/batch` request, and largest line of an NDJSON
+    /// one, in bytes. Defaults to 10 MiB.
+    pub max_batch_bytes: Option<usize>,
+}
+
+const DEFAULT_MAX_FILE_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+const DEFAULT_MAX_BATCH_BYTES: usize = 10 * 1024 * 1024;
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+        let config: ServerConfig = serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+        config.render_options.validate()
+            .map_err(|e| format!("Invalid render_options in {}: {}", path.display(), e))?;
+        if config.batch_concurrency == Some(0) {
+            return Err(format!("Invalid batch_concurrency in {}: must be at least 1", path.display()).into());
+        }
+        if config.admin_token.as_deref().is_some_and(|token| token.trim().is_empty()) {
+            return Err(format!("Invalid admin_token in {}: must not be empty", path.display()).into());
+        }
+        let limits = [
+            ("max_file_bytes", config.max_file_bytes),
+            ("max_upload_bytes", config.max_upload_bytes),
+            ("max_batch_bytes", config.max_batch_bytes),
+        ];
+        for (field, limit) in limits {
+            if limit == Some(0) {
+                return Err(format!("Invalid {} in {}: must be at least 1", field, path.display()).into());
+            }
+        }
+        config.helpers.validate()
+            .map_err(|e| format!("Invalid helpers in {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    pub fn batch_concurrency(&self) -> usize {
+        self.batch_concurrency
+            .filter(|concurrency| *concurrency > 0)
+            .unwrap_or_else(|| thread::available_parallelism().map(NonZeroUsize::get).unwrap_or(4))
+    }
+
+    pub fn max_file_bytes(&self) -> usize {
+        self.max_file_bytes.unwrap_or(DEFAULT_MAX_FILE_BYTES)
+    }
+
+    pub fn max_upload_bytes(&self) -> usize {
+        self.max_upload_bytes.unwrap_or(DEFAULT_MAX_UPLOAD_BYTES)
+    }
+
+    pub fn max_batch_bytes(&self) -> usize {
+        self.max_batch_bytes.unwrap_or(DEFAULT_MAX_BATCH_BYTES)
+    }
+}
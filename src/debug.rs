@@ -0,0 +1,150 @@
+use std::time::Instant;
+
+use axum::http::StatusCode;
+use mrml::prelude::render::RenderOptions;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::app_state::{AppState, CacheLayer, CompiledTemplate};
+use crate::escaping::strip_invalid_xml_chars;
+use crate::locales::{with_locale, LocaleTag};
+use crate::missing_variables::{render_error_status, track_unresolved, MissingVariables};
+use crate::source_map::{error_offset, SourceLocation};
+
+/// Every stage of one render, answered by `/convert` with `"debug": true`.
+/// A failing stage ends the report, with the stages before it filled in.
+#[derive(Serialize)]
+pub struct DebugReport {
+    /// Template name, `None` for inline MJML.
+    pub template: Option<String>,
+    /// Where the compiled template came from, `None` for inline MJML.
+    pub cache: Option<CacheLayer>,
+    /// Whether the HTML pre-rendered for a static template was served,
+    /// skipping Handlebars, parsing and rendering.
+    pub prerendered: bool,
+    pub stages: Stages,
+    pub timings_ms: Timings,
+    /// Paths `report` mode couldn't resolve.
+    pub unresolved: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Where in the template an MJML error comes from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<SourceLocation>,
+    #[serde(skip)]
+    status: StatusCode,
+}
+
+#[derive(Default, Serialize)]
+pub struct Stages {
+    /// The template source.
+    pub template: String,
+    /// What Handlebars expanded the template to, as handed to mrml.
+    pub mjml: Option<String>,
+    /// mrml's parsed document.
+    pub ast: Option<Value>,
+    pub html: Option<String>,
+}
+
+/// Milliseconds spent in each stage. Stages that didn't run are `null`.
+#[derive(Default, Serialize)]
+pub struct Timings {
+    /// Fetching the compiled template, or compiling inline MJML.
+    pub load: f64,
+    pub handlebars: Option<f64>,
+    pub parse: Option<f64>,
+    pub render: Option<f64>,
+    pub total: f64,
+}
+
+impl DebugReport {
+    /// Renders `template` like `render_compiled`, recording each stage.
+    pub fn render(
+        app_state: &AppState,
+        template: &CompiledTemplate,
+        data: &Value,
+        render_options: &RenderOptions,
+        default_options: bool,
+        mode: MissingVariables,
+        locale: Option<&LocaleTag>,
+    ) -> Self {
+        let mut report = DebugReport {
+            template: None,
+            cache: None,
+            prerendered: false,
+            stages: Stages { template: template.source().to_string(), ..Default::default() },
+            timings_ms: Timings::default(),
+            unresolved: Vec::new(),
+            error: None,
+            location: None,
+            status: StatusCode::OK,
+        };
+        let handlebars = app_state.registry(mode).read().unwrap();
+
+        let parsed = match &template.static_render {
+            Some(static_render) => {
+                // Without expressions the expansion is the template text.
+                report.stages.mjml = template.render_handlebars(&handlebars, data).ok();
+                report.prerendered = default_options;
+                static_render.mjml.clone()
+            }
+            None => {
+                let started = Instant::now();
+                let (mjml, unresolved) = with_locale(locale, || {
+                    track_unresolved(mode, || template.render_handlebars(&handlebars, data))
+                });
+                report.timings_ms.handlebars = Some(elapsed_ms(started));
+                report.unresolved = unresolved;
+                let mjml = match mjml {
+                    Ok(mjml) => strip_invalid_xml_chars(&mjml).into_owned(),
+                    Err(e) => return report.failed(render_error_status(&e), format!("Handlebars rendering error: {}", e)),
+                };
+
+                let started = Instant::now();
+                let parsed = mrml::parse_with_options(mjml.as_str(), &template.parser_options);
+                report.timings_ms.parse = Some(elapsed_ms(started));
+                let parsed = match parsed {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        report.location = error_offset(&mjml, &e).and_then(|offset| {
+                            with_locale(locale, || template.locate(&handlebars, &app_state.template_dir, data, offset))
+                        });
+                        report.stages.mjml = Some(mjml);
+                        return report.failed(StatusCode::BAD_REQUEST, format!("Invalid MJML input: {}", e));
+                    }
+                };
+                report.stages.mjml = Some(mjml);
+                parsed.into()
+            }
+        };
+        report.stages.ast = serde_json::to_value(&*parsed).ok();
+
+        if report.prerendered {
+            report.stages.html = template.static_render.as_ref().map(|static_render| static_render.html.clone());
+            return report;
+        }
+        let started = Instant::now();
+        let rendered = parsed.render(render_options);
+        report.timings_ms.render = Some(elapsed_ms(started));
+        match rendered {
+            Ok(html) => report.stages.html = Some(html),
+            Err(e) => return report.failed(StatusCode::BAD_REQUEST, format!("Couldn't render MJML template: {}", e)),
+        }
+        report
+    }
+
+    /// Status of the failing stage, 200 when every stage succeeded.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    fn failed(mut self, status: StatusCode, error: String) -> Self {
+        self.status = status;
+        self.error = Some(error);
+        self
+    }
+}
+
+pub fn elapsed_ms(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
@@ -0,0 +1,98 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    sync::RwLock,
+};
+
+use serde::Serialize;
+
+use crate::template_id::TemplateId;
+
+/// Which files each template pulled in while it was compiled or rendered:
+/// partials and layouts, `<mj-include>` files, and through them whatever
+/// those files use in turn.
+///
+/// Files are identified by the same `TemplateId` as templates, so the
+/// watcher can look up a changed path directly.
+#[derive(Default)]
+pub struct DependencyGraph {
+    edges: RwLock<Edges>,
+}
+
+#[derive(Default)]
+struct Edges {
+    /// Template or file -> files it uses.
+    dependencies: HashMap<TemplateId, HashSet<TemplateId>>,
+    /// File -> templates and files that use it.
+    dependents: HashMap<TemplateId, HashSet<TemplateId>>,
+}
+
+/// Both directions of the graph, sorted, as served by `/admin/dependencies`.
+#[derive(Serialize)]
+pub struct GraphSnapshot {
+    pub dependencies: BTreeMap<String, BTreeSet<String>>,
+    pub dependents: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl DependencyGraph {
+    /// Records that `template` uses `dependency`.
+    pub fn record(&self, template: &TemplateId, dependency: &TemplateId) {
+        let known = self
+            .edges
+            .read()
+            .unwrap()
+            .dependencies
+            .get(template)
+            .is_some_and(|dependencies| dependencies.contains(dependency));
+        // Dynamic templates record their includes on every render.
+        if known {
+            return;
+        }
+        let mut edges = self.edges.write().unwrap();
+        edges.dependencies.entry(template.clone()).or_default().insert(dependency.clone());
+        edges.dependents.entry(dependency.clone()).or_default().insert(template.clone());
+    }
+
+    /// Forgets what `template` used, before it is compiled again.
+    pub fn clear(&self, template: &TemplateId) {
+        let mut edges = self.edges.write().unwrap();
+        let Some(dependencies) = edges.dependencies.remove(template) else { return };
+        for dependency in dependencies {
+            if let Some(dependents) = edges.dependents.get_mut(&dependency) {
+                dependents.remove(template);
+                if dependents.is_empty() {
+                    edges.dependents.remove(&dependency);
+                }
+            }
+        }
+    }
+
+    /// Everything that uses `file`, directly or through other files.
+    pub fn transitive_dependents(&self, file: &TemplateId) -> Vec<TemplateId> {
+        let edges = self.edges.read().unwrap();
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::from([file]);
+        let mut found = Vec::new();
+        while let Some(current) = queue.pop_front() {
+            for dependent in edges.dependents.get(current).into_iter().flatten() {
+                if seen.insert(dependent) {
+                    found.push(dependent.clone());
+                    queue.push_back(dependent);
+                }
+            }
+        }
+        found
+    }
+
+    pub fn snapshot(&self) -> GraphSnapshot {
+        let edges = self.edges.read().unwrap();
+        let sorted = |map: &HashMap<TemplateId, HashSet<TemplateId>>| {
+            map.iter()
+                .map(|(id, ids)| (id.to_string(), ids.iter().map(TemplateId::to_string).collect()))
+                .collect()
+        };
+        GraphSnapshot {
+            dependencies: sorted(&edges.dependencies),
+            dependents: sorted(&edges.dependents),
+        }
+    }
+}
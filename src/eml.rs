@@ -0,0 +1,241 @@
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::models::EmailHeaders;
+
+/// Longest encoded line allowed by RFC 2045, not counting the CRLF.
+const QP_LINE_LENGTH: usize = 76;
+/// Folding point for header lines recommended by RFC 5322.
+const HEADER_LINE_LENGTH: usize = 78;
+/// Characters a display name can only hold inside a quoted string
+/// (RFC 5322 section 3.2.3).
+const SPECIALS: &[char] = &['(', ')', '<', '>', '[', ']', ':', ';', '@', '\\', ',', '.', '"'];
+
+/// Assembles a complete RFC 5322 message with a multipart/alternative body
+/// holding `text` and `html`, both quoted-printable encoded.
+///
+/// `subject` is used when the request doesn't set one, typically the
+/// template's `<mj-title>`.
+pub fn build_message(
+    headers: &EmailHeaders,
+    subject: Option<&str>,
+    text: &str,
+    html: &str,
+) -> Result<String, String> {
+    let from = headers
+        .from
+        .as_deref()
+        .filter(|from| !from.trim().is_empty())
+        .ok_or_else(|| "email.from is required for eml output".to_string())?;
+    let subject = headers.subject.as_deref().or(subject);
+
+    let mut message = String::with_capacity(text.len() + html.len() * 2);
+    write_header(&mut message, "Date", &format_date(SystemTime::now()))?;
+    write_header(&mut message, "Message-ID", &message_id(from))?;
+    write_header(&mut message, "From", &encode_address(from))?;
+    if !headers.to.is_empty() {
+        let to: Vec<String> = headers.to.iter().map(|to| encode_address(to)).collect();
+        write_header(&mut message, "To", &to.join(", "))?;
+    }
+    if let Some(reply_to) = &headers.reply_to {
+        write_header(&mut message, "Reply-To", &encode_address(reply_to))?;
+    }
+    if let Some(subject) = subject {
+        write_header(&mut message, "Subject", &encode_word(subject.trim()))?;
+    }
+    write_header(&mut message, "MIME-Version", "1.0")?;
+
+    // Quoted-printable output always escapes '=', so a boundary starting with
+    // "=_" can never collide with the encoded parts.
+    let boundary = boundary(&[text, html]);
+    message.push_str(&format!("Content-Type: multipart/alternative; boundary=\"{}\"\r\n\r\n", boundary));
+    for (content_type, body) in [("text/plain", text), ("text/html", html)] {
+        message.push_str(&format!("--{}\r\n", boundary));
+        message.push_str(&format!("Content-Type: {}; charset=utf-8\r\n", content_type));
+        message.push_str("Content-Transfer-Encoding: quoted-printable\r\n\r\n");
+        message.push_str(&encode_quoted_printable(body));
+        message.push_str("\r\n");
+    }
+    message.push_str(&format!("--{}--\r\n", boundary));
+    Ok(message)
+}
+
+/// Quoted-printable encoding (RFC 2045 section 6.7) with CRLF line endings
+/// and soft breaks keeping every line within 76 characters.
+pub fn encode_quoted_printable(input: &str) -> String {
+    let normalized = input.replace("\r\n", "\n");
+    let mut encoded_lines = Vec::new();
+    for line in normalized.split('\n') {
+        let bytes = line.as_bytes();
+        let mut encoded = String::new();
+        let mut current = 0;
+        for (index, &byte) in bytes.iter().enumerate() {
+            let is_last = index + 1 == bytes.len();
+            let token = match byte {
+                b'=' => "=3D".to_string(),
+                b' ' | b'\t' if is_last => format!("={:02X}", byte),
+                b' ' | b'\t' | 33..=126 => (byte as char).to_string(),
+                _ => format!("={:02X}", byte),
+            };
+            // Leave room for the trailing '=' of a soft break, unless this is
+            // the last token of the line.
+            let limit = if is_last { QP_LINE_LENGTH } else { QP_LINE_LENGTH - 1 };
+            if current + token.len() > limit {
+                encoded.push_str("=\r\n");
+                current = 0;
+            }
+            current += token.len();
+            encoded.push_str(&token);
+        }
+        encoded_lines.push(encoded);
+    }
+    encoded_lines.join("\r\n")
+}
+
+fn write_header(message: &mut String, name: &str, value: &str) -> Result<(), String> {
+    if value.contains(['\r', '\n']) {
+        return Err(format!("{} header must not contain line breaks", name));
+    }
+    message.push_str(&fold_header(&format!("{}: {}", name, value)));
+    message.push_str("\r\n");
+    Ok(())
+}
+
+/// Folds a header line at whitespace so lines stay within 78 characters where possible.
+fn fold_header(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut current = 0;
+    for (index, word) in line.split(' ').enumerate() {
+        if index > 0 {
+            if current + 1 + word.len() > HEADER_LINE_LENGTH {
+                folded.push_str("\r\n");
+                current = 0;
+            }
+            folded.push(' ');
+            current += 1;
+        }
+        folded.push_str(word);
+        current += word.len();
+    }
+    folded
+}
+
+/// Writes the display name of `Name <addr@example.com>` so it reads as one
+/// name: encoded when it isn't ASCII, quoted when it holds specials such as
+/// the comma of `Doe, John`.
+fn encode_address(address: &str) -> String {
+    let address = address.trim();
+    let Some((name, addr)) = address.rsplit_once('<') else {
+        return address.to_string();
+    };
+    let name = name.trim();
+    let is_quoted = name.len() >= 2 && name.starts_with('"') && name.ends_with('"');
+    if name.is_empty() || (is_quoted && name.is_ascii()) {
+        return address.to_string();
+    }
+    let name = if is_quoted { &name[1..name.len() - 1] } else { name };
+    if !name.is_ascii() {
+        format!("{} <{}", encode_word(name), addr)
+    } else if name.contains(SPECIALS) {
+        format!("\"{}\" <{}", name.replace('\\', "\\\\").replace('"', "\\\""), addr)
+    } else {
+        format!("{} <{}", name, addr)
+    }
+}
+
+/// A fresh `<unique@domain>` Message-ID, on the domain of the sender.
+fn message_id(from: &str) -> String {
+    let addr = from.rsplit_once('<').map_or(from, |(_, addr)| addr.trim_end().trim_end_matches('>'));
+    let domain = addr
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.trim())
+        .filter(|domain| !domain.is_empty() && domain.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-'))
+        .unwrap_or("localhost");
+    // Each `RandomState` is keyed differently, so ids don't repeat even
+    // within the same instant.
+    let unique = |salt: u8| {
+        let mut hasher = RandomState::new().build_hasher();
+        SystemTime::now().hash(&mut hasher);
+        salt.hash(&mut hasher);
+        hasher.finish()
+    };
+    format!("<{:016x}{:016x}@{}>", unique(0), unique(1), domain)
+}
+
+/// RFC 2047 "Q" encoded-words for non-ASCII header text, split so that each
+/// word stays under 75 characters and multi-byte characters are never cut.
+fn encode_word(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+    const PREFIX: &str = "=?UTF-8?Q?";
+    const SUFFIX: &str = "?=";
+    let max_payload = 75 - PREFIX.len() - SUFFIX.len();
+
+    let mut words = Vec::new();
+    let mut payload = String::new();
+    for c in value.chars() {
+        let mut buffer = [0; 4];
+        let token: String = c
+            .encode_utf8(&mut buffer)
+            .bytes()
+            .map(|byte| match byte {
+                b' ' => "_".to_string(),
+                b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' | b'!' | b'*' | b'+' | b'-' | b'/' => {
+                    (byte as char).to_string()
+                }
+                _ => format!("={:02X}", byte),
+            })
+            .collect();
+        if payload.len() + token.len() > max_payload {
+            words.push(format!("{}{}{}", PREFIX, payload, SUFFIX));
+            payload.clear();
+        }
+        payload.push_str(&token);
+    }
+    words.push(format!("{}{}{}", PREFIX, payload, SUFFIX));
+    words.join(" ")
+}
+
+fn boundary(parts: &[&str]) -> String {
+    let mut hasher = DefaultHasher::new();
+    SystemTime::now().hash(&mut hasher);
+    parts.hash(&mut hasher);
+    format!("=_mjml_{:016x}", hasher.finish())
+}
+
+/// Formats `time` as an RFC 5322 date in UTC, e.g. `Thu, 01 Jan 1970 00:00:00 +0000`.
+fn format_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let seconds = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (seconds / 86_400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let rem = seconds % 86_400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Converts days since 1970-01-01 into a (year, month, day) civil date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,369 @@
+use std::borrow::Cow;
+
+use handlebars::{Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError};
+use serde_json::Value;
+
+/// Escape function of both registries. Handlebars' default targets HTML,
+/// but its output is MJML, which mrml parses as XML. This escapes what XML
+/// needs in text and in quoted attributes, drops characters XML forbids,
+/// and breaks up `--` so a value can't end a comment it is placed in.
+/// `=` and backticks are left alone, so URLs stay readable.
+pub fn escape_mjml(data: &str) -> String {
+    let mut output = String::with_capacity(data.len());
+    let mut previous = '\0';
+    for c in data.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&#39;"),
+            '-' if previous == '-' => output.push_str("&#45;"),
+            c if !is_xml_char(c) => {}
+            c => output.push(c),
+        }
+        previous = c;
+    }
+    output
+}
+
+/// Characters allowed in an XML 1.0 document.
+fn is_xml_char(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\r' | '\u{20}'..='\u{D7FF}' | '\u{E000}'..='\u{FFFD}' | '\u{10000}'..='\u{10FFFF}')
+}
+
+/// Drops the characters XML forbids from expanded MJML, including any that
+/// came in through `{{{raw}}}` output or the template itself.
+pub fn strip_invalid_xml_chars(mjml: &str) -> Cow<'_, str> {
+    if mjml.chars().all(is_xml_char) {
+        Cow::Borrowed(mjml)
+    } else {
+        Cow::Owned(mjml.chars().filter(|c| is_xml_char(*c)).collect())
+    }
+}
+
+/// Registers the escape function and `safe_html`.
+pub fn configure(handlebars: &mut Handlebars<'static>) {
+    handlebars.register_escape_fn(escape_mjml);
+    handlebars.register_helper("safe_html", Box::new(SafeHtml));
+}
+
+/// `{{safe_html bio}}` outputs payload HTML unescaped once it went through
+/// [`sanitize_html`], for rich text that `{{{bio}}}` would pass on as is.
+pub struct SafeHtml;
+
+impl HelperDef for SafeHtml {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let html = match h.param(0).map(|param| param.value()) {
+            None | Some(Value::Null) => return Ok(()),
+            Some(Value::String(html)) => html,
+            Some(other) => return Err(RenderError::new(format!("safe_html: expected a string, got {}", other))),
+        };
+        out.write(&sanitize_html(html))?;
+        Ok(())
+    }
+}
+
+/// Tags kept by [`sanitize_html`], with the attributes each may carry.
+const ALLOWED_TAGS: [(&str, &[&str]); 30] = [
+    ("a", &["href", "title", "target"]),
+    ("b", &[]),
+    ("blockquote", &[]),
+    ("br", &[]),
+    ("code", &[]),
+    ("del", &[]),
+    ("div", &[]),
+    ("em", &[]),
+    ("h1", &[]),
+    ("h2", &[]),
+    ("h3", &[]),
+    ("h4", &[]),
+    ("h5", &[]),
+    ("h6", &[]),
+    ("hr", &[]),
+    ("i", &[]),
+    ("img", &["src", "alt", "width", "height"]),
+    ("ins", &[]),
+    ("li", &[]),
+    ("mark", &[]),
+    ("ol", &[]),
+    ("p", &[]),
+    ("pre", &[]),
+    ("s", &[]),
+    ("small", &[]),
+    ("span", &[]),
+    ("strong", &[]),
+    ("sub", &[]),
+    ("sup", &[]),
+    ("u", &[]),
+];
+
+/// Attributes allowed on every kept tag.
+const GLOBAL_ATTRIBUTES: [&str; 3] = ["title", "dir", "lang"];
+
+const VOID_TAGS: [&str; 3] = ["br", "hr", "img"];
+
+/// Tags dropped along with their content.
+const DROPPED_WITH_CONTENT: [&str; 7] = ["script", "style", "iframe", "object", "template", "textarea", "title"];
+
+/// Reduces `html` to well-formed markup mrml can parse: tags and attributes
+/// outside the allowlist are dropped (keeping their text, except for
+/// scripts and the like), links must be `http`, `https`, `mailto` or `tel`
+/// URLs, comments go, and unclosed tags are closed.
+pub fn sanitize_html(html: &str) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut open: Vec<&'static str> = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find(['<', '&']) {
+        push_text(&mut output, &rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with('&') {
+            let (entity, after) = read_entity(rest);
+            output.push_str(&entity);
+            rest = after;
+            continue;
+        }
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let Some(tag) = Tag::parse(rest) else {
+            output.push_str("&lt;");
+            rest = &rest[1..];
+            continue;
+        };
+        rest = &rest[tag.len..];
+        let name = tag.name.to_ascii_lowercase();
+        if tag.closing {
+            if let Some(position) = open.iter().rposition(|open| *open == name) {
+                for closed in open.drain(position..).rev() {
+                    output.push_str(&format!("</{}>", closed));
+                }
+            }
+            continue;
+        }
+        if DROPPED_WITH_CONTENT.contains(&name.as_str()) {
+            if !tag.self_closing {
+                rest = skip_past_closing_tag(rest, &name);
+            }
+            continue;
+        }
+        let Some((name, attributes)) = ALLOWED_TAGS.iter().find(|(allowed, _)| *allowed == name) else { continue };
+        output.push('<');
+        output.push_str(name);
+        for (attribute, value) in tag.attributes {
+            let attribute = attribute.to_ascii_lowercase();
+            if !attributes.contains(&attribute.as_str()) && !GLOBAL_ATTRIBUTES.contains(&attribute.as_str()) {
+                continue;
+            }
+            let value = decode_entities(&value);
+            if matches!(attribute.as_str(), "href" | "src") && !is_allowed_url(&attribute, &value) {
+                continue;
+            }
+            output.push_str(&format!(" {}=\"{}\"", attribute, escape_mjml(&value)));
+        }
+        if VOID_TAGS.contains(name) {
+            output.push_str(" />");
+        } else {
+            output.push('>');
+            open.push(name);
+        }
+    }
+    push_text(&mut output, rest);
+    for closed in open.into_iter().rev() {
+        output.push_str(&format!("</{}>", closed));
+    }
+    output
+}
+
+/// Text between tags, which may still hold `>` and illegal characters.
+fn push_text(output: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '>' => output.push_str("&gt;"),
+            c if is_xml_char(c) => output.push(c),
+            _ => {}
+        }
+    }
+}
+
+/// HTML entities without an XML equivalent, written as character references.
+const NAMED_ENTITIES: [(&str, &str); 18] = [
+    ("nbsp", "&#160;"),
+    ("copy", "&#169;"),
+    ("reg", "&#174;"),
+    ("trade", "&#8482;"),
+    ("hellip", "&#8230;"),
+    ("mdash", "&#8212;"),
+    ("ndash", "&#8211;"),
+    ("lsquo", "&#8216;"),
+    ("rsquo", "&#8217;"),
+    ("ldquo", "&#8220;"),
+    ("rdquo", "&#8221;"),
+    ("laquo", "&#171;"),
+    ("raquo", "&#187;"),
+    ("euro", "&#8364;"),
+    ("pound", "&#163;"),
+    ("middot", "&#183;"),
+    ("bull", "&#8226;"),
+    ("deg", "&#176;"),
+];
+
+/// Reads the entity at the start of `text` (which starts with `&`), as it
+/// should be written to XML, and what follows it. A `&` that doesn't start
+/// a known entity is escaped.
+fn read_entity(text: &str) -> (Cow<'static, str>, &str) {
+    let body = &text[1..];
+    let end = body.find(';').filter(|end| *end <= 32);
+    let Some(end) = end else { return (Cow::Borrowed("&amp;"), body) };
+    let name = &body[..end];
+    let after = &body[end + 1..];
+    if let Some(code) = name.strip_prefix('#') {
+        let code = match code.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => code.parse().ok(),
+        };
+        return match code.and_then(char::from_u32).filter(|c| is_xml_char(*c)) {
+            Some(c) => (Cow::Owned(format!("&#{};", u32::from(c))), after),
+            None => (Cow::Borrowed(""), after),
+        };
+    }
+    if matches!(name, "amp" | "lt" | "gt" | "quot" | "apos") {
+        return (Cow::Owned(format!("&{};", name)), after);
+    }
+    match NAMED_ENTITIES.iter().find(|(entity, _)| *entity == name) {
+        Some((_, reference)) => (Cow::Borrowed(reference), after),
+        None => (Cow::Borrowed("&amp;"), body),
+    }
+}
+
+/// Attribute value as the browser would see it, for checking URLs.
+fn decode_entities(value: &str) -> String {
+    let mut decoded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let (entity, after) = read_entity(&rest[start..]);
+        let c = match entity.as_ref() {
+            "&amp;" => Some('&'),
+            "&lt;" => Some('<'),
+            "&gt;" => Some('>'),
+            "&quot;" => Some('"'),
+            "&apos;" => Some('\''),
+            reference => reference
+                .strip_prefix("&#")
+                .and_then(|code| code.strip_suffix(';'))
+                .and_then(|code| code.parse().ok())
+                .and_then(char::from_u32),
+        };
+        decoded.extend(c);
+        rest = after;
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn is_allowed_url(attribute: &str, url: &str) -> bool {
+    // Browsers ignore whitespace and control characters inside a scheme.
+    let scheme: String = url
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .take_while(|c| *c != ':')
+        .collect::<String>()
+        .to_ascii_lowercase();
+    let has_scheme = url.contains(':') && !scheme.contains(['/', '?', '#']);
+    match attribute {
+        "src" => has_scheme && matches!(scheme.as_str(), "http" | "https" | "cid"),
+        _ => has_scheme && matches!(scheme.as_str(), "http" | "https" | "mailto" | "tel"),
+    }
+}
+
+fn skip_past_closing_tag<'a>(rest: &'a str, name: &str) -> &'a str {
+    let lowercase = rest.to_ascii_lowercase();
+    let closing = format!("</{}", name);
+    match lowercase.find(&closing) {
+        Some(start) => rest[start..].find('>').map_or("", |end| &rest[start + end + 1..]),
+        None => "",
+    }
+}
+
+/// A start or end tag at the beginning of some HTML.
+struct Tag<'a> {
+    name: &'a str,
+    closing: bool,
+    self_closing: bool,
+    attributes: Vec<(&'a str, String)>,
+    /// Bytes the tag spans.
+    len: usize,
+}
+
+impl<'a> Tag<'a> {
+    fn parse(html: &'a str) -> Option<Self> {
+        let mut position = 1;
+        let closing = html[position..].starts_with('/');
+        if closing {
+            position += 1;
+        }
+        let name_len = html[position..].find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(html.len() - position);
+        if name_len == 0 || !html[position..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return None;
+        }
+        let name = &html[position..position + name_len];
+        position += name_len;
+        let mut attributes = Vec::new();
+        loop {
+            let rest = &html[position..];
+            let trimmed = rest.trim_start();
+            position += rest.len() - trimmed.len();
+            if let Some(after) = trimmed.strip_prefix("/>") {
+                let len = html.len() - after.len();
+                return Some(Tag { name, closing, self_closing: true, attributes, len });
+            }
+            if trimmed.starts_with('>') {
+                return Some(Tag { name, closing, self_closing: false, attributes, len: position + 1 });
+            }
+            if trimmed.is_empty() {
+                return None;
+            }
+            let attribute_len = trimmed
+                .find(|c: char| c.is_whitespace() || matches!(c, '=' | '>' | '/' | '"' | '\''))
+                .unwrap_or(trimmed.len());
+            if attribute_len == 0 {
+                // A stray quote or slash: skip it.
+                position += trimmed.chars().next().map_or(1, char::len_utf8);
+                continue;
+            }
+            let attribute = &trimmed[..attribute_len];
+            position += attribute_len;
+            let rest = &html[position..];
+            let after_space = rest.trim_start();
+            let Some(value_start) = after_space.strip_prefix('=') else {
+                attributes.push((attribute, String::new()));
+                continue;
+            };
+            let value_start_trimmed = value_start.trim_start();
+            position += rest.len() - value_start_trimmed.len();
+            let (value, value_len) = match value_start_trimmed.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let end = value_start_trimmed[1..].find(quote)?;
+                    (&value_start_trimmed[1..end + 1], end + 2)
+                }
+                _ => {
+                    let end = value_start_trimmed
+                        .find(|c: char| c.is_whitespace() || c == '>')
+                        .unwrap_or(value_start_trimmed.len());
+                    (&value_start_trimmed[..end], end)
+                }
+            };
+            position += value_len;
+            attributes.push((attribute, value.to_string()));
+        }
+    }
+}
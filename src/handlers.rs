@@ -1,188 +1,1063 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
 use axum::{
-    extract::{Json, Multipart, State},
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    body::{Body, Bytes, StreamBody},
+    extract::{Json, Multipart, Path as AxumPath, Query, State},
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode, Uri},
+    response::{Html, IntoResponse, Response},
 };
+use futures_util::{stream, StreamExt};
+use handlebars::Template;
+use mrml::mjml::Mjml;
+use mrml::prelude::parser::ParserOptions;
+use mrml::prelude::render::RenderOptions;
+use serde_json::{json, Value};
+
+use tracing::error;
 
-use tokio::io::{AsyncWriteExt};
-use tokio::io::BufWriter as AsyncBufWriter;
-use tokio::fs::File;
+use crate::app_state::{compile_template, AppState, CacheLayer, CompiledTemplate};
+use crate::atomic_write::{write_atomically, StagedFile};
+use crate::batch::{ndjson_lines, render_lines, BatchRenderer, INLINE_TEMPLATE, NDJSON};
+use crate::debug::{elapsed_ms, DebugReport};
+use crate::eml::build_message;
+use crate::escaping::strip_invalid_xml_chars;
+use crate::history;
+use crate::listing::{self, ListQuery};
+use crate::locales::{localized_names, with_locale, LocaleTag};
+use crate::missing_variables::{render_error_status, track_unresolved, MissingVariables};
+use crate::models::{BatchInput, ConvertResponse, DiffQuery, MjmlInput, OutputFormat, RenderOptionsInput, RollbackInput};
+use crate::render_options::resolve_render_options;
+use crate::schema::Violation;
+use crate::source_map::{self, error_offset, SourceLocation};
+use crate::template_id::TemplateId;
+use crate::text_renderer::html_to_text;
+use crate::utils::content_hash;
+use crate::validation::{check_upload, is_fixtures};
 
-use mrml::{prelude::render::RenderOptions, self};
+/// Response header listing the unresolved paths in `report` mode.
+const UNRESOLVED_VARIABLES: &str = "x-unresolved-variables";
 
-use crate::app_state::AppState;
-use crate::models::MjmlInput;
+/// Gmail clips messages whose HTML is larger than this.
+const GMAIL_CLIP_BYTES: usize = 102 * 1024;
 
 pub async fn convert_mjml(
     State(app_state): State<AppState>,
+    headers: HeaderMap,
     Json(payload): Json<MjmlInput>,
 ) -> Result<Response, (StatusCode, String)> {
-    let mjml_content = match &payload.template {
+    let started = Instant::now();
+    if payload.debug {
+        require_admin(&app_state, &headers)?;
+    }
+    let output = payload.output.unwrap_or_else(|| negotiate_output(&headers));
+    let render_options = request_render_options(&app_state, payload.render_options.as_ref())?;
+    let mode = payload.missing_variables.unwrap_or(app_state.config.missing_variables);
+    let locale = request_locale(payload.locale.as_deref())?;
+    let (template_id, revision) = match &payload.template {
         Some(template_name) => {
-            let template_path = format!("{}/{}", app_state.template_dir.display(), template_name);
-            app_state
-                .get_template(&template_path)
-                .await
-                .map_err(|e| {
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Failed to read template file {}: {}", template_path, e),
-                    )
-                })?
-        }
-        None => payload
-            .mjml
-            .clone()
-            .ok_or((StatusCode::BAD_REQUEST, "Missing MJML input".to_string()))?,
+            let (id, revision) = resolve_requested(&app_state, template_name, locale.as_ref()).await?;
+            (Some(id), revision)
+        }
+        None => (None, None),
+    };
+    if payload.debug {
+        return debug_convert(&app_state, &payload, template_id.as_ref(), revision, &render_options, mode, locale.as_ref()).await;
+    }
+    let rendered = match &template_id {
+        Some(id) => {
+            let (template, _) = load_requested(&app_state, id, revision).await?;
+            if let Some(schema) = &template.schema {
+                let violations = schema.validate(&payload.payload);
+                if !violations.is_empty() {
+                    return Ok(invalid_payload(id.as_str(), violations));
+                }
+            }
+            render_compiled(&app_state, &template, &payload.payload, &render_options, payload.render_options.is_none(), mode, locale.as_ref())
+        }
+        None => {
+            let mjml_content = payload
+                .mjml
+                .as_deref()
+                .ok_or((StatusCode::BAD_REQUEST, "Missing MJML input".to_string()))?;
+            render_inline(&app_state, mjml_content, &payload.payload, &render_options, mode, locale.as_ref())
+        }
+    };
+    let (parsed, rendered, unresolved) = match rendered {
+        Ok(rendered) => rendered,
+        Err(failure) => return failure.into_response(),
+    };
+    let mut response = match output {
+        OutputFormat::Html => (StatusCode::OK, Html(rendered)).into_response(),
+        OutputFormat::Text => (StatusCode::OK, html_to_text(&rendered)).into_response(),
+        OutputFormat::Json => {
+            let template_name = template_id.as_ref().map(|id| requested_name(id, revision));
+            let mut response = ConvertResponse::new(&parsed, rendered, template_name, started);
+            response.add_unresolved(&unresolved);
+            (StatusCode::OK, Json(response)).into_response()
+        }
+        OutputFormat::Eml => {
+            let email = payload.email.clone().unwrap_or_default();
+            let message = build_message(&email, parsed.get_title().as_deref(), &html_to_text(&rendered), &rendered)
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+            (StatusCode::OK, [(header::CONTENT_TYPE, "message/rfc822")], message).into_response()
+        }
+    };
+    if !unresolved.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&unresolved.join(", ")) {
+            response.headers_mut().insert(UNRESOLVED_VARIABLES, value);
+        }
+    }
+    Ok(response)
+}
+
+/// Answers a `debug` request with a [`DebugReport`] of the render.
+async fn debug_convert(
+    app_state: &AppState,
+    payload: &MjmlInput,
+    template_id: Option<&TemplateId>,
+    revision: Option<u64>,
+    render_options: &RenderOptions,
+    mode: MissingVariables,
+    locale: Option<&LocaleTag>,
+) -> Result<Response, (StatusCode, String)> {
+    let started = Instant::now();
+    let (template, cache) = match template_id {
+        Some(id) => {
+            let (template, layer) = load_requested(app_state, id, revision).await?;
+            (template, Some(layer))
+        }
+        None => {
+            let source = payload
+                .mjml
+                .as_deref()
+                .ok_or((StatusCode::BAD_REQUEST, "Missing MJML input".to_string()))?;
+            let server_options = resolve_render_options(&app_state.config.render_options, None);
+            let template = compile_template(INLINE_TEMPLATE, source, &server_options, app_state.includes.parser_options(None))
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+            (Arc::new(template), None)
+        }
+    };
+    let load = elapsed_ms(started);
+    if let (Some(id), Some(schema)) = (template_id, &template.schema) {
+        let violations = schema.validate(&payload.payload);
+        if !violations.is_empty() {
+            return Ok(invalid_payload(id.as_str(), violations));
+        }
+    }
+    let default_options = payload.render_options.is_none();
+    let mut report = DebugReport::render(app_state, &template, &payload.payload, render_options, default_options, mode, locale);
+    report.template = template_id.map(|id| requested_name(id, revision));
+    report.cache = cache;
+    report.timings_ms.load = load;
+    report.timings_ms.total = elapsed_ms(started);
+    Ok((report.status(), Json(report)).into_response())
+}
+
+/// 422 listing every place the payload doesn't match the template's schema.
+fn invalid_payload(template_name: &str, violations: Vec<Violation>) -> Response {
+    let body = json!({
+        "error": format!("Payload does not match the schema of {}", template_name),
+        "violations": violations,
+    });
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response()
+}
+
+/// Renders one template against many payloads and streams back one NDJSON
+/// line per payload, in order. The body is either a JSON [`BatchInput`] or,
+/// with `Content-Type: application/x-ndjson`, a stream whose first line is the
+/// `BatchInput` header and every following line one payload.
+pub async fn convert_batch(
+    State(app_state): State<AppState>,
+    request: Request<Body>,
+) -> Result<Response, (StatusCode, String)> {
+    let is_ndjson = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(NDJSON));
+
+    let max_batch_bytes = app_state.config.max_batch_bytes();
+    let (input, payloads) = if is_ndjson {
+        let mut lines = Box::pin(ndjson_lines(request.into_body(), max_batch_bytes));
+        let header_line = match lines.next().await {
+            Some(line) => line?,
+            None => return Err((StatusCode::BAD_REQUEST, "Missing batch header line".to_string())),
+        };
+        let input: BatchInput = serde_json::from_str(&header_line)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid batch header: {}", e)))?;
+        let payloads = lines.map(|line| {
+            line.map_err(|(_, e)| e)
+                .and_then(|line| serde_json::from_str(&line).map_err(|e| format!("Invalid payload: {}", e)))
+        });
+        (input, payloads.boxed())
+    } else {
+        if content_length(request.headers()).is_some_and(|length| length > max_batch_bytes) {
+            return Err(too_large("The batch", max_batch_bytes));
+        }
+        let mut stream = request.into_body();
+        let mut body = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, format!("Failed to read request body: {}", e)))?;
+            if body.len() + chunk.len() > max_batch_bytes {
+                return Err(too_large("The batch", max_batch_bytes));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        let mut input: BatchInput = serde_json::from_slice(&body)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid batch input: {}", e)))?;
+        let payloads = std::mem::take(&mut input.payloads);
+        (input, stream::iter(payloads.into_iter().map(Ok)).boxed())
     };
 
-    let mjml_content = app_state
-        .handlebars
-        .render_template(&mjml_content, &payload.payload)
+    let render_options = request_render_options(&app_state, input.render_options.as_ref())?;
+    let default_options = input.render_options.is_none();
+    let mode = input.missing_variables.unwrap_or(app_state.config.missing_variables);
+    let locale = request_locale(input.locale.as_deref())?;
+    let renderer = match &input.template {
+        Some(template_name) => {
+            let (id, revision) = resolve_requested(&app_state, template_name, locale.as_ref()).await?;
+            let (template, _) = load_requested(&app_state, &id, revision).await?;
+            BatchRenderer::new(&app_state, template, requested_name(&id, revision), render_options, default_options, mode, locale)
+        }
+        None => {
+            let source = input
+                .mjml
+                .ok_or((StatusCode::BAD_REQUEST, "Missing MJML input".to_string()))?;
+            BatchRenderer::inline(&app_state, source, render_options, default_options, mode, locale)
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?
+        }
+    };
+
+    let lines = render_lines(Arc::new(renderer), payloads, app_state.config.batch_concurrency());
+    Ok((StatusCode::OK, [(header::CONTENT_TYPE, NDJSON)], StreamBody::new(lines)).into_response())
+}
+
+/// Shows which files each template was built from, and the reverse.
+pub async fn dependency_graph(
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    require_admin(&app_state, &headers)?;
+    Ok(Json(app_state.dependencies.snapshot()).into_response())
+}
+
+/// Checks the `Authorization: Bearer` header against the configured
+/// `admin_token`. Without one, admin endpoints don't exist.
+fn require_admin(app_state: &AppState, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    let Some(expected) = app_state.config.admin_token.as_deref() else {
+        return Err((StatusCode::NOT_FOUND, "Admin endpoints are disabled".to_string()));
+    };
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => Err((StatusCode::UNAUTHORIZED, "Missing or invalid admin token".to_string())),
+    }
+}
+
+/// Compares without stopping at the first difference, so response times
+/// don't reveal how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Fetches a compiled template from the cache, loading it on a miss.
+async fn load_template(
+    app_state: &AppState,
+    id: &TemplateId,
+) -> Result<(Arc<CompiledTemplate>, CacheLayer), (StatusCode, String)> {
+    app_state
+        .fetch_template(id)
+        .await
         .map_err(|e| {
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Handlebars rendering error: {}", e),
+                format!("Failed to load template {}: {}", id, e),
             )
+        })
+}
+
+/// Picks the most specific variant of `template_name` that exists for
+/// `locale`, falling back to `template_name` itself.
+async fn resolve_template(
+    app_state: &AppState,
+    template_name: &str,
+    locale: Option<&LocaleTag>,
+) -> Result<TemplateId, (StatusCode, String)> {
+    let mut names = localized_names(template_name, locale);
+    let name = names.pop().expect("the template name itself is always a candidate");
+    for variant in names {
+        // A variant name that isn't valid can't exist on disk, and
+        // `has_template` checks where the others lead.
+        let Ok(id) = parse_template_name(&variant) else { continue };
+        if app_state.has_template(&id).await {
+            return Ok(id);
+        }
+    }
+    template_id(app_state, &name).await
+}
+
+/// Resolves the `template` of a render request. `name@rev` names a revision
+/// from the template's history, which is rendered as is, without looking for
+/// locale variants.
+async fn resolve_requested(
+    app_state: &AppState,
+    template_name: &str,
+    locale: Option<&LocaleTag>,
+) -> Result<(TemplateId, Option<u64>), (StatusCode, String)> {
+    match history::split_revision(template_name) {
+        Some((name, rev)) => Ok((template_id(app_state, name).await?, Some(rev))),
+        None => Ok((resolve_template(app_state, template_name, locale).await?, None)),
+    }
+}
+
+/// Loads the current template, or compiles a revision of it. Revisions are
+/// not cached.
+async fn load_requested(
+    app_state: &AppState,
+    id: &TemplateId,
+    revision: Option<u64>,
+) -> Result<(Arc<CompiledTemplate>, CacheLayer), (StatusCode, String)> {
+    let Some(rev) = revision else {
+        return load_template(app_state, id).await;
+    };
+    let source = history::read_revision(&app_state.template_dir, id, rev)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Revision {} of {} not found", rev, id)))?;
+    let template = app_state
+        .compile_revision(id, &source)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to load template {}@{}: {}", id, rev, e)))?;
+    Ok((Arc::new(template), CacheLayer::Disk))
+}
+
+/// Name of a rendered template in responses, with its revision if any.
+fn requested_name(id: &TemplateId, revision: Option<u64>) -> String {
+    match revision {
+        Some(rev) => format!("{}@{}", id, rev),
+        None => id.to_string(),
+    }
+}
+
+/// Parses the `locale` of a request, answering 400 for malformed tags.
+fn request_locale(locale: Option<&str>) -> Result<Option<LocaleTag>, (StatusCode, String)> {
+    locale
+        .map(LocaleTag::new)
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))
+}
+
+/// Last segments of the endpoints below `/templates/{name}`, which a file
+/// name can't end with or it could never be fetched.
+const SUB_RESOURCES: [&str; 4] = ["variables", "revisions", "diff", "rollback"];
+
+/// Validates a client-supplied template name, answering 400 for names that
+/// would reach outside the template directory or into its history, or that
+/// read as one of the endpoints below a template. Where the name leads on
+/// disk is only checked for templates that aren't cached, off the async
+/// executor.
+async fn template_id(app_state: &AppState, template_name: &str) -> Result<TemplateId, (StatusCode, String)> {
+    let id = parse_template_name(template_name)?;
+    if app_state.is_cached(&id) {
+        return Ok(id);
+    }
+    let (template_dir, checked) = (app_state.template_dir.clone(), id.clone());
+    tokio::task::spawn_blocking(move || checked.resolve_in(&template_dir))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    Ok(id)
+}
+
+/// The checks of [`template_id`] that only look at the name.
+fn parse_template_name(template_name: &str) -> Result<TemplateId, (StatusCode, String)> {
+    let id = TemplateId::new(template_name).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if history::is_history(&id) {
+        return Err((StatusCode::BAD_REQUEST, format!("Invalid template name {:?}: {} holds the revision history", template_name, history::HISTORY_DIR)));
+    }
+    let segments: Vec<&str> = id.as_str().split('/').collect();
+    let reserved = match segments.as_slice() {
+        [.., _, "revisions", rev] => SUB_RESOURCES.contains(rev) || rev.parse::<u64>().is_ok(),
+        [.., _, last] => SUB_RESOURCES.contains(last),
+        _ => false,
+    };
+    if reserved {
+        return Err((StatusCode::BAD_REQUEST, format!("Invalid template name {:?}: it names an endpoint below a template", template_name)));
+    }
+    Ok(id)
+}
+
+/// Parsed MJML, its HTML, and the paths `report` mode couldn't resolve.
+pub type Rendered = (Arc<Mjml>, String, Vec<String>);
+
+/// Why a render failed.
+#[derive(Debug)]
+pub enum RenderFailure {
+    /// Answered with the status and message as plain text.
+    Request(StatusCode, String),
+    /// The expanded MJML didn't parse or render. `location` is where in the
+    /// template the offending text came from, when that is known.
+    Mjml { message: String, location: Option<SourceLocation> },
+}
+
+impl From<(StatusCode, String)> for RenderFailure {
+    fn from((status, message): (StatusCode, String)) -> Self {
+        RenderFailure::Request(status, message)
+    }
+}
+
+impl RenderFailure {
+    /// The error message, and the template location of MJML errors.
+    pub fn into_parts(self) -> (String, Option<SourceLocation>) {
+        match self {
+            RenderFailure::Request(_, message) => (message, None),
+            RenderFailure::Mjml { message, location } => (message, location),
+        }
+    }
+
+    /// MJML errors become a 400 JSON body with their `location`.
+    fn into_response(self) -> Result<Response, (StatusCode, String)> {
+        match self {
+            RenderFailure::Request(status, message) => Err((status, message)),
+            RenderFailure::Mjml { message, location } => {
+                let body = json!({ "error": message, "location": location });
+                Ok((StatusCode::BAD_REQUEST, Json(body)).into_response())
+            }
+        }
+    }
+}
+
+/// An MJML error, and the byte offset of the expanded MJML it points at.
+type MjmlError = (String, Option<usize>);
+
+/// Renders a compiled template with `data`. Static templates skip Handlebars
+/// and mrml parsing, and also skip rendering when `default_options` says the
+/// request didn't override the server-wide render options.
+pub fn render_compiled(
+    app_state: &AppState,
+    template: &CompiledTemplate,
+    data: &Value,
+    render_options: &RenderOptions,
+    default_options: bool,
+    mode: MissingVariables,
+    locale: Option<&LocaleTag>,
+) -> Result<Rendered, RenderFailure> {
+    if let Some(static_render) = &template.static_render {
+        if default_options {
+            return Ok((static_render.mjml.clone(), static_render.html.clone(), Vec::new()));
+        }
+        let rendered = static_render.mjml.render(render_options).map_err(|e| RenderFailure::Mjml {
+            message: format!("Couldn't render MJML template: {}", e),
+            location: None,
         })?;
-    let parsed = mrml::parse(&mjml_content).map_err(|e| {
-        (
-            StatusCode::BAD_REQUEST,
-            format!("Invalid MJML input: {}", e),
-        )
+        return Ok((static_render.mjml.clone(), rendered, Vec::new()));
+    }
+
+    let handlebars = app_state.registry(mode);
+    let (mjml_content, unresolved) = with_locale(locale, || {
+        track_unresolved(mode, || template.render_handlebars(&handlebars.read().unwrap(), data))
+    });
+    let mjml_content = mjml_content.map_err(|e| (render_error_status(&e), format!("Handlebars rendering error: {}", e)))?;
+    let (parsed, rendered) = render_mjml(&mjml_content, &template.parser_options, render_options).map_err(|(message, offset)| {
+        let location = offset.and_then(|offset| {
+            with_locale(locale, || template.locate(&handlebars.read().unwrap(), &app_state.template_dir, data, offset))
+        });
+        RenderFailure::Mjml { message, location }
+    })?;
+    Ok((parsed, rendered, unresolved))
+}
+
+/// Renders MJML sent in the request. Errors are located in it as the file
+/// `inline`.
+fn render_inline(
+    app_state: &AppState,
+    source: &str,
+    data: &Value,
+    render_options: &RenderOptions,
+    mode: MissingVariables,
+    locale: Option<&LocaleTag>,
+) -> Result<Rendered, RenderFailure> {
+    let handlebars = app_state.registry(mode);
+    let (mjml_content, unresolved) = with_locale(locale, || {
+        track_unresolved(mode, || handlebars.read().unwrap().render_template(source, data))
+    });
+    let mjml_content = mjml_content.map_err(|e| (render_error_status(&e), format!("Handlebars rendering error: {}", e)))?;
+    let parser_options = app_state.includes.parser_options(None);
+    let (parsed, rendered) = render_mjml(&mjml_content, &parser_options, render_options).map_err(|(message, offset)| {
+        let location = offset.and_then(|offset| {
+            let mut template = Template::compile(source).ok()?;
+            template.name = Some(INLINE_TEMPLATE.to_string());
+            with_locale(locale, || {
+                source_map::locate(&handlebars.read().unwrap(), &app_state.template_dir, &template, source, data, offset)
+            })
+        });
+        RenderFailure::Mjml { message, location }
     })?;
-    let rendered = parsed.render(&RenderOptions::default()).map_err(|e| {
-        (
-            StatusCode::BAD_REQUEST,
-            format!("Couldn't render MJML template: {}", e),
-        )
+    Ok((parsed, rendered, unresolved))
+}
+
+/// Parses expanded MJML and renders it to HTML. Only parse errors have an
+/// offset: mrml's render errors don't say where in the MJML they are.
+pub fn render_mjml(
+    mjml_content: &str,
+    parser_options: &ParserOptions,
+    render_options: &RenderOptions,
+) -> Result<(Arc<Mjml>, String), MjmlError> {
+    let mjml_content = strip_invalid_xml_chars(mjml_content);
+    let parsed = mrml::parse_with_options(mjml_content.as_ref(), parser_options).map_err(|e| {
+        (format!("Invalid MJML input: {}", e), error_offset(&mjml_content, &e))
     })?;
-    Ok((StatusCode::OK, rendered).into_response())
+    let rendered = parsed
+        .render(render_options)
+        .map_err(|e| (format!("Couldn't render MJML template: {}", e), None))?;
+    Ok((Arc::new(parsed), rendered))
 }
 
-/// Lists all MJML templates in the ./templates directory.
+/// Layers a request's `render_options` over the server-wide defaults.
+fn request_render_options(
+    app_state: &AppState,
+    value: Option<&Value>,
+) -> Result<RenderOptions, (StatusCode, String)> {
+    let request_options = value
+        .cloned()
+        .map(RenderOptionsInput::from_value)
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    Ok(resolve_render_options(&app_state.config.render_options, request_options.as_ref()))
+}
+
+impl ConvertResponse {
+    /// Collects the metadata of a finished render.
+    /// Adds a warning for each path `report` mode couldn't resolve.
+    pub fn add_unresolved(&mut self, unresolved: &[String]) {
+        self.warnings
+            .extend(unresolved.iter().map(|path| format!("Unresolved variable: {}", path)));
+    }
+
+    pub fn new(parsed: &Mjml, html: String, template: Option<String>, started: Instant) -> Self {
+        let title = parsed.get_title();
+        let mut warnings = Vec::new();
+        if title.as_deref().is_none_or(|title| title.trim().is_empty()) {
+            warnings.push("Missing <mj-title>, the email will have no <title>".to_string());
+        }
+        if html.len() > GMAIL_CLIP_BYTES {
+            warnings.push(format!(
+                "Rendered HTML is {} bytes, Gmail clips messages above {} bytes",
+                html.len(),
+                GMAIL_CLIP_BYTES
+            ));
+        }
+        ConvertResponse {
+            text: html_to_text(&html),
+            title,
+            preview: parsed.get_preview(),
+            size_bytes: html.len(),
+            warnings,
+            template,
+            render_ms: elapsed_ms(started),
+            html,
+        }
+    }
+}
+
+/// Picks the output format from the `Accept` header when the request body
+/// doesn't name one. The supported media type with the highest `q` wins,
+/// the first listed on a tie; `q=0` excludes a type.
+fn negotiate_output(headers: &HeaderMap) -> OutputFormat {
+    let accept = match headers.get(header::ACCEPT).and_then(|value| value.to_str().ok()) {
+        Some(accept) => accept,
+        None => return OutputFormat::Html,
+    };
+    let mut best: Option<(f32, OutputFormat)> = None;
+    for entry in accept.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let format = match parts.next().unwrap_or_default().to_ascii_lowercase().as_str() {
+            "application/json" => OutputFormat::Json,
+            "message/rfc822" => OutputFormat::Eml,
+            "text/html" => OutputFormat::Html,
+            "text/plain" => OutputFormat::Text,
+            _ => continue,
+        };
+        // A malformed weight counts as the default of 1.
+        let quality = parts
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .and_then(|(_, value)| value.trim().parse::<f32>().ok())
+            .filter(|quality| (0.0..=1.0).contains(quality))
+            .unwrap_or(1.0);
+        if quality > 0.0 && best.as_ref().is_none_or(|(best_quality, _)| quality > *best_quality) {
+            best = Some((quality, format));
+        }
+    }
+    best.map_or(OutputFormat::Html, |(_, format)| format)
+}
+
+/// Lists the MJML templates of the template directory and its folders, see
+/// [`ListQuery`] for the filters and pagination.
 pub async fn list_templates(
     State(app_state): State<AppState>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    use std::fs;
-    use axum::Json;
-    let templates_dir = app_state.template_dir.clone();
-    let entries = match fs::read_dir(templates_dir) {
-        Ok(entries) => entries,
+    Query(query): Query<ListQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    query.limit().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let listing = tokio::task::spawn_blocking(move || listing::list_templates(&app_state, &query))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(listing).into_response())
+}
+
+/// `GET /templates/{name}` answers the raw source of a file of the template
+/// directory with its `ETag`. Below it, `/variables` answers the payload
+/// paths a template uses, `/revisions` its history, `/revisions/{rev}` the
+/// source of one revision and `/diff?from=&to=` the changes between two.
+pub async fn get_template_file(
+    State(app_state): State<AppState>,
+    AxumPath(path): AxumPath<String>,
+    uri: Uri,
+) -> Result<Response, (StatusCode, String)> {
+    if let Some(template_name) = path.strip_suffix("/variables") {
+        return template_variables(&app_state, template_name).await;
+    }
+    if let Some(template_name) = path.strip_suffix("/revisions") {
+        return template_revisions(&app_state, template_name).await;
+    }
+    if let Some((template_name, rev)) = path.rsplit_once("/revisions/") {
+        if let Ok(rev) = rev.parse() {
+            return template_revision(&app_state, template_name, rev).await;
+        }
+    }
+    if let Some(template_name) = path.strip_suffix("/diff") {
+        let Query(query) = Query::<DiffQuery>::try_from_uri(&uri).map_err(|e| (StatusCode::BAD_REQUEST, e.body_text()))?;
+        return template_diff(&app_state, template_name, query).await;
+    }
+    let id = template_id(&app_state, &path).await?;
+    let source = read_template_file(&app_state, &id)
+        .await?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Template {} not found", id)))?;
+    let etag = etag(&source);
+    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8".to_string()), (header::ETAG, etag)], source).into_response())
+}
+
+/// The payload paths a template uses, with an example payload and a draft
+/// JSON Schema built from them.
+async fn template_variables(app_state: &AppState, template_name: &str) -> Result<Response, (StatusCode, String)> {
+    let id = template_id(app_state, template_name).await?;
+    if !app_state.has_template(&id).await {
+        return Err((StatusCode::NOT_FOUND, format!("Template {} not found", id)));
+    }
+    let (template, _) = load_template(app_state, &id).await?;
+    let variables = template.variables(&app_state.handlebars.read().unwrap());
+    Ok(Json(variables).into_response())
+}
+
+/// Every revision of a template, oldest first.
+async fn template_revisions(app_state: &AppState, template_name: &str) -> Result<Response, (StatusCode, String)> {
+    let id = template_id(app_state, template_name).await?;
+    let revisions = history::revisions(&app_state.template_dir, &id).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    if revisions.is_empty() && read_template_file(app_state, &id).await?.is_none() {
+        return Err((StatusCode::NOT_FOUND, format!("Template {} not found", id)));
+    }
+    Ok(Json(json!({"template": id.as_str(), "revisions": revisions})).into_response())
+}
+
+/// The source of one revision, with the `ETag` the file had then.
+async fn template_revision(app_state: &AppState, template_name: &str, rev: u64) -> Result<Response, (StatusCode, String)> {
+    let id = template_id(app_state, template_name).await?;
+    let source = read_revision(app_state, &id, rev)?;
+    let etag = etag(&source);
+    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8".to_string()), (header::ETAG, etag)], source).into_response())
+}
+
+/// Unified diff from revision `from` to revision `to`, or to the current
+/// file when `to` is left out. 422 when they differ too much to compare.
+async fn template_diff(app_state: &AppState, template_name: &str, query: DiffQuery) -> Result<Response, (StatusCode, String)> {
+    let id = template_id(app_state, template_name).await?;
+    let old = read_revision(app_state, &id, query.from)?;
+    let (new, new_label) = match query.to {
+        Some(to) => (read_revision(app_state, &id, to)?, format!("{}@{}", id, to)),
+        None => {
+            let current = read_template_file(app_state, &id)
+                .await?
+                .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Template {} not found", id)))?;
+            (current, id.to_string())
+        }
+    };
+    let diff = history::unified_diff(&old, &new, &format!("{}@{}", id, query.from), &new_label)
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    Ok(([(header::CONTENT_TYPE, "text/x-diff; charset=utf-8")], diff).into_response())
+}
+
+/// Content of revision `rev` of `id`, 404 when there is none.
+fn read_revision(app_state: &AppState, id: &TemplateId, rev: u64) -> Result<String, (StatusCode, String)> {
+    history::read_revision(&app_state.template_dir, id, rev)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Revision {} of {} not found", rev, id)))
+}
+
+/// `POST /templates/{name}/rollback` with `{"rev": 3}` makes revision 3 the
+/// current content again, recorded as a new revision. An `If-Match`, when
+/// sent, must match the content being replaced. Needs the admin token.
+pub async fn post_template_file(
+    State(app_state): State<AppState>,
+    AxumPath(path): AxumPath<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, (StatusCode, String)> {
+    require_admin(&app_state, &headers)?;
+    let Some(template_name) = path.strip_suffix("/rollback") else {
+        return Err((StatusCode::NOT_FOUND, format!("No POST endpoint at /templates/{}", path)));
+    };
+    let input: RollbackInput = serde_json::from_slice(&body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid rollback input: {}", e)))?;
+    let id = template_id(&app_state, template_name).await?;
+    let _writes = app_state.lock_writes().await;
+    let source = read_revision(&app_state, &id, input.rev)?;
+    let current = read_template_file(&app_state, &id).await?;
+    match (&current, headers.get(header::IF_MATCH)) {
+        (Some(current), Some(if_match)) => check_if_match(if_match, current)?,
+        (None, Some(_)) => return Err((StatusCode::PRECONDITION_FAILED, format!("Template {} doesn't exist", id))),
+        (_, None) => {}
+    }
+    // The partials it uses may have changed since.
+    check_source(&id, &source)?;
+
+    let revision = write_revision(&app_state, &id, current.as_deref(), &source, uploader(&headers), Some(input.rev)).await?;
+    Ok(([(header::ETAG, etag(&source))], Json(revision)).into_response())
+}
+
+/// `PUT /templates/{name}` creates or replaces a file of the template
+/// directory. Replacing needs an `If-Match` with the file's current `ETag`,
+/// so an edit based on an outdated copy answers 412 instead of losing the
+/// changes made since. The cache is updated before answering. Needs the
+/// admin token.
+pub async fn put_template_file(
+    State(app_state): State<AppState>,
+    AxumPath(path): AxumPath<String>,
+    headers: HeaderMap,
+    source: String,
+) -> Result<Response, (StatusCode, String)> {
+    require_admin(&app_state, &headers)?;
+    let id = template_id(&app_state, &path).await?;
+    if source.len() > app_state.config.max_file_bytes() {
+        return Err(too_large(id.as_str(), app_state.config.max_file_bytes()));
+    }
+    check_source(&id, &source)?;
+    let _writes = app_state.lock_writes().await;
+    let current = read_template_file(&app_state, &id).await?;
+    match (&current, headers.get(header::IF_MATCH)) {
+        (Some(current), Some(if_match)) => check_if_match(if_match, current)?,
+        (Some(_), None) => {
+            return Err((StatusCode::PRECONDITION_REQUIRED, format!("Replacing {} needs an If-Match header", id)));
+        }
+        (None, Some(_)) => return Err((StatusCode::PRECONDITION_FAILED, format!("Template {} doesn't exist", id))),
+        (None, None) => {}
+    }
+
+    write_revision(&app_state, &id, current.as_deref(), &source, uploader(&headers), None).await?;
+
+    let (status, message) = match current {
+        Some(_) => (StatusCode::OK, format!("Template {} replaced", id)),
+        None => (StatusCode::CREATED, format!("Template {} created", id)),
+    };
+    Ok((status, [(header::ETAG, etag(&source))], message).into_response())
+}
+
+/// `DELETE /templates/{name}` removes a file of the template directory and
+/// everything cached from it. An `If-Match`, when sent, must match. Needs
+/// the admin token.
+pub async fn delete_template_file(
+    State(app_state): State<AppState>,
+    AxumPath(path): AxumPath<String>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    require_admin(&app_state, &headers)?;
+    let id = template_id(&app_state, &path).await?;
+    let _writes = app_state.lock_writes().await;
+    let current = read_template_file(&app_state, &id)
+        .await?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Template {} not found", id)))?;
+    if let Some(if_match) = headers.get(header::IF_MATCH) {
+        check_if_match(if_match, &current)?;
+    }
+    remove_template_file(&app_state, &id).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Deletes the file `id` and everything built from it.
+async fn remove_template_file(app_state: &AppState, id: &TemplateId) -> Result<(), (StatusCode, String)> {
+    let file_path = id.resolve_in(&app_state.template_dir).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    tokio::fs::remove_file(&file_path)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete file: {}", e)))?;
+    app_state
+        .file_changed(id, true)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+/// Writes `source` to the file `id`, creating its folders.
+async fn write_template_file(app_state: &AppState, id: &TemplateId, source: &str) -> Result<(), (StatusCode, String)> {
+    let file_path = id.resolve_in(&app_state.template_dir).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if let Some(parent) = file_path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create directory: {}", e)))?;
+    }
+    write_atomically(&file_path, source.as_bytes())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to write file: {}", e)))
+}
+
+/// Writes `source` over the file `id`, which held `previous`, records it in
+/// the history, and brings everything built from the file up to date. If
+/// the history can't be recorded, `previous` is put back, so the current
+/// content of a file is always its last revision.
+async fn write_revision(
+    app_state: &AppState,
+    id: &TemplateId,
+    previous: Option<&str>,
+    source: &str,
+    uploader: Option<&str>,
+    rollback_of: Option<u64>,
+) -> Result<history::Revision, (StatusCode, String)> {
+    write_template_file(app_state, id, source).await?;
+    let revision = match history::record(&app_state.template_dir, id, previous, source, uploader, rollback_of) {
+        Ok(revision) => revision,
         Err(e) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to read templates directory: {}", e),
-            ))
+            let restored = match previous {
+                Some(previous) => write_template_file(app_state, id, previous).await.map_err(|(_, e)| e),
+                None => match id.resolve_in(&app_state.template_dir) {
+                    Ok(file_path) => tokio::fs::remove_file(file_path).await.map_err(|e| e.to_string()),
+                    Err(e) => Err(e),
+                },
+            };
+            if let Err(restore_error) = restored {
+                error!("Failed to restore {} after its history failed: {}", id, restore_error);
+            }
+            // Whatever is on disk now, nothing built from the file is stale.
+            if let Err(e) = app_state.file_changed(id, previous.is_none()) {
+                error!("Failed to reload {}: {}", id, e);
+            }
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, e));
         }
     };
+    app_state
+        .file_changed(id, false)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(revision)
+}
 
-    let templates: Vec<String> = entries
-        .filter_map(|entry| entry.ok())
-        .map(|entry| {
-            entry
-                .path()
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string()
-        })
-        .collect();
+/// Who made a change, from the `X-Uploader` header.
+fn uploader(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(history::UPLOADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|uploader| !uploader.is_empty())
+}
+
+/// Content of the file `id`, `None` when it doesn't exist.
+async fn read_template_file(app_state: &AppState, id: &TemplateId) -> Result<Option<String>, (StatusCode, String)> {
+    let path = id.resolve_in(&app_state.template_dir).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    match tokio::fs::read_to_string(&path).await {
+        Ok(source) => Ok(Some(source)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read {}: {}", id, e))),
+    }
+}
+
+/// Rejects content that would break the file it replaces: templates,
+/// partials and layouts must compile, JSON files must parse.
+fn check_source(id: &TemplateId, source: &str) -> Result<(), (StatusCode, String)> {
+    if id.as_str().ends_with(".mjml") {
+        Template::compile(source).map_err(|e| (StatusCode::BAD_REQUEST, format!("Handlebars template error in {}: {}", id, e)))?;
+    } else if id.as_str().ends_with(".json") {
+        serde_json::from_str::<Value>(source).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid JSON in {}: {}", id, e)))?;
+    }
+    Ok(())
+}
 
-    Ok(Json(templates))
+/// Strong `ETag` of a file's content.
+fn etag(content: &str) -> String {
+    format!("\"{}\"", content_hash(content.as_bytes()))
 }
 
-/// Uploads a new MJML template to the ./templates directory. Validates file type and MJML syntax.
+/// Answers 412 unless `if_match` is `*` or lists the `ETag` of `current`.
+fn check_if_match(if_match: &HeaderValue, current: &str) -> Result<(), (StatusCode, String)> {
+    let expected = etag(current);
+    let matches = if_match
+        .to_str()
+        .map(|value| value.split(',').map(str::trim).any(|tag| tag == "*" || tag == expected))
+        .unwrap_or(false);
+    if matches {
+        Ok(())
+    } else {
+        Err((StatusCode::PRECONDITION_FAILED, "The template changed since it was fetched".to_string()))
+    }
+}
+
+/// Uploads MJML templates to the ./templates directory, with their
+/// fixtures. Every file is read and checked before any is written, see
+/// [`check_upload`], and all of them replace their destination together, so
+/// a failed upload changes nothing. Like `PUT`, it needs the admin token,
+/// and replacing files needs an `If-Match` listing the `ETag` of each, or
+/// `*`. Answers the report of the checks.
 pub async fn upload_template(
     State(app_state): State<AppState>,
+    headers: HeaderMap,
     mut multipart: Multipart,
-) -> Result<impl IntoResponse, (axum::http::StatusCode, String)> {
-    use tracing::error;
-    let mut templates = Vec::new();
-
-    while let Ok(Some(mut field)) = multipart.next_field().await { // Added mut here
-        let file_name = match field.file_name() {
-            Some(name) => name.to_owned(),
-            None => {
-                return Err((
-                    axum::http::StatusCode::BAD_REQUEST,
-                    "Missing filename".to_string(),
-                ))
-            }
-        };
-
-        let content_type = field.content_type().unwrap_or("text/plain").to_string();
+) -> Result<Response, (StatusCode, String)> {
+    require_admin(&app_state, &headers)?;
+    let max_file_bytes = app_state.config.max_file_bytes();
+    let max_upload_bytes = app_state.config.max_upload_bytes();
+    if content_length(&headers).is_some_and(|length| length > max_upload_bytes) {
+        return Err(too_large("The upload", max_upload_bytes));
+    }
 
-        if content_type != "text/plain" {
-            return Err((
-                axum::http::StatusCode::BAD_REQUEST,
-                "Invalid file type. Only text/plain is allowed.".to_string(),
-            ));
+    let mut files: Vec<(TemplateId, String)> = Vec::new();
+    let mut upload_bytes = 0;
+    while let Some(mut field) = multipart.next_field().await.map_err(|e| (e.status(), e.body_text()))? {
+        let file_name = field
+            .file_name()
+            .map(str::to_string)
+            .ok_or((StatusCode::BAD_REQUEST, "Missing filename".to_string()))?;
+        let id = template_id(&app_state, &file_name).await?;
+        if files.iter().any(|(uploaded, _)| *uploaded == id) {
+            return Err((StatusCode::BAD_REQUEST, format!("{} is uploaded more than once", id)));
+        }
+        let content_type = field.content_type().unwrap_or("text/plain");
+        if content_type != "text/plain" && !(is_fixtures(&id) && content_type == "application/json") {
+            return Err((StatusCode::BAD_REQUEST, "Invalid file type. Only text/plain is allowed.".to_string()));
         }
 
         let mut buffer: Vec<u8> = Vec::with_capacity(8192);
-        while let Ok(Some(chunk)) = field.chunk().await {
+        while let Some(chunk) = field.chunk().await.map_err(|e| (e.status(), e.body_text()))? {
+            upload_bytes += chunk.len();
+            if upload_bytes > max_upload_bytes {
+                return Err(too_large("The upload", max_upload_bytes));
+            }
+            if buffer.len() + chunk.len() > max_file_bytes {
+                return Err(too_large(id.as_str(), max_file_bytes));
+            }
             buffer.extend_from_slice(&chunk);
         }
+        let content = String::from_utf8(buffer).map_err(|e| {
+            error!("Invalid UTF-8 sequence: {}", e);
+            (StatusCode::BAD_REQUEST, "Invalid UTF-8 encoding".to_string())
+        })?;
+        files.push((id, content));
+    }
+    if files.is_empty() {
+        return Ok((StatusCode::OK, "No files uploaded".to_string()).into_response());
+    }
+    // Compiling and rendering every file reads the disk and takes a while.
+    let check_state = app_state.clone();
+    let (files, reports) = tokio::task::spawn_blocking(move || {
+        let reports = check_upload(&check_state, &files);
+        (files, reports)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let reports = reports.map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let failed = reports.iter().filter(|report| !report.ok).count();
+    if failed > 0 {
+        let body = json!({"error": format!("{} of {} templates failed validation", failed, reports.len()), "templates": reports});
+        return Ok((StatusCode::BAD_REQUEST, Json(body)).into_response());
+    }
 
-        match String::from_utf8(buffer) {
-            Ok(mjml_content) => {
-                let parsed = mrml::parse(&mjml_content);
-
-                match parsed {
-                    Ok(_) => {
-                        let file_path = format!("./{}/{}", app_state.clone().template_dir.display(), file_name);
-                        let file = File::create(file_path)
-                            .await
-                            .map_err(|e| {
-                                (
-                                    axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                                    format!("Failed to create file: {}", e),
-                                )
-                            })?;
-                        let mut buffer_writer = AsyncBufWriter::new(file);
-                        buffer_writer
-                            .write_all(mjml_content.as_bytes())
-                            .await
-                            .map_err(|e| {
-                                (
-                                    axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                                    format!("Failed to write file: {}", e),
-                                )
-                            })?;
-                        buffer_writer.flush().await.map_err(|e| {
-                            (
-                                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                                format!("Failed to flush file: {}", e),
-                            )
-                        })?;
-                        templates.push(file_name);
-                    }
-                    Err(_) => {
-                        return Err((
-                            axum::http::StatusCode::BAD_REQUEST,
-                            "Invalid MJML input".to_string(),
-                        ))
-                    }
-                }
+    let _writes = app_state.lock_writes().await;
+    // Keep what the upload replaces, to restore it and for the history.
+    let mut replaced = Vec::with_capacity(files.len());
+    for (id, _) in &files {
+        let previous = read_template_file(&app_state, id).await?;
+        match (&previous, headers.get(header::IF_MATCH)) {
+            (Some(previous), Some(if_match)) => check_if_match(if_match, previous)?,
+            (Some(_), None) => {
+                return Err((StatusCode::PRECONDITION_REQUIRED, format!("Replacing {} needs an If-Match header", id)));
             }
+            (None, _) => {}
+        }
+        replaced.push(previous);
+    }
+    let mut staged = Vec::with_capacity(files.len());
+    for ((id, mjml_content), previous) in files.iter().zip(replaced) {
+        let file_path = id.resolve_in(&app_state.template_dir).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        if let Some(parent) = file_path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create directory: {}", e)))?;
+        }
+        let file = StagedFile::write(&file_path, mjml_content.as_bytes())
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to write {}: {}", id, e)))?;
+        staged.push((id, file, previous));
+    }
+
+    let mut committed: Vec<(&TemplateId, PathBuf, Option<String>)> = Vec::with_capacity(staged.len());
+    for (id, file, previous) in staged {
+        let file_path = file.path().to_path_buf();
+        if let Err(e) = file.commit().await {
+            restore_files(&app_state, committed).await;
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to write {}: {}", id, e)));
+        }
+        committed.push((id, file_path, previous));
+    }
+
+    // Every file is in place: record them all, or undo the whole upload.
+    let mut recorded = Vec::with_capacity(committed.len());
+    for ((id, _, previous), (_, mjml_content)) in committed.iter().zip(&files) {
+        match history::record(&app_state.template_dir, id, previous.as_deref(), mjml_content, uploader(&headers), None) {
+            Ok(revision) => recorded.push((*id, revision)),
             Err(e) => {
-                error!("Invalid UTF-8 sequence: {}", e);
-                return Err((
-                    axum::http::StatusCode::BAD_REQUEST,
-                    "Invalid UTF-8 encoding".to_string(),
-                ));
+                for (id, revision) in recorded {
+                    if let Err(e) = history::discard(&app_state.template_dir, id, &revision) {
+                        error!("{}", e);
+                    }
+                }
+                restore_files(&app_state, committed).await;
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, e));
             }
         }
     }
+    // The upload took effect, so a file that fails to reload doesn't stop
+    // the others from being brought up to date.
+    for (id, _, _) in &committed {
+        if let Err(e) = app_state.file_changed(id, false) {
+            error!("Failed to reload {}: {}", id, e);
+        }
+    }
+    let uploaded: Vec<&str> = files.iter().map(|(id, _)| id.as_str()).collect();
+    Ok(Json(json!({"uploaded": uploaded, "templates": reports})).into_response())
+}
 
-    if templates.is_empty() {
-        Ok((axum::http::StatusCode::OK, "No files uploaded".to_string()))
-    } else {
-        Ok((
-            axum::http::StatusCode::OK,
-            format!("Templates {:?} uploaded successfully", templates),
-        ))
+/// Puts back the files an upload already replaced when a later one failed.
+async fn restore_files(app_state: &AppState, committed: Vec<(&TemplateId, PathBuf, Option<String>)>) {
+    for (id, file_path, previous) in committed.into_iter().rev() {
+        let removed = previous.is_none();
+        let restored = match previous {
+            Some(previous) => write_atomically(&file_path, previous.as_bytes()).await,
+            None => tokio::fs::remove_file(&file_path).await,
+        };
+        if let Err(e) = restored {
+            error!("Failed to restore {} after a failed upload: {}", id, e);
+        }
+        if let Err(e) = app_state.file_changed(id, removed) {
+            error!("Failed to reload {}: {}", id, e);
+        }
     }
 }
+
+/// The `Content-Length` a request announces, if any.
+fn content_length(headers: &HeaderMap) -> Option<usize> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// 413 for `what` going over `limit` bytes.
+fn too_large(what: &str, limit: usize) -> (StatusCode, String) {
+    (StatusCode::PAYLOAD_TOO_LARGE, format!("{} is larger than the limit of {} bytes", what, limit))
+}
@@ -0,0 +1,61 @@
+use std::cmp::Ordering;
+
+use handlebars::{Handlebars, Helper, RenderError};
+use serde_json::Value;
+
+use super::{param, register_value, required};
+
+/// Replaces Handlebars' built-in operators, which compare integers only:
+/// numbers compare by value whatever their JSON representation, strings
+/// compare lexicographically, and `and`/`or` take any number of operands.
+pub fn register(handlebars: &mut Handlebars<'static>) {
+    register_value(handlebars, "eq", |h| Ok(Value::Bool(equal(operand(h, 0)?, operand(h, 1)?))));
+    register_value(handlebars, "ne", |h| Ok(Value::Bool(!equal(operand(h, 0)?, operand(h, 1)?))));
+    for (name, accepts) in [
+        ("gt", Ordering::is_gt as fn(Ordering) -> bool),
+        ("gte", Ordering::is_ge),
+        ("lt", Ordering::is_lt),
+        ("lte", Ordering::is_le),
+    ] {
+        register_value(handlebars, name, move |h| Ok(Value::Bool(accepts(compare(h)?))));
+    }
+    register_value(handlebars, "and", |h| {
+        Ok(Value::Bool(!h.params().is_empty() && h.params().iter().all(|param| truthy(param.value()))))
+    });
+    register_value(handlebars, "or", |h| Ok(Value::Bool(h.params().iter().any(|param| truthy(param.value())))));
+    register_value(handlebars, "not", |h| Ok(Value::Bool(!truthy(param(h, 0)))));
+}
+
+fn operand<'a>(h: &'a Helper<'_, '_>, index: usize) -> Result<&'a Value, RenderError> {
+    required(h, index, "operand")
+}
+
+/// `1` equals `1.0`; everything else compares as JSON.
+fn equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => left.as_f64() == right.as_f64(),
+        _ => left == right,
+    }
+}
+
+fn compare(h: &Helper<'_, '_>) -> Result<Ordering, RenderError> {
+    let (left, right) = (operand(h, 0)?, operand(h, 1)?);
+    let ordering = match (left, right) {
+        (Value::Number(left), Value::Number(right)) => left.as_f64().partial_cmp(&right.as_f64()),
+        (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+        _ => None,
+    };
+    ordering.ok_or_else(|| RenderError::new(format!("{}: can't compare {} with {}", h.name(), left, right)))
+}
+
+/// Handlebars' truthiness: `false`, `null`, `0`, `""`, `[]` and `{}` are false.
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(value) => *value,
+        Value::Number(number) => number.as_f64() != Some(0.0),
+        Value::String(text) => !text.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(fields) => !fields.is_empty(),
+    }
+}
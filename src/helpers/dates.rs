@@ -0,0 +1,185 @@
+use std::{fmt::Write, sync::Arc};
+
+use handlebars::{Handlebars, RenderError};
+use serde_json::Value;
+
+use super::timezone::{civil_from_days, days_from_civil, format_offset, weekday_of, ZoneLoader};
+use super::{hash_str, param, register_value, required};
+
+const MONTHS: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June",
+    "July", "August", "September", "October", "November", "December",
+];
+const WEEKDAYS: [&str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+
+/// `0000-01-01T00:00:00Z` and `9999-12-31T23:59:59Z`. Timestamps outside
+/// them are refused, which keeps the calendar arithmetic from overflowing.
+const MIN_TIMESTAMP: i64 = -62_167_219_200;
+const MAX_TIMESTAMP: i64 = 253_402_300_799;
+
+/// `{{format_date value "%d %B %Y, %H:%M" tz="Europe/Paris"}}`
+///
+/// `value` is an RFC 3339 timestamp, a `YYYY-MM-DD` date or Unix seconds.
+/// Timestamps are shown in `tz` (the configured default otherwise); a bare
+/// date is a calendar day and is shown as is. The format defaults to
+/// `%Y-%m-%d`.
+pub fn register(handlebars: &mut Handlebars<'static>, zones: Arc<ZoneLoader>, default_timezone: &str) {
+    let default_timezone = default_timezone.to_string();
+    register_value(handlebars, "format_date", move |h| {
+        let value = required(h, 0, "date")?;
+        let format = match param(h, 1) {
+            Value::Null => "%Y-%m-%d",
+            Value::String(format) => format,
+            other => return Err(RenderError::new(format!("format_date: format must be a string, got {}", other))),
+        };
+        let timezone = hash_str(h, "tz")?.unwrap_or(&default_timezone);
+        let formatted = match parse_date(value).map_err(|e| RenderError::new(format!("format_date: {}", e)))? {
+            Instant::Timestamp(utc) => {
+                let zone = zones.get(timezone).map_err(|e| RenderError::new(format!("format_date: {}", e)))?;
+                let (offset, abbreviation) = zone.offset_at(utc);
+                let local = utc.checked_add(i64::from(offset)).ok_or_else(|| out_of_range(value))?;
+                format_local(local, offset, abbreviation, format)
+            }
+            Instant::Date(days) => format_local(days.checked_mul(86_400).ok_or_else(|| out_of_range(value))?, 0, "", format),
+        };
+        Ok(Value::String(formatted))
+    });
+}
+
+enum Instant {
+    /// Seconds since the epoch, UTC.
+    Timestamp(i64),
+    /// Days since the epoch, without a time or zone.
+    Date(i64),
+}
+
+fn out_of_range(value: &Value) -> RenderError {
+    RenderError::new(format!("format_date: {} is outside the years 0 to 9999", value))
+}
+
+fn parse_date(value: &Value) -> Result<Instant, String> {
+    let in_range = |seconds: &i64| (MIN_TIMESTAMP..=MAX_TIMESTAMP).contains(seconds);
+    match value {
+        Value::Number(number) => number
+            .as_i64()
+            // Checked before the cast, which would saturate huge values.
+            .or_else(|| {
+                number
+                    .as_f64()
+                    .map(f64::floor)
+                    .filter(|seconds| (MIN_TIMESTAMP as f64..=MAX_TIMESTAMP as f64).contains(seconds))
+                    .map(|seconds| seconds as i64)
+            })
+            .filter(in_range)
+            .map(Instant::Timestamp)
+            .ok_or_else(|| format!("timestamp {} is outside the years 0 to 9999", number)),
+        Value::String(text) => match parse_rfc3339(text.trim()) {
+            Some(Instant::Timestamp(seconds)) if !in_range(&seconds) => {
+                Err(format!("{:?} is outside the years 0 to 9999", text))
+            }
+            Some(instant) => Ok(instant),
+            None => Err(format!("invalid date {:?}", text)),
+        },
+        other => Err(format!("expected a date, got {}", other)),
+    }
+}
+
+fn parse_rfc3339(text: &str) -> Option<Instant> {
+    let bytes = text.as_bytes();
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = text.get(range)?;
+        digits.bytes().all(|byte| byte.is_ascii_digit()).then(|| digits.parse().ok())?
+    };
+    let (year, month, day) = (number(0..4)?, number(5..7)? as u32, number(8..10)? as u32);
+    if bytes.get(4) != Some(&b'-') || bytes.get(7) != Some(&b'-') || !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    if text.len() == 10 {
+        return Some(Instant::Date(days));
+    }
+    if !matches!(bytes.get(10), Some(b'T' | b't' | b' ')) || bytes.get(13) != Some(&b':') || bytes.get(16) != Some(&b':') {
+        return None;
+    }
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let mut rest = &text[19..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let end = fraction.find(|c: char| !c.is_ascii_digit()).unwrap_or(fraction.len());
+        if end == 0 {
+            return None;
+        }
+        rest = &fraction[end..];
+    }
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes().first()? {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            if rest.len() != 6 || rest.as_bytes()[3] != b':' {
+                return None;
+            }
+            let (hours, minutes) = (rest[1..3].parse::<i64>().ok()?, rest[4..6].parse::<i64>().ok()?);
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+    let seconds = days.checked_mul(86_400)?.checked_add(hour * 3600 + minute * 60 + second.min(59))?;
+    seconds.checked_sub(offset).map(Instant::Timestamp)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if super::timezone::is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// strftime-style formatting of `local` (seconds since the epoch in local
+/// time). Supports `%Y %y %m %d %e %H %I %M %S %p %B %b %A %a %j %Z %z %%`.
+fn format_local(local: i64, offset: i32, abbreviation: &str, format: &str) -> String {
+    let days = local.div_euclid(86_400);
+    let seconds = local.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+    let (hour, minute, second) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    let weekday = weekday_of(days) as usize;
+    let month_name = MONTHS[month as usize - 1];
+    let mut output = String::with_capacity(format.len() + 16);
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            output.push(c);
+            continue;
+        }
+        let _ = match chars.next() {
+            Some('Y') => write!(output, "{}", year),
+            Some('y') => write!(output, "{:02}", year.rem_euclid(100)),
+            Some('m') => write!(output, "{:02}", month),
+            Some('d') => write!(output, "{:02}", day),
+            Some('e') => write!(output, "{}", day),
+            Some('H') => write!(output, "{:02}", hour),
+            Some('I') => write!(output, "{:02}", (hour + 11) % 12 + 1),
+            Some('M') => write!(output, "{:02}", minute),
+            Some('S') => write!(output, "{:02}", second),
+            Some('p') => write!(output, "{}", if hour < 12 { "AM" } else { "PM" }),
+            Some('B') => write!(output, "{}", month_name),
+            Some('b') => write!(output, "{}", &month_name[..3]),
+            Some('A') => write!(output, "{}", WEEKDAYS[weekday]),
+            Some('a') => write!(output, "{}", &WEEKDAYS[weekday][..3]),
+            Some('j') => write!(output, "{:03}", days - days_from_civil(year, 1, 1) + 1),
+            Some('Z') => write!(output, "{}", abbreviation),
+            Some('z') => write!(output, "{}", format_offset(offset, false)),
+            Some('%') => write!(output, "%"),
+            // Unknown directives are kept as written.
+            Some(other) => write!(output, "%{}", other),
+            None => write!(output, "%"),
+        };
+    }
+    output
+}
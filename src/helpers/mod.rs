@@ -0,0 +1,135 @@
+//! Helpers available to every template, in groups that can be turned off
+//! from the `helpers` section of the server config.
+
+use std::{path::PathBuf, sync::Arc};
+
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson};
+use serde::Deserialize;
+use serde_json::Value;
+
+mod comparison;
+mod dates;
+mod numbers;
+mod text;
+pub mod timezone;
+
+use timezone::ZoneLoader;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HelpersConfig {
+    /// `format_date`.
+    pub dates: bool,
+    /// `format_number` and `format_currency`.
+    pub numbers: bool,
+    /// `pluralize`, `truncate` and `default`.
+    pub text: bool,
+    /// `eq`, `ne`, `gt`, `gte`, `lt`, `lte`, `and`, `or` and `not`. When off,
+    /// Handlebars' own versions remain, which compare integers only.
+    pub comparison: bool,
+    /// Locale of `format_number` and `format_currency` without `locale=`.
+    pub default_locale: String,
+    /// Time zone of `format_date` without `tz=`.
+    pub default_timezone: String,
+    /// Compiled tz database time zones are read from.
+    pub zoneinfo_dir: PathBuf,
+}
+
+impl Default for HelpersConfig {
+    fn default() -> Self {
+        HelpersConfig {
+            dates: true,
+            numbers: true,
+            text: true,
+            comparison: true,
+            default_locale: "en-US".to_string(),
+            default_timezone: "UTC".to_string(),
+            zoneinfo_dir: PathBuf::from("/usr/share/zoneinfo"),
+        }
+    }
+}
+
+impl HelpersConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.dates {
+            ZoneLoader::new(self.zoneinfo_dir.clone()).get(&self.default_timezone)?;
+        }
+        if self.numbers {
+            numbers::Locale::parse(&self.default_locale)?;
+        }
+        Ok(())
+    }
+}
+
+/// Registers the enabled groups in `handlebars`. Call it for each registry
+/// with the same `zones`, so both share parsed time zones.
+pub fn register(handlebars: &mut Handlebars<'static>, config: &HelpersConfig, zones: &Arc<ZoneLoader>) {
+    if config.dates {
+        dates::register(handlebars, zones.clone(), &config.default_timezone);
+    }
+    if config.numbers {
+        numbers::register(handlebars, &config.default_locale);
+    }
+    if config.text {
+        text::register(handlebars);
+    }
+    if config.comparison {
+        comparison::register(handlebars);
+    }
+}
+
+/// A helper computing a value from its parameters, usable both as
+/// `{{name ...}}` and as a subexpression like `{{#if (gt a b)}}`.
+struct ValueHelper<F>(F);
+
+impl<F> HelperDef for ValueHelper<F>
+where
+    F: Fn(&Helper<'_, '_>) -> Result<Value, RenderError> + Send + Sync,
+{
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        (self.0)(h).map(ScopedJson::Derived)
+    }
+}
+
+fn register_value<F>(handlebars: &mut Handlebars<'static>, name: &str, helper: F)
+where
+    F: Fn(&Helper<'_, '_>) -> Result<Value, RenderError> + Send + Sync + 'static,
+{
+    handlebars.register_helper(name, Box::new(ValueHelper(helper)));
+}
+
+/// Parameter `index`, `Null` when absent or unresolved.
+fn param<'a>(h: &'a Helper<'_, '_>, index: usize) -> &'a Value {
+    h.param(index).map(|param| param.value()).unwrap_or(&Value::Null)
+}
+
+fn required<'a>(h: &'a Helper<'_, '_>, index: usize, what: &str) -> Result<&'a Value, RenderError> {
+    match h.param(index) {
+        Some(param) => Ok(param.value()),
+        None => Err(RenderError::new(format!("{}: missing {}", h.name(), what))),
+    }
+}
+
+/// A string option from the hash, e.g. `tz="Europe/Paris"`.
+fn hash_str<'a>(h: &'a Helper<'_, '_>, key: &str) -> Result<Option<&'a str>, RenderError> {
+    match h.hash_get(key).map(|value| value.value()) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(other) => Err(RenderError::new(format!("{}: {}= must be a string, got {}", h.name(), key, other))),
+    }
+}
+
+/// A number given as a JSON number or a numeric string.
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse().ok().filter(|number: &f64| number.is_finite()),
+        _ => None,
+    }
+}
@@ -0,0 +1,170 @@
+use handlebars::{Handlebars, Helper, RenderError};
+use serde_json::Value;
+
+use crate::locales::current_locale;
+
+use super::{as_number, hash_str, register_value, required};
+
+/// Separators and currency placement of a locale.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Locale {
+    group: &'static str,
+    decimal: char,
+    currency: Placement,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Placement {
+    /// `$1,234.50`
+    Prefix,
+    /// `€ 1.234,50`
+    PrefixSpaced,
+    /// `1.234,50 €`
+    Suffix,
+}
+
+const NBSP: &str = "\u{a0}";
+const NARROW_NBSP: &str = "\u{202f}";
+
+impl Locale {
+    /// Looks up a BCP 47 tag like `de`, `de-CH` or `pt_BR`, region first.
+    pub fn parse(tag: &str) -> Result<Self, String> {
+        let tag = tag.replace('_', "-").to_ascii_lowercase();
+        let language = tag.split('-').next().unwrap_or_default();
+        let locale = |group, decimal, currency| Locale { group, decimal, currency };
+        let by_region = match tag.as_str() {
+            "de-ch" | "de-li" => Some(locale("’", '.', Placement::PrefixSpaced)),
+            "de-at" => Some(locale(NBSP, ',', Placement::PrefixSpaced)),
+            "fr-ch" => Some(locale(NARROW_NBSP, ',', Placement::Suffix)),
+            "es-mx" | "es-us" => Some(locale(",", '.', Placement::Prefix)),
+            "pt-pt" => Some(locale(NBSP, ',', Placement::Suffix)),
+            _ => None,
+        };
+        if let Some(locale) = by_region {
+            return Ok(locale);
+        }
+        Ok(match language {
+            "en" | "ja" | "zh" | "ko" | "th" | "he" => locale(",", '.', Placement::Prefix),
+            "nl" | "pt" => locale(".", ',', Placement::PrefixSpaced),
+            "de" | "es" | "it" | "id" | "tr" | "da" | "el" | "ro" | "hr" | "sl" | "sr" | "vi" => locale(".", ',', Placement::Suffix),
+            "fr" => locale(NARROW_NBSP, ',', Placement::Suffix),
+            "ru" | "pl" | "cs" | "sk" | "sv" | "nb" | "no" | "fi" | "uk" | "hu" | "bg" | "lt" | "lv" | "et" => {
+                locale(NBSP, ',', Placement::Suffix)
+            }
+            _ => return Err(format!("Unsupported locale {:?}", tag)),
+        })
+    }
+
+    /// `value` with `decimals` digits after the separator, grouped by
+    /// thousands.
+    fn format(&self, value: f64, decimals: usize) -> String {
+        let digits = format!("{:.*}", decimals, value.abs());
+        let (integer, fraction) = digits.split_once('.').unwrap_or((&digits, ""));
+        let mut output = String::with_capacity(digits.len() + integer.len() / 3 * 3 + 1);
+        // Rounding can turn -0.001 into "0.00", which shouldn't get a sign.
+        if value.is_sign_negative() && digits.bytes().any(|byte| matches!(byte, b'1'..=b'9')) {
+            output.push('-');
+        }
+        for (index, digit) in integer.chars().enumerate() {
+            if index > 0 && (integer.len() - index) % 3 == 0 {
+                output.push_str(self.group);
+            }
+            output.push(digit);
+        }
+        if !fraction.is_empty() {
+            output.push(self.decimal);
+            output.push_str(fraction);
+        }
+        output
+    }
+
+    fn format_currency(&self, value: f64, currency: &str) -> String {
+        let (symbol, decimals) = currency_symbol(currency);
+        let number = self.format(value, decimals);
+        let (sign, number) = match number.strip_prefix('-') {
+            Some(unsigned) => ("-", unsigned),
+            None => ("", number.as_str()),
+        };
+        match self.currency {
+            Placement::Prefix => format!("{}{}{}", sign, symbol, number),
+            Placement::PrefixSpaced => format!("{}{}{}{}", sign, symbol, NBSP, number),
+            Placement::Suffix => format!("{}{}{}{}", sign, number, NBSP, symbol),
+        }
+    }
+}
+
+/// Symbol and minor-unit digits of an ISO 4217 code. Unknown codes are
+/// shown as the code itself.
+fn currency_symbol(code: &str) -> (&str, usize) {
+    match code {
+        "USD" => ("$", 2),
+        "EUR" => ("€", 2),
+        "GBP" => ("£", 2),
+        "JPY" => ("¥", 0),
+        "KRW" => ("₩", 0),
+        "CNY" => ("CN¥", 2),
+        "INR" => ("₹", 2),
+        "CAD" => ("CA$", 2),
+        "AUD" => ("A$", 2),
+        "MXN" => ("MX$", 2),
+        "BRL" => ("R$", 2),
+        "CHF" => ("CHF", 2),
+        "SEK" | "NOK" => ("kr", 2),
+        "DKK" => ("kr.", 2),
+        "PLN" => ("zł", 2),
+        "RUB" => ("₽", 2),
+        "TRY" => ("₺", 2),
+        other => (other, 2),
+    }
+}
+
+/// `{{format_number value decimals=2 locale="de-DE"}}` groups thousands
+/// with the locale's separators. Without `decimals`, integers keep none
+/// and other numbers get two.
+///
+/// `{{format_currency value "EUR" locale="fr-FR"}}` adds the currency
+/// symbol where the locale puts it, with the currency's usual decimals.
+pub fn register(handlebars: &mut Handlebars<'static>, default_locale: &str) {
+    let default_locale = default_locale.to_string();
+    let number_locale = default_locale.clone();
+    register_value(handlebars, "format_number", move |h| {
+        let value = number(h)?;
+        let locale = locale(h, &number_locale)?;
+        let decimals = match h.hash_get("decimals").map(|decimals| decimals.value()) {
+            None => usize::from(value.fract() != 0.0) * 2,
+            Some(decimals) => decimals
+                .as_u64()
+                .filter(|decimals| *decimals <= 20)
+                .ok_or_else(|| RenderError::new(format!("format_number: decimals= must be between 0 and 20, got {}", decimals)))?
+                as usize,
+        };
+        Ok(Value::String(locale.format(value, decimals)))
+    });
+    register_value(handlebars, "format_currency", move |h| {
+        let value = number(h)?;
+        let currency = match required(h, 1, "currency code")? {
+            Value::String(code) if code.len() == 3 && code.bytes().all(|byte| byte.is_ascii_alphabetic()) => code.to_ascii_uppercase(),
+            other => return Err(RenderError::new(format!("format_currency: invalid currency code {}", other))),
+        };
+        let locale = locale(h, &default_locale)?;
+        Ok(Value::String(locale.format_currency(value, &currency)))
+    });
+}
+
+fn number(h: &Helper<'_, '_>) -> Result<f64, RenderError> {
+    let value = required(h, 0, "number")?;
+    as_number(value).ok_or_else(|| RenderError::new(format!("{}: expected a number, got {}", h.name(), value)))
+}
+
+/// `locale=`, else the locale of the render when its numbers are known,
+/// else the configured default.
+fn locale(h: &Helper<'_, '_>, default_locale: &str) -> Result<Locale, RenderError> {
+    if let Some(tag) = hash_str(h, "locale")? {
+        return Locale::parse(tag).map_err(|e| RenderError::new(format!("{}: {}", h.name(), e)));
+    }
+    let render_locale = current_locale().and_then(|tag| Locale::parse(tag.as_str()).ok());
+    match render_locale {
+        Some(locale) => Ok(locale),
+        None => Locale::parse(default_locale).map_err(|e| RenderError::new(format!("{}: {}", h.name(), e))),
+    }
+}
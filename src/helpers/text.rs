@@ -0,0 +1,54 @@
+use handlebars::{Handlebars, RenderError};
+use serde_json::Value;
+
+use super::{as_number, hash_str, param, register_value, required};
+
+/// `{{pluralize count "item" "items"}}` picks the singular when `count` is
+/// 1 and the plural otherwise; without a plural, an `s` is appended.
+///
+/// `{{truncate text 80 suffix="…"}}` keeps the first 80 characters and ends
+/// with `suffix` (`…` by default) when something was cut.
+///
+/// `{{default nickname first_name "there"}}` gives the first parameter that
+/// is neither missing, `null` nor an empty string.
+pub fn register(handlebars: &mut Handlebars<'static>) {
+    register_value(handlebars, "pluralize", |h| {
+        let count = required(h, 0, "count")?;
+        let count = as_number(count)
+            .ok_or_else(|| RenderError::new(format!("pluralize: expected a count, got {}", count)))?;
+        let singular = match required(h, 1, "singular form")? {
+            Value::String(singular) => singular,
+            other => return Err(RenderError::new(format!("pluralize: expected a word, got {}", other))),
+        };
+        Ok(Value::String(match (count == 1.0, param(h, 2)) {
+            (true, _) => singular.clone(),
+            (false, Value::String(plural)) => plural.clone(),
+            (false, _) => format!("{}s", singular),
+        }))
+    });
+    register_value(handlebars, "truncate", |h| {
+        let text = match required(h, 0, "text")? {
+            Value::Null => String::new(),
+            Value::String(text) => text.clone(),
+            other => other.to_string(),
+        };
+        let length = required(h, 1, "length")?;
+        let length = length
+            .as_u64()
+            .ok_or_else(|| RenderError::new(format!("truncate: length must be a positive integer, got {}", length)))?
+            as usize;
+        let suffix = hash_str(h, "suffix")?.unwrap_or("…");
+        let Some((cut, _)) = text.char_indices().nth(length) else {
+            return Ok(Value::String(text));
+        };
+        Ok(Value::String(format!("{}{}", text[..cut].trim_end(), suffix)))
+    });
+    register_value(handlebars, "default", |h| {
+        let present = h
+            .params()
+            .iter()
+            .map(|param| param.value())
+            .find(|value| !matches!(value, Value::Null) && value.as_str() != Some(""));
+        Ok(present.cloned().unwrap_or(Value::Null))
+    });
+}
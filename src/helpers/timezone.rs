@@ -0,0 +1,443 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+/// A time zone: a fixed offset, or the rules of an IANA zone read from the
+/// system's compiled tz database (`/usr/share/zoneinfo/Europe/Paris`).
+#[derive(Debug)]
+pub enum Zone {
+    Fixed { offset: i32, name: String },
+    Tzif(Tzif),
+}
+
+/// Contents of a TZif file: historical transitions, then a POSIX TZ rule
+/// for every instant after the last one.
+#[derive(Debug)]
+pub struct Tzif {
+    transitions: Vec<i64>,
+    /// Index into `types` for each transition.
+    transition_types: Vec<usize>,
+    types: Vec<LocalType>,
+    rule: Option<PosixRule>,
+}
+
+#[derive(Clone, Debug)]
+struct LocalType {
+    offset: i32,
+    abbreviation: String,
+}
+
+impl Zone {
+    /// UTC offset in seconds and abbreviation in effect at `utc` (seconds
+    /// since the Unix epoch).
+    pub fn offset_at(&self, utc: i64) -> (i32, &str) {
+        match self {
+            Zone::Fixed { offset, name } => (*offset, name),
+            Zone::Tzif(tzif) => tzif.offset_at(utc),
+        }
+    }
+}
+
+impl Tzif {
+    fn offset_at(&self, utc: i64) -> (i32, &str) {
+        let index = self.transitions.partition_point(|&transition| transition <= utc);
+        if index == self.transitions.len() {
+            if let Some(rule) = &self.rule {
+                return rule.offset_at(utc);
+            }
+        }
+        let local_type = match index {
+            // Before the first transition: the first standard-time type.
+            0 => &self.types[0],
+            _ => &self.types[self.transition_types[index - 1]],
+        };
+        (local_type.offset, &local_type.abbreviation)
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { data, position: 0 };
+        let header = read_header(&mut reader)?;
+        if header.version == 0 {
+            return Tzif::from_block(&mut reader, &header, 4);
+        }
+        // Skip the 32-bit block and read the 64-bit one that follows.
+        reader.skip(header.block_len(4))?;
+        let header = read_header(&mut reader)?;
+        let mut tzif = Tzif::from_block(&mut reader, &header, 8)?;
+        let footer = std::str::from_utf8(&data[reader.position..]).map_err(|_| "invalid footer".to_string())?;
+        let rule = footer.trim_matches('\n');
+        if !rule.is_empty() {
+            tzif.rule = Some(PosixRule::parse(rule)?);
+        }
+        Ok(tzif)
+    }
+
+    fn from_block(reader: &mut Reader<'_>, header: &Header, time_size: usize) -> Result<Self, String> {
+        let transitions = (0..header.transitions)
+            .map(|_| reader.int(time_size))
+            .collect::<Result<Vec<_>, _>>()?;
+        let transition_types = (0..header.transitions)
+            .map(|_| reader.byte().map(usize::from))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut raw_types = Vec::with_capacity(header.types);
+        for _ in 0..header.types {
+            let offset = reader.int(4)? as i32;
+            let _is_dst = reader.byte()?;
+            let abbreviation_index = usize::from(reader.byte()?);
+            raw_types.push((offset, abbreviation_index));
+        }
+        let abbreviations = reader.take(header.abbreviation_chars)?;
+        let types = raw_types
+            .into_iter()
+            .map(|(offset, index)| {
+                let name = abbreviations.get(index..).unwrap_or_default();
+                let end = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+                LocalType { offset, abbreviation: String::from_utf8_lossy(&name[..end]).into_owned() }
+            })
+            .collect::<Vec<_>>();
+        if types.is_empty() || transition_types.iter().any(|&index| index >= types.len()) {
+            return Err("invalid local time types".to_string());
+        }
+        reader.skip(header.leap_seconds * (time_size + 4) + header.standard_indicators + header.ut_indicators)?;
+        Ok(Tzif { transitions, transition_types, types, rule: None })
+    }
+}
+
+struct Header {
+    version: u8,
+    ut_indicators: usize,
+    standard_indicators: usize,
+    leap_seconds: usize,
+    transitions: usize,
+    types: usize,
+    abbreviation_chars: usize,
+}
+
+impl Header {
+    fn block_len(&self, time_size: usize) -> usize {
+        self.transitions * (time_size + 1)
+            + self.types * 6
+            + self.abbreviation_chars
+            + self.leap_seconds * (time_size + 4)
+            + self.standard_indicators
+            + self.ut_indicators
+    }
+}
+
+fn read_header(reader: &mut Reader<'_>) -> Result<Header, String> {
+    if reader.take(4)? != b"TZif" {
+        return Err("not a TZif file".to_string());
+    }
+    let version = reader.byte()?;
+    reader.skip(15)?;
+    let mut count = || reader.int(4).map(|value| value as usize);
+    Ok(Header {
+        version: if version == 0 { 0 } else { version - b'0' },
+        ut_indicators: count()?,
+        standard_indicators: count()?,
+        leap_seconds: count()?,
+        transitions: count()?,
+        types: count()?,
+        abbreviation_chars: count()?,
+    })
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + len)
+            .ok_or_else(|| "truncated TZif file".to_string())?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), String> {
+        self.take(len).map(|_| ())
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Big-endian signed integer of 4 or 8 bytes.
+    fn int(&mut self, size: usize) -> Result<i64, String> {
+        let bytes = self.take(size)?;
+        Ok(match size {
+            4 => i32::from_be_bytes(bytes.try_into().unwrap()) as i64,
+            _ => i64::from_be_bytes(bytes.try_into().unwrap()),
+        })
+    }
+}
+
+/// A POSIX TZ string such as `CET-1CEST,M3.5.0,M10.5.0/3`.
+#[derive(Debug)]
+struct PosixRule {
+    standard: LocalType,
+    daylight: Option<(LocalType, TransitionDate, i32, TransitionDate, i32)>,
+}
+
+#[derive(Debug)]
+enum TransitionDate {
+    /// `Mm.w.d`: day `d` (0 = Sunday) of week `w` (5 = last) of month `m`.
+    MonthWeekDay { month: u32, week: u32, weekday: u32 },
+    /// `Jn`: day 1-365, never counting February 29.
+    Julian(u32),
+    /// `n`: day 0-365, counting February 29.
+    Zero(u32),
+}
+
+impl PosixRule {
+    fn parse(rule: &str) -> Result<Self, String> {
+        let invalid = || format!("unsupported TZ rule {:?}", rule);
+        let mut rest = rule;
+        let standard_name = parse_name(&mut rest).ok_or_else(invalid)?;
+        // POSIX offsets are west of Greenwich, the opposite of UTC offsets.
+        let standard_offset = -parse_time(&mut rest).ok_or_else(invalid)?;
+        let standard = LocalType { offset: standard_offset, abbreviation: standard_name };
+        if rest.is_empty() {
+            return Ok(PosixRule { standard, daylight: None });
+        }
+        let daylight_name = parse_name(&mut rest).ok_or_else(invalid)?;
+        let daylight_offset = if rest.starts_with(',') {
+            standard_offset + 3600
+        } else {
+            -parse_time(&mut rest).ok_or_else(invalid)?
+        };
+        let transition = |rest: &mut &str| -> Option<(TransitionDate, i32)> {
+            *rest = rest.strip_prefix(',')?;
+            let date = parse_date(rest)?;
+            let time = match rest.strip_prefix('/') {
+                Some(after) => {
+                    *rest = after;
+                    parse_time(rest)?
+                }
+                None => 7200,
+            };
+            Some((date, time))
+        };
+        let (start, start_time) = transition(&mut rest).ok_or_else(invalid)?;
+        let (end, end_time) = transition(&mut rest).ok_or_else(invalid)?;
+        if !rest.is_empty() {
+            return Err(invalid());
+        }
+        let daylight = LocalType { offset: daylight_offset, abbreviation: daylight_name };
+        Ok(PosixRule { standard, daylight: Some((daylight, start, start_time, end, end_time)) })
+    }
+
+    fn offset_at(&self, utc: i64) -> (i32, &str) {
+        let Some((daylight, start, start_time, end, end_time)) = &self.daylight else {
+            return (self.standard.offset, &self.standard.abbreviation);
+        };
+        let year = civil_from_days((utc + i64::from(self.standard.offset)).div_euclid(86_400)).0;
+        // Transition times are given in the local time in effect before them.
+        let starts = start.day_in(year) * 86_400 + i64::from(*start_time) - i64::from(self.standard.offset);
+        let ends = end.day_in(year) * 86_400 + i64::from(*end_time) - i64::from(daylight.offset);
+        let in_daylight = if starts < ends {
+            starts <= utc && utc < ends
+        } else {
+            // Southern hemisphere: daylight time spans the new year.
+            utc < ends || starts <= utc
+        };
+        if in_daylight {
+            (daylight.offset, &daylight.abbreviation)
+        } else {
+            (self.standard.offset, &self.standard.abbreviation)
+        }
+    }
+}
+
+impl TransitionDate {
+    /// Days since the epoch of this date in `year`.
+    fn day_in(&self, year: i64) -> i64 {
+        match *self {
+            TransitionDate::MonthWeekDay { month, week, weekday } => {
+                let first = days_from_civil(year, month, 1);
+                let first_weekday = weekday_of(first);
+                let mut day = first + i64::from((weekday + 7 - first_weekday) % 7) + 7 * i64::from(week - 1);
+                let next_month = if month == 12 { days_from_civil(year + 1, 1, 1) } else { days_from_civil(year, month + 1, 1) };
+                while day >= next_month {
+                    day -= 7;
+                }
+                day
+            }
+            TransitionDate::Julian(day) => {
+                let leap_shift = i64::from(is_leap(year) && day >= 60);
+                days_from_civil(year, 1, 1) + i64::from(day) - 1 + leap_shift
+            }
+            TransitionDate::Zero(day) => days_from_civil(year, 1, 1) + i64::from(day),
+        }
+    }
+}
+
+fn parse_name(rest: &mut &str) -> Option<String> {
+    if let Some(quoted) = rest.strip_prefix('<') {
+        let end = quoted.find('>')?;
+        let name = quoted[..end].to_string();
+        *rest = &quoted[end + 1..];
+        return Some(name);
+    }
+    let end = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+    if end < 3 {
+        return None;
+    }
+    let name = rest[..end].to_string();
+    *rest = &rest[end..];
+    Some(name)
+}
+
+/// `[+-]hh[:mm[:ss]]` in seconds. Hours go up to 167, as in POSIX, and
+/// minutes and seconds up to 59.
+fn parse_time(rest: &mut &str) -> Option<i32> {
+    let sign = match rest.chars().next()? {
+        '-' => {
+            *rest = &rest[1..];
+            -1
+        }
+        '+' => {
+            *rest = &rest[1..];
+            1
+        }
+        _ => 1,
+    };
+    let mut seconds: i32 = 0;
+    for (index, (unit, max)) in [(3600, 167), (60, 59), (1, 59)].into_iter().enumerate() {
+        if index > 0 {
+            match rest.strip_prefix(':') {
+                Some(after) => *rest = after,
+                None => break,
+            }
+        }
+        let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let value: i32 = rest[..end].parse().ok().filter(|value| *value <= max)?;
+        seconds = seconds.checked_add(value.checked_mul(unit)?)?;
+        *rest = &rest[end..];
+    }
+    Some(sign * seconds)
+}
+
+fn parse_date(rest: &mut &str) -> Option<TransitionDate> {
+    let number = |rest: &mut &str| -> Option<u32> {
+        let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let value = rest[..end].parse().ok()?;
+        *rest = &rest[end..];
+        Some(value)
+    };
+    if let Some(after) = rest.strip_prefix('M') {
+        *rest = after;
+        let month = number(rest)?;
+        *rest = rest.strip_prefix('.')?;
+        let week = number(rest)?;
+        *rest = rest.strip_prefix('.')?;
+        let weekday = number(rest)?;
+        let valid = (1..=12).contains(&month) && (1..=5).contains(&week) && weekday <= 6;
+        return valid.then_some(TransitionDate::MonthWeekDay { month, week, weekday });
+    }
+    if let Some(after) = rest.strip_prefix('J') {
+        *rest = after;
+        return number(rest).filter(|day| (1..=365).contains(day)).map(TransitionDate::Julian);
+    }
+    number(rest).filter(|day| *day <= 365).map(TransitionDate::Zero)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Year, month and day of a number of days since 1970-01-01.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Day of the week, 0 = Sunday.
+pub fn weekday_of(days: i64) -> u32 {
+    (days + 4).rem_euclid(7) as u32
+}
+
+pub fn is_leap(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+/// Loads zones by name from a tz database directory and keeps them. Only
+/// zones read from a file are kept, so names sent in payloads can't grow
+/// the cache past the size of the database.
+pub struct ZoneLoader {
+    zoneinfo_dir: PathBuf,
+    zones: RwLock<HashMap<String, Arc<Zone>>>,
+}
+
+impl ZoneLoader {
+    pub fn new(zoneinfo_dir: PathBuf) -> Self {
+        ZoneLoader { zoneinfo_dir, zones: RwLock::new(HashMap::new()) }
+    }
+
+    /// Resolves `UTC`, a fixed offset like `+02:00`, or an IANA name like
+    /// `America/New_York`.
+    pub fn get(&self, name: &str) -> Result<Arc<Zone>, String> {
+        if let Some(zone) = self.zones.read().unwrap().get(name) {
+            return Ok(zone.clone());
+        }
+        let zone = Arc::new(self.load(name)?);
+        if matches!(*zone, Zone::Tzif(_)) {
+            self.zones.write().unwrap().insert(name.to_string(), zone.clone());
+        }
+        Ok(zone)
+    }
+
+    fn load(&self, name: &str) -> Result<Zone, String> {
+        if name.eq_ignore_ascii_case("UTC") || name == "Z" {
+            return Ok(Zone::Fixed { offset: 0, name: "UTC".to_string() });
+        }
+        if name.starts_with(['+', '-']) {
+            let mut rest = name;
+            let offset = parse_time(&mut rest)
+                .filter(|offset| rest.is_empty() && offset.abs() < 86_400)
+                .ok_or_else(|| format!("Invalid UTC offset {:?}", name))?;
+            return Ok(Zone::Fixed { offset, name: format_offset(offset, true) });
+        }
+        // Zone names are plain paths inside the database.
+        let valid = name
+            .split('/')
+            .all(|part| !part.is_empty() && !part.starts_with('.') && part.chars().all(|c| c.is_ascii_alphanumeric() || "_+-".contains(c)));
+        if !valid {
+            return Err(format!("Invalid time zone {:?}", name));
+        }
+        let path = Path::new(&self.zoneinfo_dir).join(name);
+        let data = fs::read(&path).map_err(|e| format!("Unknown time zone {:?}: {}", name, e))?;
+        Tzif::parse(&data)
+            .map(Zone::Tzif)
+            .map_err(|e| format!("Failed to read time zone {:?}: {}", name, e))
+    }
+}
+
+/// `+0200`, or `+02:00` with `colon`.
+pub fn format_offset(offset: i32, colon: bool) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let minutes = offset.abs() / 60;
+    let separator = if colon { ":" } else { "" };
+    format!("{}{:02}{}{:02}", sign, minutes / 60, separator, minutes % 60)
+}
@@ -0,0 +1,263 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::template_id::TemplateId;
+use crate::utils::{content_hash, format_utc};
+
+/// Folder of the template directory keeping every revision written through
+/// the API. It is not a template folder: the API refuses names inside it.
+pub const HISTORY_DIR: &str = ".history";
+
+/// Request header naming who made a change, recorded with the revision.
+pub const UPLOADER: &str = "x-uploader";
+
+/// Lines of unchanged text around each change of a diff.
+const CONTEXT: usize = 3;
+
+/// Most cells of the table a diff compares the changed lines with, one per
+/// pair of an old and a new line: 40 MB, reached by two revisions with
+/// about 3,000 lines differing on each side.
+const MAX_DIFF_CELLS: usize = 10_000_000;
+
+/// One entry of a template's history. Revisions are numbered from 1 and
+/// never rewritten; a rollback adds a revision with the old content.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revision {
+    pub rev: u64,
+    /// Hex SHA-256 of the content, as in the template's `ETag`.
+    pub hash: String,
+    /// When it was recorded, in RFC 3339 UTC.
+    pub created: String,
+    /// The `X-Uploader` of the request, `None` for the content a file had
+    /// before its first change through the API.
+    pub uploader: Option<String>,
+    /// Bytes.
+    pub size: usize,
+    /// The revision this one restored, for rollbacks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollback_of: Option<u64>,
+}
+
+/// Whether `id` lies in the history folder.
+pub fn is_history(id: &TemplateId) -> bool {
+    id.as_str().split('/').next() == Some(HISTORY_DIR)
+}
+
+/// Splits `welcome.mjml@3` into the template name and revision. Names
+/// without a numeric `@` suffix aren't revisions.
+pub fn split_revision(template_name: &str) -> Option<(&str, u64)> {
+    let (name, rev) = template_name.rsplit_once('@')?;
+    if name.is_empty() || rev.is_empty() || !rev.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    Some((name, rev.parse().ok()?))
+}
+
+/// Folder of the revisions of `id`: one file per revision, named by its
+/// number, and the `revisions.jsonl` log describing them.
+fn revisions_dir(template_dir: &Path, id: &TemplateId) -> PathBuf {
+    template_dir.join(HISTORY_DIR).join(id.as_str())
+}
+
+/// Every revision of `id`, oldest first. Empty for files never changed
+/// through the API.
+pub fn revisions(template_dir: &Path, id: &TemplateId) -> Result<Vec<Revision>, String> {
+    let log = revisions_dir(template_dir, id).join("revisions.jsonl");
+    let content = match fs::read_to_string(&log) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read the history of {}: {}", id, e)),
+    };
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|e| format!("Corrupt history of {}: {}", id, e)))
+        .collect()
+}
+
+/// Content of revision `rev` of `id`, `None` when there is no such revision.
+pub fn read_revision(template_dir: &Path, id: &TemplateId, rev: u64) -> Result<Option<String>, String> {
+    match fs::read_to_string(revisions_dir(template_dir, id).join(rev.to_string())) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Failed to read revision {} of {}: {}", rev, id, e)),
+    }
+}
+
+/// Appends `content` to the history of `id`, before it replaces `previous`
+/// on disk. The first change of a file that predates the history records
+/// `previous` first, so it can be rolled back to as well.
+pub fn record(
+    template_dir: &Path,
+    id: &TemplateId,
+    previous: Option<&str>,
+    content: &str,
+    uploader: Option<&str>,
+    rollback_of: Option<u64>,
+) -> Result<Revision, String> {
+    let mut history = revisions(template_dir, id)?;
+    if history.is_empty() {
+        if let Some(previous) = previous {
+            history.push(append(template_dir, id, 1, previous, None, None)?);
+        }
+    }
+    let rev = history.last().map_or(1, |last| last.rev + 1);
+    append(template_dir, id, rev, content, uploader, rollback_of)
+}
+
+/// Takes back `revision` when the change it records was undone. Only the
+/// last revision of `id` can be taken back.
+pub fn discard(template_dir: &Path, id: &TemplateId, revision: &Revision) -> Result<(), String> {
+    let dir = revisions_dir(template_dir, id);
+    let failed = |e: io::Error| format!("Failed to discard revision {} of {}: {}", revision.rev, id, e);
+    let log = dir.join("revisions.jsonl");
+    let content = fs::read_to_string(&log).map_err(failed)?;
+    let kept = content.trim_end_matches('\n');
+    let start = kept.rfind('\n').map_or(0, |newline| newline + 1);
+    if serde_json::from_str::<Revision>(&kept[start..]).ok().as_ref() != Some(revision) {
+        return Err(format!("Failed to discard revision {} of {}: it isn't the last one", revision.rev, id));
+    }
+    // A single truncation, so the log is never left half rewritten.
+    OpenOptions::new().write(true).open(&log).and_then(|file| file.set_len(start as u64)).map_err(failed)?;
+    fs::remove_file(dir.join(revision.rev.to_string())).map_err(failed)
+}
+
+fn append(
+    template_dir: &Path,
+    id: &TemplateId,
+    rev: u64,
+    content: &str,
+    uploader: Option<&str>,
+    rollback_of: Option<u64>,
+) -> Result<Revision, String> {
+    let dir = revisions_dir(template_dir, id);
+    let failed = |e: io::Error| format!("Failed to record revision {} of {}: {}", rev, id, e);
+    fs::create_dir_all(&dir).map_err(failed)?;
+    // Numbers come from the log, so a file already there was left by a
+    // write whose log line failed and isn't a revision yet.
+    fs::write(dir.join(rev.to_string()), content).map_err(failed)?;
+
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs());
+    let revision = Revision {
+        rev,
+        hash: content_hash(content.as_bytes()),
+        created: format_utc(seconds as i64),
+        uploader: uploader.map(str::to_string),
+        size: content.len(),
+        rollback_of,
+    };
+    let mut line = serde_json::to_string(&revision).map_err(|e| format!("Failed to record revision {} of {}: {}", rev, id, e))?;
+    line.push('\n');
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(dir.join("revisions.jsonl"))
+        .and_then(|mut log| log.write_all(line.as_bytes()))
+        .map_err(failed)?;
+    Ok(revision)
+}
+
+/// Unified diff of two texts, line by line, empty when they are the same.
+/// Refused when the lines between their common start and end are too many
+/// to compare.
+pub fn unified_diff(old: &str, new: &str, old_label: &str, new_label: &str) -> Result<String, String> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let lines = diff_lines(&old_lines, &new_lines)?;
+
+    let changes: Vec<usize> = lines.iter().enumerate().filter(|(_, (kind, _))| *kind != ' ').map(|(index, _)| index).collect();
+    if changes.is_empty() {
+        return Ok(String::new());
+    }
+    // Line numbers in each text before each diff line.
+    let mut old_before = Vec::with_capacity(lines.len() + 1);
+    let mut new_before = Vec::with_capacity(lines.len() + 1);
+    let (mut old_count, mut new_count) = (0, 0);
+    for (kind, _) in &lines {
+        old_before.push(old_count);
+        new_before.push(new_count);
+        old_count += usize::from(*kind != '+');
+        new_count += usize::from(*kind != '-');
+    }
+    old_before.push(old_count);
+    new_before.push(new_count);
+
+    let mut diff = format!("--- {}\n+++ {}\n", old_label, new_label);
+    let mut group_start = 0;
+    for (position, change) in changes.iter().enumerate() {
+        // Changes at most twice the context apart share a hunk.
+        if changes.get(position + 1).is_some_and(|next| next - change - 1 <= 2 * CONTEXT) {
+            continue;
+        }
+        let start = changes[group_start].saturating_sub(CONTEXT);
+        let end = (change + 1 + CONTEXT).min(lines.len());
+        let old_len = old_before[end] - old_before[start];
+        let new_len = new_before[end] - new_before[start];
+        // An empty range is numbered by the line before it.
+        let old_start = old_before[start] + usize::from(old_len > 0);
+        let new_start = new_before[start] + usize::from(new_len > 0);
+        diff.push_str(&format!("@@ -{},{} +{},{} @@\n", old_start, old_len, new_start, new_len));
+        for (kind, text) in &lines[start..end] {
+            diff.push(*kind);
+            diff.push_str(text);
+            diff.push('\n');
+        }
+        group_start = position + 1;
+    }
+    Ok(diff)
+}
+
+/// The lines of both texts marked ` ` when kept, `-` when removed and `+`
+/// when added, along a longest common subsequence.
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Result<Vec<(char, &'a str)>, String> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (old_middle, new_middle) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+    let cells = (old_middle.len() + 1).saturating_mul(new_middle.len() + 1);
+    if cells > MAX_DIFF_CELLS {
+        return Err(format!(
+            "Too many changed lines to diff: {} old and {} new, the limit is {} pairs",
+            old_middle.len(),
+            new_middle.len(),
+            MAX_DIFF_CELLS
+        ));
+    }
+
+    // common[i][j]: length of the longest common subsequence of
+    // `old_middle[i..]` and `new_middle[j..]`.
+    let width = new_middle.len() + 1;
+    let mut common = vec![0u32; (old_middle.len() + 1) * width];
+    for i in (0..old_middle.len()).rev() {
+        for j in (0..new_middle.len()).rev() {
+            common[i * width + j] = if old_middle[i] == new_middle[j] {
+                common[(i + 1) * width + j + 1] + 1
+            } else {
+                common[(i + 1) * width + j].max(common[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut lines: Vec<(char, &str)> = old[..prefix].iter().map(|line| (' ', *line)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < old_middle.len() || j < new_middle.len() {
+        if i < old_middle.len() && j < new_middle.len() && old_middle[i] == new_middle[j] {
+            lines.push((' ', old_middle[i]));
+            i += 1;
+            j += 1;
+        } else if j == new_middle.len() || (i < old_middle.len() && common[(i + 1) * width + j] >= common[i * width + j + 1]) {
+            lines.push(('-', old_middle[i]));
+            i += 1;
+        } else {
+            lines.push(('+', new_middle[j]));
+            j += 1;
+        }
+    }
+    lines.extend(old[old.len() - suffix..].iter().map(|line| (' ', *line)));
+    Ok(lines)
+}
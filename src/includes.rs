@@ -0,0 +1,104 @@
+use std::{
+    fmt,
+    io::ErrorKind,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use mrml::prelude::parser::{
+    loader::{IncludeLoader, IncludeLoaderError},
+    ParserOptions,
+};
+
+use crate::dependency_graph::DependencyGraph;
+use crate::template_cache::TemplateCache;
+use crate::template_id::TemplateId;
+
+/// Files pulled in by `<mj-include path="...">`.
+///
+/// Paths are relative to the template directory, with or without mrml's
+/// `file:///` prefix, and go through the same checks as template names, so
+/// an include can't read outside the directory. File contents are cached
+/// until the watcher reports a change, and every include is recorded in the
+/// dependency graph so the templates using a file are invalidated with it.
+pub struct Includes {
+    template_dir: PathBuf,
+    files: TemplateCache<Arc<str>>,
+    graph: Arc<DependencyGraph>,
+}
+
+impl Includes {
+    pub fn new(template_dir: PathBuf, capacity: NonZeroUsize, graph: Arc<DependencyGraph>) -> Self {
+        Includes { template_dir, files: TemplateCache::new(capacity), graph }
+    }
+
+    /// Parser options resolving includes from the template directory, on
+    /// behalf of `includer` (`None` for inline MJML).
+    pub fn parser_options(self: &Arc<Self>, includer: Option<TemplateId>) -> ParserOptions {
+        ParserOptions {
+            include_loader: Box::new(TemplateIncludeLoader { includes: self.clone(), includer }),
+        }
+    }
+
+    /// Forgets the cached content of `id`.
+    pub fn forget(&self, id: &TemplateId) {
+        self.files.remove(id);
+    }
+
+    fn load(&self, path: &str) -> Result<Arc<str>, IncludeLoaderError> {
+        let name = path.strip_prefix("file:///").unwrap_or(path);
+        let id = TemplateId::new(name).map_err(|_| outside_template_dir(path))?;
+        if let Some(content) = self.files.get(&id) {
+            return Ok(content);
+        }
+        let file_path = id.resolve_in(&self.template_dir).map_err(|_| outside_template_dir(path))?;
+        let content: Arc<str> = std::fs::read_to_string(file_path)
+            .map_err(|e| {
+                IncludeLoaderError::new(path, e.kind())
+                    .with_message("unable to read the included file")
+                    .with_cause(Arc::new(e))
+            })?
+            .into();
+        self.files.insert(id, content.clone());
+        Ok(content)
+    }
+
+    fn record(&self, included: &str, includer: &TemplateId) {
+        let name = included.strip_prefix("file:///").unwrap_or(included);
+        if let Ok(id) = TemplateId::new(name) {
+            self.graph.record(includer, &id);
+        }
+    }
+}
+
+fn outside_template_dir(path: &str) -> IncludeLoaderError {
+    IncludeLoaderError::new(path, ErrorKind::PermissionDenied)
+        .with_message("the path should stay in the template directory")
+}
+
+/// Include loader handed to mrml for one template.
+struct TemplateIncludeLoader {
+    includes: Arc<Includes>,
+    includer: Option<TemplateId>,
+}
+
+impl fmt::Debug for TemplateIncludeLoader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TemplateIncludeLoader")
+            .field("template_dir", &self.includes.template_dir)
+            .field("includer", &self.includer)
+            .finish()
+    }
+}
+
+impl IncludeLoader for TemplateIncludeLoader {
+    fn resolve(&self, path: &str) -> Result<String, IncludeLoaderError> {
+        // Record before loading, so a template waiting on a missing file
+        // is invalidated once the file shows up.
+        if let Some(includer) = &self.includer {
+            self.includes.record(path, includer);
+        }
+        self.includes.load(path).map(|content| content.to_string())
+    }
+}
@@ -0,0 +1,151 @@
+use std::{fs, sync::OnceLock, time::UNIX_EPOCH};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::app_state::AppState;
+use crate::partials::{collect_mjml_files, partial_name};
+use crate::template_id::TemplateId;
+use crate::utils::{content_hash, format_utc};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+/// Query of `GET /templates`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListQuery {
+    /// Only names starting with this, such as `billing/`.
+    pub prefix: Option<String>,
+    /// Only templates declaring this tag.
+    pub tag: Option<String>,
+    /// Page size, 100 by default and at most 1000.
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TemplateList {
+    pub templates: Vec<TemplateEntry>,
+    /// Passed as `cursor` to get the next page, `None` on the last one.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TemplateEntry {
+    pub name: String,
+    /// `template`, or `partial` and `layout` for the files of `partials/`
+    /// and `layouts/`.
+    pub kind: &'static str,
+    /// Bytes.
+    pub size: u64,
+    /// Last modification, in RFC 3339 UTC.
+    pub modified: Option<String>,
+    /// Hex SHA-256 of the content, the `ETag` of `GET /templates/{name}`
+    /// without its quotes.
+    pub hash: String,
+    /// Whether the compiled template is in the cache.
+    pub cached: bool,
+    /// From a `{{!-- tags: billing, receipt --}}` comment in the template.
+    pub tags: Vec<String>,
+}
+
+impl ListQuery {
+    pub fn limit(&self) -> Result<usize, String> {
+        match self.limit {
+            None => Ok(DEFAULT_LIMIT),
+            Some(limit) if (1..=MAX_LIMIT).contains(&limit) => Ok(limit),
+            Some(limit) => Err(format!("limit must be between 1 and {}, got {}", MAX_LIMIT, limit)),
+        }
+    }
+}
+
+/// Lists the `.mjml` files of the template directory and its folders, sorted
+/// by name, one page at a time. Only the files of the page, and with a `tag`
+/// filter those before it, are read.
+pub fn list_templates(app_state: &AppState, query: &ListQuery) -> Result<TemplateList, String> {
+    let limit = query.limit()?;
+    let template_dir = &app_state.template_dir;
+    // Unlike a missing subfolder, a missing template directory is an error.
+    fs::read_dir(template_dir).map_err(|e| format!("Failed to read templates directory: {}", e))?;
+    let mut files = Vec::new();
+    collect_mjml_files(template_dir, &mut files);
+
+    let mut ids: Vec<TemplateId> = files
+        .iter()
+        .filter_map(|path| match TemplateId::from_path(template_dir, path) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                warn!("Not listing {:?}: {}", path, e);
+                None
+            }
+        })
+        .filter(|id| query.prefix.as_deref().is_none_or(|prefix| id.as_str().starts_with(prefix)))
+        .filter(|id| query.cursor.as_deref().is_none_or(|cursor| id.as_str() > cursor))
+        .collect();
+    ids.sort();
+
+    let mut templates = Vec::new();
+    let mut next_cursor = None;
+    for id in ids {
+        let entry = match template_entry(app_state, &id) {
+            Ok(entry) => entry,
+            // Deleted since the directory was read.
+            Err(e) => {
+                warn!("Not listing {}: {}", id, e);
+                continue;
+            }
+        };
+        if query.tag.as_ref().is_some_and(|tag| !entry.tags.contains(tag)) {
+            continue;
+        }
+        if templates.len() == limit {
+            next_cursor = templates.last().map(|last: &TemplateEntry| last.name.clone());
+            break;
+        }
+        templates.push(entry);
+    }
+    Ok(TemplateList { templates, next_cursor })
+}
+
+fn template_entry(app_state: &AppState, id: &TemplateId) -> Result<TemplateEntry, String> {
+    let path = id.resolve_in(&app_state.template_dir)?;
+    let content = fs::read(&path).map_err(|e| e.to_string())?;
+    let modified = fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since_epoch| format_utc(since_epoch.as_secs() as i64));
+    let kind = match partial_name(id) {
+        Some(_) if id.as_str().starts_with("layouts/") => "layout",
+        Some(_) => "partial",
+        None => "template",
+    };
+    Ok(TemplateEntry {
+        name: id.to_string(),
+        kind,
+        size: content.len() as u64,
+        modified,
+        hash: content_hash(&content),
+        cached: app_state.is_cached(id),
+        tags: tags(&String::from_utf8_lossy(&content)),
+    })
+}
+
+/// Tags declared by `{{!-- tags: a, b --}}` or `{{! tags: a, b }}`.
+fn tags(source: &str) -> Vec<String> {
+    static TAGS: OnceLock<Regex> = OnceLock::new();
+    let pattern = TAGS.get_or_init(|| Regex::new(r"\{\{!(?:--)?\s*tags:([^}]*?)(?:--)?\}\}").unwrap());
+    pattern
+        .captures_iter(source)
+        .flat_map(|captures| {
+            captures[1]
+                .split(',')
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
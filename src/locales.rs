@@ -0,0 +1,319 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson};
+use serde_json::Value;
+use tracing::{error, info};
+
+use crate::template_id::TemplateId;
+
+/// Subdirectory of the template directory holding one message catalog per
+/// locale, e.g. `locales/fr.json` or `locales/fr-CA.json`.
+pub const LOCALES_DIR: &str = "locales";
+
+/// A BCP 47 language tag like `fr`, `fr-CA` or `zh-Hant-TW`, normalized to
+/// its usual casing.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LocaleTag(String);
+
+impl LocaleTag {
+    pub fn new(tag: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid locale {:?}", tag);
+        let mut subtags = tag.split(['-', '_']);
+        let language = subtags.next().unwrap_or_default();
+        if !(2..=3).contains(&language.len()) || !language.bytes().all(|byte| byte.is_ascii_alphabetic()) {
+            return Err(invalid());
+        }
+        let mut normalized = language.to_ascii_lowercase();
+        for subtag in subtags {
+            if !(1..=8).contains(&subtag.len()) || !subtag.bytes().all(|byte| byte.is_ascii_alphanumeric()) {
+                return Err(invalid());
+            }
+            normalized.push('-');
+            match subtag.len() {
+                // Region: `CA`.
+                2 => normalized.push_str(&subtag.to_ascii_uppercase()),
+                // Script: `Hant`.
+                4 if subtag.bytes().all(|byte| byte.is_ascii_alphabetic()) => {
+                    normalized.push_str(&subtag[..1].to_ascii_uppercase());
+                    normalized.push_str(&subtag[1..].to_ascii_lowercase());
+                }
+                _ => normalized.push_str(&subtag.to_ascii_lowercase()),
+            }
+        }
+        Ok(LocaleTag(normalized))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn language(&self) -> &str {
+        self.0.split('-').next().unwrap_or_default()
+    }
+
+    /// The tag, then each shorter prefix: `fr-CA`, `fr`.
+    pub fn fallbacks(&self) -> impl Iterator<Item = &str> {
+        let tag = self.0.as_str();
+        std::iter::successors(Some(tag), |tag| tag.rsplit_once('-').map(|(shorter, _)| shorter))
+    }
+}
+
+impl fmt::Display for LocaleTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Names to try for template `name` in `locale`, most specific first:
+/// `welcome.fr-CA.mjml`, `welcome.fr.mjml`, then `welcome.mjml`.
+pub fn localized_names(name: &str, locale: Option<&LocaleTag>) -> Vec<String> {
+    let mut names = Vec::new();
+    if let Some(locale) = locale {
+        let (stem, extension) = match name.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() && !extension.contains('/') => (stem, Some(extension)),
+            _ => (name, None),
+        };
+        for tag in locale.fallbacks() {
+            names.push(match extension {
+                Some(extension) => format!("{}.{}.{}", stem, tag, extension),
+                None => format!("{}.{}", stem, tag),
+            });
+        }
+    }
+    names.push(name.to_string());
+    names
+}
+
+thread_local! {
+    /// Locale of the render running on the current thread.
+    static CURRENT_LOCALE: RefCell<Option<LocaleTag>> = const { RefCell::new(None) };
+}
+
+/// Runs `render` with `locale` as the locale of `{{t}}` and the number
+/// helpers. Rendering is synchronous, so it is kept on the current thread.
+pub fn with_locale<T>(locale: Option<&LocaleTag>, render: impl FnOnce() -> T) -> T {
+    let previous = CURRENT_LOCALE.with(|current| current.replace(locale.cloned()));
+    let result = render();
+    CURRENT_LOCALE.with(|current| current.replace(previous));
+    result
+}
+
+pub fn current_locale() -> Option<LocaleTag> {
+    CURRENT_LOCALE.with(|current| current.borrow().clone())
+}
+
+/// Locale a catalog file is for: `locales/fr-CA.json` -> `fr-CA`.
+pub fn catalog_locale(id: &TemplateId) -> Option<LocaleTag> {
+    let name = id.as_str().strip_prefix(LOCALES_DIR)?.strip_prefix('/')?.strip_suffix(".json")?;
+    LocaleTag::new(name).ok()
+}
+
+/// Message catalogs of the template directory, by locale.
+pub struct Catalogs {
+    template_dir: PathBuf,
+    default_locale: Option<LocaleTag>,
+    catalogs: RwLock<HashMap<LocaleTag, Arc<Value>>>,
+}
+
+impl Catalogs {
+    /// `default_locale` is tried after the locale of the request.
+    pub fn new(template_dir: PathBuf, default_locale: Option<LocaleTag>) -> Self {
+        Catalogs { template_dir, default_locale, catalogs: RwLock::new(HashMap::new()) }
+    }
+
+    /// Loads every catalog under `locales/`, returning how many were loaded.
+    /// Files that fail to parse are logged and skipped.
+    pub fn load_all(&self) -> usize {
+        // A missing locales directory just means there are no catalogs.
+        let Ok(entries) = fs::read_dir(self.template_dir.join(LOCALES_DIR)) else { return 0 };
+        let mut loaded = 0;
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let Ok(id) = TemplateId::from_path(&self.template_dir, &entry.path()) else { continue };
+            let Some(locale) = catalog_locale(&id) else { continue };
+            match self.reload(&id, locale) {
+                Ok(()) => loaded += 1,
+                Err(e) => error!("Failed to load catalog {}: {}", id, e),
+            }
+        }
+        info!("{} message catalogs loaded.", loaded);
+        loaded
+    }
+
+    /// Reads the catalog `id` for `locale` again after its file changed.
+    pub fn reload(&self, id: &TemplateId, locale: LocaleTag) -> Result<(), String> {
+        let loaded = load_catalog(&self.template_dir, id);
+        let mut catalogs = self.catalogs.write().unwrap();
+        match loaded {
+            Ok(catalog) => {
+                catalogs.insert(locale, Arc::new(catalog));
+                Ok(())
+            }
+            Err(e) => {
+                // Don't keep serving the previous version of a broken catalog.
+                catalogs.remove(&locale);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn remove(&self, locale: &LocaleTag) {
+        self.catalogs.write().unwrap().remove(locale);
+    }
+
+    /// Looks `key` up in the catalogs of `locale` and its fallbacks, then in
+    /// those of the default locale.
+    fn lookup(&self, locale: Option<&LocaleTag>, key: &str) -> Option<(LocaleTag, Value)> {
+        let catalogs = self.catalogs.read().unwrap();
+        let chain = locale.into_iter().chain(&self.default_locale);
+        for locale in chain {
+            for tag in locale.fallbacks() {
+                let Ok(tag) = LocaleTag::new(tag) else { continue };
+                if let Some(message) = catalogs.get(&tag).and_then(|catalog| find_message(catalog, key)) {
+                    return Some((tag, message.clone()));
+                }
+            }
+        }
+        None
+    }
+}
+
+fn load_catalog(template_dir: &Path, id: &TemplateId) -> Result<Value, String> {
+    let path = id.resolve_in(template_dir)?;
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read catalog file {}: {}", path.display(), e))?;
+    let catalog: Value = serde_json::from_str(&content).map_err(|e| format!("Invalid catalog {}: {}", id, e))?;
+    if !catalog.is_object() {
+        return Err(format!("Invalid catalog {}: expected a JSON object", id));
+    }
+    Ok(catalog)
+}
+
+/// A message by its dotted key, either written out (`"cart.title": ...`) or
+/// nested (`"cart": {"title": ...}`).
+fn find_message<'a>(catalog: &'a Value, key: &str) -> Option<&'a Value> {
+    if let Some(message) = catalog.get(key) {
+        return Some(message);
+    }
+    let (first, rest) = key.split_once('.')?;
+    find_message(catalog.get(first)?, rest)
+}
+
+/// Plural forms a message can have, keyed by CLDR category.
+const PLURAL_CATEGORIES: [&str; 6] = ["zero", "one", "two", "few", "many", "other"];
+
+/// CLDR plural category of `count` in `language`, for the languages with
+/// rules beyond one/other.
+fn plural_category(language: &str, count: f64) -> &'static str {
+    if count.fract() != 0.0 {
+        return "other";
+    }
+    let n = count.abs() as u64;
+    let (mod10, mod100) = (n % 10, n % 100);
+    let few = (2..=4).contains(&mod10) && !(12..=14).contains(&mod100);
+    match language {
+        "ja" | "zh" | "ko" | "th" | "vi" | "id" | "ms" => "other",
+        "fr" | "pt" if n <= 1 => "one",
+        "ru" | "uk" | "be" | "sr" | "hr" | "bs" if mod10 == 1 && mod100 != 11 => "one",
+        "ru" | "uk" | "be" | "sr" | "hr" | "bs" | "pl" if few => "few",
+        "ru" | "uk" | "be" | "sr" | "hr" | "bs" => "many",
+        "pl" if n == 1 => "one",
+        "pl" => "many",
+        "cs" | "sk" if n == 1 => "one",
+        "cs" | "sk" if (2..=4).contains(&n) => "few",
+        "fr" | "pt" | "cs" | "sk" => "other",
+        _ if n == 1 => "one",
+        _ => "other",
+    }
+}
+
+/// `{{t "cart.items" count=n}}` renders the message `cart.items` of the
+/// render's locale. `{placeholders}` are filled from the hash, and a message
+/// given as `{"one": ..., "other": ...}` picks its form by `count`, with
+/// `zero` used for 0 when present. `locale="de"` overrides the locale.
+///
+/// A missing key fails the render in strict mode and renders the key
+/// otherwise.
+pub struct Translate {
+    pub catalogs: Arc<Catalogs>,
+}
+
+impl HelperDef for Translate {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let key = h
+            .param(0)
+            .and_then(|key| key.value().as_str())
+            .ok_or_else(|| RenderError::new("t: expected a message key"))?;
+        let locale = match h.hash_get("locale").and_then(|locale| locale.value().as_str()) {
+            Some(tag) => Some(LocaleTag::new(tag).map_err(|e| RenderError::new(format!("t: {}", e)))?),
+            None => current_locale(),
+        };
+        let Some((found_in, message)) = self.catalogs.lookup(locale.as_ref(), key) else {
+            if r.strict_mode() {
+                let locale = locale.as_ref().map_or("the default locale".to_string(), LocaleTag::to_string);
+                return Err(RenderError::new(format!("t: no message {:?} for {}", key, locale)));
+            }
+            return Ok(ScopedJson::Derived(Value::String(key.to_string())));
+        };
+        let count = h.hash_get("count").and_then(|count| count.value().as_f64());
+        let text = match &message {
+            Value::String(text) => text.as_str(),
+            Value::Object(forms) if forms.keys().all(|form| PLURAL_CATEGORIES.contains(&form.as_str())) => {
+                let category = match count {
+                    Some(count) if count == 0.0 && forms.contains_key("zero") => "zero",
+                    Some(count) => plural_category(found_in.language(), count),
+                    None => "other",
+                };
+                forms
+                    .get(category)
+                    .or_else(|| forms.get("other"))
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| RenderError::new(format!("t: message {:?} has no {:?} form", key, category)))?
+            }
+            _ => return Err(RenderError::new(format!("t: {:?} is not a message", key))),
+        };
+        Ok(ScopedJson::Derived(Value::String(interpolate(text, h))))
+    }
+}
+
+/// Replaces `{name}` with the `name=` hash value of the helper call.
+/// Unknown placeholders are left as written.
+fn interpolate(text: &str, h: &Helper<'_, '_>) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after
+            .find('}')
+            .map(|end| (end, &after[..end]))
+            .and_then(|(end, name)| h.hash_get(name).map(|value| (end, value.value())));
+        match value {
+            Some((end, value)) => {
+                match value {
+                    Value::String(text) => output.push_str(text),
+                    Value::Null => {}
+                    other => output.push_str(&other.to_string()),
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                output.push('{');
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
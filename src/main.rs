@@ -1,20 +1,47 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
 
-use axum::Router;
+use axum::{extract::DefaultBodyLimit, Router};
 use axum::routing::{get, post};
 use clap::{Arg, Command};
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 
 mod app_state;
+mod atomic_write;
+mod batch;
+mod config;
+mod debug;
+mod dependency_graph;
+mod eml;
+mod escaping;
 mod handlers;
+mod helpers;
+mod history;
+mod includes;
+mod listing;
+mod locales;
+mod template_cache;
+mod template_id;
 mod template_watcher;
 mod utils;
+mod validation;
+mod variables;
+mod missing_variables;
 mod models;
+mod partials;
+mod render_options;
+mod schema;
+mod source_map;
+mod text_renderer;
 
 use app_state::initialize_state;
-use handlers::{convert_mjml, list_templates, upload_template};
+use config::ServerConfig;
+use handlers::{
+    convert_batch, convert_mjml, delete_template_file, dependency_graph, get_template_file, list_templates, post_template_file,
+    put_template_file, upload_template,
+};
 
 #[tokio::main]
 async fn main() {
@@ -30,6 +57,12 @@ async fn main() {
              .help("Sets the logging level (e.g., debug, info, warn, error)")
              .default_value("info")
              .value_parser(clap::value_parser!(String))) // Important: Use a value parser
+        .arg(Arg::new("config")
+             .short('c')
+             .long("config")
+             .value_name("FILE")
+             .help("Path to a JSON file with server-wide defaults (e.g., render_options)")
+             .value_parser(clap::value_parser!(PathBuf)))
         .get_matches();
 
     // Get the log level from the command-line arguments
@@ -44,12 +77,31 @@ async fn main() {
         .finish();
     tracing::subscriber::set_global_default(subscriber).expect("Failed to set global default subscriber");
 
-    let app_state = initialize_state("templates").await.expect("Failed to initialize app state");
+    let config = match matches.get_one::<PathBuf>("config") {
+        Some(path) => ServerConfig::load(path).expect("Failed to load config file"),
+        None => ServerConfig::default(),
+    };
+
+    let app_state = initialize_state("templates", config).await.expect("Failed to initialize app state");
+    // Handlers check the configured limits; these keep bodies from growing
+    // past them before they do.
+    let max_file_bytes = DefaultBodyLimit::max(app_state.config.max_file_bytes());
+    let max_upload_bytes = DefaultBodyLimit::max(app_state.config.max_upload_bytes());
     // Creates the Axum router with routes for MJML conversion, template listing, and template upload.
     let app = Router::new()
         .route("/convert", post(convert_mjml))
+        .route("/convert/batch", post(convert_batch))
         .route("/templates", get(list_templates))
-        .route("/templates", post(upload_template))
+        .route("/templates", post(upload_template).layer(max_upload_bytes))
+        .route(
+            "/templates/*path",
+            get(get_template_file)
+                .put(put_template_file)
+                .post(post_template_file)
+                .delete(delete_template_file)
+                .layer(max_file_bytes),
+        )
+        .route("/admin/dependencies", get(dependency_graph))
         .with_state(app_state);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 3030));
@@ -0,0 +1,103 @@
+use std::cell::RefCell;
+
+use axum::http::StatusCode;
+use handlebars::{Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError};
+use serde::Deserialize;
+
+/// What rendering does when a template refers to a value the payload
+/// doesn't have.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingVariables {
+    /// Render it as an empty string, like Handlebars does by default.
+    #[default]
+    Ignore,
+    /// Fail the render, using Handlebars' strict mode.
+    Strict,
+    /// Render it as an empty string and report its path as a warning. Only
+    /// bare `{{value}}` outputs are seen: missing values used by `#if`,
+    /// `#each` or `#with`, or passed to a helper, aren't reported.
+    Report,
+}
+
+thread_local! {
+    /// Paths collected by `ReportMissing` during the current render.
+    static UNRESOLVED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Runs `render` and, in `Report` mode, returns the paths of the `{{values}}`
+/// it couldn't resolve. Rendering is synchronous, so the paths are collected
+/// on the current thread.
+pub fn track_unresolved<T>(mode: MissingVariables, render: impl FnOnce() -> T) -> (T, Vec<String>) {
+    if mode != MissingVariables::Report {
+        return (render(), Vec::new());
+    }
+    let previous = UNRESOLVED.with(|unresolved| unresolved.borrow_mut().replace(Vec::new()));
+    let result = render();
+    let paths = UNRESOLVED.with(|unresolved| std::mem::replace(&mut *unresolved.borrow_mut(), previous));
+    (result, paths.unwrap_or_default())
+}
+
+/// `helperMissing` hook of the lenient registry. Handlebars calls it for a
+/// `{{value}}` it can't resolve, and for a helper call to an unknown helper.
+pub struct ReportMissing;
+
+impl HelperDef for ReportMissing {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        _: &mut dyn Output,
+    ) -> HelperResult {
+        // Keep the error Handlebars gives for unknown helpers without the hook.
+        if !h.params().is_empty() || !h.hash().is_empty() {
+            return Err(RenderError::new(format!("Helper not defined: {:?}", h.name())));
+        }
+        UNRESOLVED.with(|unresolved| {
+            if let Some(paths) = unresolved.borrow_mut().as_mut() {
+                let path = full_path(rc, h.name());
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Path of `name` from the root of the payload, e.g. `items.1.sku` for
+/// `{{sku}}` inside `{{#each items}}`.
+fn full_path(rc: &RenderContext<'_, '_>, name: &str) -> String {
+    let name = name.strip_prefix("this.").unwrap_or(name);
+    if let Some(absolute) = name.strip_prefix("@root.") {
+        return absolute.to_string();
+    }
+    let base = rc.block().map(|block| block.base_path().join(".")).unwrap_or_default();
+    if base.is_empty() || name.starts_with("../") {
+        name.to_string()
+    } else {
+        format!("{}.{}", base, name)
+    }
+}
+
+/// Status of a failed Handlebars render: 422 when strict mode refused a
+/// value the payload doesn't have, 500 when the template is at fault.
+pub fn render_error_status(error: &RenderError) -> StatusCode {
+    // Handlebars' messages for `RenderError::strict_error`.
+    let missing = error.desc == "Value is missing in strict mode"
+        || (error.desc.starts_with("Variable ") && error.desc.ends_with(" not found in strict mode."));
+    if missing {
+        StatusCode::UNPROCESSABLE_ENTITY
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Registers the hooks `MissingVariables` relies on. `strict` is the twin
+/// registry used for `MissingVariables::Strict`.
+pub fn configure(lenient: &mut Handlebars<'static>, strict: &mut Handlebars<'static>) {
+    lenient.register_helper("helperMissing", Box::new(ReportMissing));
+    strict.set_strict_mode(true);
+}
@@ -1,9 +1,155 @@
-use serde::{Deserialize};
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Deserialize)]
+use crate::missing_variables::MissingVariables;
+use crate::source_map::SourceLocation;
+
+#[derive(Default, Deserialize)]
 pub struct MjmlInput {
     pub mjml: Option<String>,
     pub payload: Value,
     pub template: Option<String>,
+    /// Overrides for mrml's `RenderOptions`, see [`RenderOptionsInput`].
+    /// Kept as a raw value so unknown keys can be reported as a 400.
+    #[serde(default)]
+    pub render_options: Option<Value>,
+    /// What `/convert` sends back. When omitted it is negotiated from the
+    /// `Accept` header, falling back to HTML.
+    #[serde(default)]
+    pub output: Option<OutputFormat>,
+    /// Envelope headers used by the `eml` output.
+    #[serde(default)]
+    pub email: Option<EmailHeaders>,
+    /// Overrides the server-wide handling of unresolved `{{values}}`.
+    #[serde(default)]
+    pub missing_variables: Option<MissingVariables>,
+    /// Language of the email, e.g. `fr-CA`: picks the most specific template
+    /// variant (`welcome.fr-CA.mjml`, `welcome.fr.mjml`, `welcome.mjml`) and
+    /// the messages of `{{t}}`.
+    #[serde(default)]
+    pub locale: Option<String>,
+    /// Answer with every stage of the render instead of its output, see
+    /// [`DebugReport`](crate::debug::DebugReport). Needs the admin token.
+    #[serde(default)]
+    pub debug: bool,
+}
+
+/// Headers for a `message/rfc822` response. `subject` falls back to the
+/// template's `<mj-title>`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailHeaders {
+    pub subject: Option<String>,
+    pub from: Option<String>,
+    #[serde(default)]
+    pub to: Vec<String>,
+    pub reply_to: Option<String>,
+}
+
+/// Response body produced by `/convert`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// The HTML rendered by mrml.
+    #[default]
+    Html,
+    /// The text/plain alternative derived from the rendered HTML.
+    Text,
+    /// A [`ConvertResponse`] document.
+    Json,
+    /// A complete `message/rfc822` email with text and HTML alternatives.
+    Eml,
+}
+
+/// Body returned by `/convert` in JSON mode.
+#[derive(Debug, Serialize)]
+pub struct ConvertResponse {
+    pub html: String,
+    pub text: String,
+    /// Content of `<mj-title>`, if any.
+    pub title: Option<String>,
+    /// Content of `<mj-preview>`, if any.
+    pub preview: Option<String>,
+    /// Size of `html` in bytes.
+    pub size_bytes: usize,
+    pub warnings: Vec<String>,
+    /// Name of the template that was rendered, `None` for inline MJML.
+    pub template: Option<String>,
+    /// Wall time spent in the conversion pipeline, in milliseconds.
+    pub render_ms: f64,
+}
+
+/// User facing mirror of `mrml::prelude::render::RenderOptions`.
+/// Every field is optional so it can be layered: mrml defaults, then the
+/// server config, then the request.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RenderOptionsInput {
+    /// Strip the HTML comments mrml emits around conditional blocks.
+    pub disable_comments: Option<bool>,
+    /// Base URL used for `<mj-social-element>` icons.
+    pub social_icon_origin: Option<String>,
+    /// Font name to stylesheet URL. Entries are merged over mrml's default
+    /// fonts; a `null` URL removes a font from the set.
+    pub fonts: Option<HashMap<String, Option<String>>>,
+}
+
+/// Body of `/convert/batch`: one template rendered against many payloads.
+#[derive(Default, Deserialize)]
+pub struct BatchInput {
+    pub mjml: Option<String>,
+    pub template: Option<String>,
+    #[serde(default)]
+    pub render_options: Option<Value>,
+    #[serde(default)]
+    pub missing_variables: Option<MissingVariables>,
+    /// Locale of every payload, as in [`MjmlInput`].
+    #[serde(default)]
+    pub locale: Option<String>,
+    /// One entry per recipient. Left empty when payloads are streamed as NDJSON.
+    #[serde(default)]
+    pub payloads: Vec<Value>,
+}
+
+/// Query of `GET /templates/{name}/diff`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiffQuery {
+    pub from: u64,
+    /// Defaults to the current file.
+    pub to: Option<u64>,
+}
+
+/// Body of `POST /templates/{name}/rollback`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RollbackInput {
+    /// The revision to restore.
+    pub rev: u64,
+}
+
+/// One NDJSON line of a batch response.
+#[derive(Debug, Serialize)]
+pub struct BatchLine {
+    /// Position of the payload in the request.
+    pub index: usize,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<ConvertResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Where in the template an MJML error comes from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<SourceLocation>,
+}
+
+impl BatchLine {
+    pub fn new(index: usize, outcome: Result<ConvertResponse, (String, Option<SourceLocation>)>) -> Self {
+        match outcome {
+            Ok(result) => BatchLine { index, ok: true, result: Some(result), error: None, location: None },
+            Err((error, location)) => BatchLine { index, ok: false, result: None, error: Some(error), location },
+        }
+    }
 }
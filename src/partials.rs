@@ -0,0 +1,144 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use handlebars::{template::TemplateElement, Handlebars, Template};
+use tracing::{error, info, warn};
+
+use crate::dependency_graph::DependencyGraph;
+use crate::template_id::TemplateId;
+
+/// Subdirectories of the template directory whose files are registered as
+/// Handlebars partials: `partials/` for `{{> header}}`, `layouts/` for
+/// `{{#> base}}...{{/base}}` partial blocks.
+pub const PARTIAL_DIRS: [&str; 2] = ["partials", "layouts"];
+
+/// Name a template is registered under when it is a partial or layout:
+/// its path inside `partials/` or `layouts/`, without the `.mjml` extension.
+pub fn partial_name(id: &TemplateId) -> Option<&str> {
+    let (dir, rest) = id.as_str().split_once('/')?;
+    if !PARTIAL_DIRS.contains(&dir) {
+        return None;
+    }
+    rest.strip_suffix(".mjml")
+}
+
+/// Files a partial called `name` can come from. Both are dependencies of a
+/// template using it, even when one doesn't exist yet.
+pub fn partial_files(name: &str) -> impl Iterator<Item = TemplateId> + '_ {
+    PARTIAL_DIRS
+        .into_iter()
+        .filter_map(move |dir| TemplateId::new(&format!("{}/{}.mjml", dir, name)).ok())
+}
+
+/// Records in `graph` the files of the partials that `template` uses by name.
+pub fn record_partials(graph: &DependencyGraph, id: &TemplateId, template: &Template) {
+    let mut names = Vec::new();
+    collect_partial_names(template, &mut names);
+    for name in names {
+        for file in partial_files(name) {
+            graph.record(id, &file);
+        }
+    }
+}
+
+fn collect_partial_names<'a>(template: &'a Template, names: &mut Vec<&'a str>) {
+    for element in &template.elements {
+        match element {
+            TemplateElement::PartialExpression(partial) | TemplateElement::PartialBlock(partial) => {
+                // `@partial-block` and partials named by an expression
+                // aren't files that can be looked up ahead of time.
+                if let Some(name) = partial.name.as_name().filter(|name| !name.starts_with('@')) {
+                    names.push(name);
+                }
+                if let Some(inner) = &partial.template {
+                    collect_partial_names(inner, names);
+                }
+            }
+            TemplateElement::HelperBlock(helper) => {
+                for inner in helper.template.iter().chain(&helper.inverse) {
+                    collect_partial_names(inner, names);
+                }
+            }
+            TemplateElement::DecoratorBlock(decorator) => {
+                if let Some(inner) = &decorator.template {
+                    collect_partial_names(inner, names);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Registers every partial and layout found under `template_dir` in each of
+/// `registries`, returning how many were registered. Files that fail to
+/// compile are logged and skipped.
+pub fn register_partials(
+    registries: &mut [&mut Handlebars<'static>],
+    template_dir: &Path,
+    graph: &DependencyGraph,
+) -> usize {
+    let mut registered = 0;
+    for dir in PARTIAL_DIRS {
+        let mut files = Vec::new();
+        collect_mjml_files(&template_dir.join(dir), &mut files);
+        for path in files {
+            let id = match TemplateId::from_path(template_dir, &path) {
+                Ok(id) => id,
+                Err(e) => {
+                    error!("Skipping partial {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            let Some(name) = partial_name(&id) else { continue };
+            if registries.iter().any(|handlebars| handlebars.has_template(name)) {
+                warn!("Partial {} is defined more than once, {} replaces it", name, id);
+            }
+            match load_partial(registries, template_dir, graph, &id, name) {
+                Ok(()) => registered += 1,
+                Err(e) => error!("Failed to register partial {}: {}", id, e),
+            }
+        }
+    }
+    info!("{} partials registered.", registered);
+    registered
+}
+
+/// Reads `id` from disk, registers it under `name` in each of `registries`
+/// and records the partials it uses in turn.
+pub fn load_partial(
+    registries: &mut [&mut Handlebars<'static>],
+    template_dir: &Path,
+    graph: &DependencyGraph,
+    id: &TemplateId,
+    name: &str,
+) -> Result<(), String> {
+    let path = id.resolve_in(template_dir)?;
+    let source = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read partial file {}: {}", path.display(), e))?;
+    let mut template = Template::compile(&source)
+        .map_err(|e| format!("Handlebars compile error in {}: {}", id, e))?;
+    template.name = Some(name.to_string());
+    graph.clear(id);
+    record_partials(graph, id, &template);
+    for handlebars in registries.iter_mut() {
+        handlebars.register_template(name, template.clone());
+    }
+    Ok(())
+}
+
+/// Every `.mjml` file below `dir`.
+pub fn collect_mjml_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    // A missing partials or layouts directory just means there are none.
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        // Symlinked directories are not followed, so a link cycle can't hang startup.
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            collect_mjml_files(&path, files);
+        } else if path.extension().is_some_and(|ext| ext == "mjml") {
+            files.push(path);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::borrow::Cow;
+
+use mrml::prelude::render::RenderOptions;
+use serde_json::Value;
+
+use crate::models::RenderOptionsInput;
+
+impl RenderOptionsInput {
+    /// Parses the `render_options` object of a request, rejecting unknown keys.
+    pub fn from_value(value: Value) -> Result<Self, String> {
+        let input: RenderOptionsInput = serde_json::from_value(value)
+            .map_err(|e| format!("Invalid render_options: {}", e))?;
+        input.validate()?;
+        Ok(input)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(origin) = &self.social_icon_origin {
+            check_url("social_icon_origin", origin)?;
+        }
+        if let Some(fonts) = &self.fonts {
+            for (name, url) in fonts {
+                if name.trim().is_empty() {
+                    return Err("fonts: font name must not be empty".to_string());
+                }
+                if let Some(url) = url {
+                    check_url(&format!("fonts.{}", name), url)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies these options on top of `options`, leaving unset fields untouched.
+    pub fn apply(&self, options: &mut RenderOptions) {
+        if let Some(disable_comments) = self.disable_comments {
+            options.disable_comments = disable_comments;
+        }
+        if let Some(origin) = &self.social_icon_origin {
+            options.social_icon_origin = Some(Cow::Owned(origin.clone()));
+        }
+        if let Some(fonts) = &self.fonts {
+            for (name, url) in fonts {
+                match url {
+                    Some(url) => {
+                        options.fonts.insert(name.clone(), Cow::Owned(url.clone()));
+                    }
+                    None => {
+                        options.fonts.remove(name);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Builds the options for one render: mrml defaults, then server config, then request.
+pub fn resolve_render_options(
+    defaults: &RenderOptionsInput,
+    request: Option<&RenderOptionsInput>,
+) -> RenderOptions {
+    let mut options = RenderOptions::default();
+    defaults.apply(&mut options);
+    if let Some(request) = request {
+        request.apply(&mut options);
+    }
+    options
+}
+
+fn check_url(field: &str, url: &str) -> Result<(), String> {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        Ok(())
+    } else {
+        Err(format!("{}: expected an http(s) URL, got {:?}", field, url))
+    }
+}
@@ -0,0 +1,481 @@
+use std::{collections::HashMap, fmt, fs, io, path::Path};
+
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::template_id::TemplateId;
+
+/// Extension of the sidecar schema of a template: `billing/invoice.mjml` is
+/// validated against `billing/invoice.schema.json`.
+pub const SCHEMA_EXTENSION: &str = ".schema.json";
+
+/// A JSON Schema a template's payload is validated against before rendering.
+///
+/// Supports the validation keywords of draft 2020-12 that describe the shape
+/// of data: `type`, `enum`, `const`, `properties`, `required`,
+/// `additionalProperties`, `patternProperties`, `minProperties`,
+/// `maxProperties`, `items` (and draft-07 tuple `items`), `prefixItems`,
+/// `minItems`, `maxItems`, `uniqueItems`, `minLength`, `maxLength`,
+/// `pattern`, `minimum`, `maximum`, `exclusiveMinimum`, `exclusiveMaximum`,
+/// `multipleOf`, `allOf`, `anyOf`, `oneOf`, `not`, `if`/`then`/`else` and
+/// local `$ref`s. Other keywords, `format` included, are annotations and
+/// don't fail validation.
+#[derive(Debug)]
+pub struct Schema {
+    root: Value,
+    patterns: HashMap<String, Regex>,
+}
+
+/// One place where a payload doesn't match its schema.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Violation {
+    /// JSON pointer to the offending value, `""` for the payload itself.
+    pub path: String,
+    pub message: String,
+}
+
+/// Id of the sidecar schema of the template `id`.
+pub fn schema_id(id: &TemplateId) -> TemplateId {
+    let stem = id.as_str().strip_suffix(".mjml").unwrap_or(id.as_str());
+    TemplateId::new(&format!("{}{}", stem, SCHEMA_EXTENSION)).expect("a template id with a suffix is a valid id")
+}
+
+/// Loads the sidecar schema of the template `id`, `None` if it has none.
+pub fn load_schema(template_dir: &Path, id: &TemplateId) -> Result<Option<Schema>, String> {
+    let schema_id = schema_id(id);
+    let path = schema_id.resolve_in(template_dir)?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read schema {}: {}", schema_id, e)),
+    };
+    let root = serde_json::from_str(&content).map_err(|e| format!("Invalid JSON in schema {}: {}", schema_id, e))?;
+    Schema::compile(root)
+        .map(Some)
+        .map_err(|e| format!("Invalid schema {}: {}", schema_id, e))
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = if self.path.is_empty() { "(payload)" } else { &self.path };
+        write!(f, "{}: {}", path, self.message)
+    }
+}
+
+/// Keywords holding example data rather than sub-schemas.
+const DATA_KEYWORDS: [&str; 4] = ["enum", "const", "default", "examples"];
+
+/// How deep `$ref`s may nest before the schema is considered recursive
+/// without end.
+const MAX_DEPTH: usize = 64;
+
+impl Schema {
+    /// Checks that `root` is a usable schema and compiles its patterns.
+    pub fn compile(root: Value) -> Result<Self, String> {
+        let mut patterns = HashMap::new();
+        collect_patterns(&root, &mut patterns)?;
+        let schema = Schema { root, patterns };
+        schema.check_refs(&schema.root)?;
+        Ok(schema)
+    }
+
+    /// Every violation in `instance`, empty when it matches.
+    pub fn validate(&self, instance: &Value) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        self.validate_at(&self.root, instance, &mut String::new(), 0, &mut violations);
+        violations
+    }
+
+    fn is_valid(&self, schema: &Value, instance: &Value, depth: usize) -> bool {
+        let mut violations = Vec::new();
+        self.validate_at(schema, instance, &mut String::new(), depth, &mut violations);
+        violations.is_empty()
+    }
+
+    fn validate_at(&self, schema: &Value, instance: &Value, path: &mut String, depth: usize, out: &mut Vec<Violation>) {
+        let mut fail = |message: String| out.push(Violation { path: path.clone(), message });
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => return fail("No value is allowed here".to_string()),
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+        if depth > MAX_DEPTH {
+            return fail("Schema $ref nesting is too deep".to_string());
+        }
+
+        if let Some(Value::String(reference)) = schema.get("$ref") {
+            match self.resolve_ref(reference) {
+                Some(target) => self.validate_at(target, instance, path, depth + 1, out),
+                None => out.push(Violation { path: path.clone(), message: format!("Unresolvable $ref {}", reference) }),
+            }
+        }
+        let mut fail = |message: String| out.push(Violation { path: path.clone(), message });
+
+        if let Some(expected) = schema.get("type") {
+            let matches = match expected {
+                Value::String(name) => has_type(instance, name),
+                Value::Array(names) => names.iter().filter_map(Value::as_str).any(|name| has_type(instance, name)),
+                _ => true,
+            };
+            if !matches {
+                fail(format!("Expected {}, found {}", type_list(expected), type_name(instance)));
+            }
+        }
+        if let Some(Value::Array(allowed)) = schema.get("enum") {
+            if !allowed.iter().any(|value| json_eq(value, instance)) {
+                fail(format!("Must be one of {}", Value::Array(allowed.clone())));
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if !json_eq(expected, instance) {
+                fail(format!("Must be {}", expected));
+            }
+        }
+
+        match instance {
+            Value::String(text) => self.validate_string(schema, text, &mut fail),
+            Value::Number(_) => validate_number(schema, instance.as_f64().unwrap_or_default(), &mut fail),
+            _ => {}
+        }
+        match instance {
+            Value::Object(object) => self.validate_object(schema, object, path, depth, out),
+            Value::Array(items) => self.validate_array(schema, items, path, depth, out),
+            _ => {}
+        }
+
+        if let Some(Value::Array(schemas)) = schema.get("allOf") {
+            for sub_schema in schemas {
+                self.validate_at(sub_schema, instance, path, depth + 1, out);
+            }
+        }
+        let mut fail = |message: String| out.push(Violation { path: path.clone(), message });
+        if let Some(Value::Array(schemas)) = schema.get("anyOf") {
+            if !schemas.iter().any(|sub_schema| self.is_valid(sub_schema, instance, depth + 1)) {
+                fail("Must match at least one schema in anyOf".to_string());
+            }
+        }
+        if let Some(Value::Array(schemas)) = schema.get("oneOf") {
+            let matching = schemas.iter().filter(|sub_schema| self.is_valid(sub_schema, instance, depth + 1)).count();
+            if matching != 1 {
+                fail(format!("Must match exactly one schema in oneOf, matches {}", matching));
+            }
+        }
+        if let Some(sub_schema) = schema.get("not") {
+            if self.is_valid(sub_schema, instance, depth + 1) {
+                fail("Must not match the schema in not".to_string());
+            }
+        }
+        if let Some(condition) = schema.get("if") {
+            let branch = if self.is_valid(condition, instance, depth + 1) { "then" } else { "else" };
+            if let Some(sub_schema) = schema.get(branch) {
+                self.validate_at(sub_schema, instance, path, depth + 1, out);
+            }
+        }
+    }
+
+    fn validate_string(&self, schema: &Map<String, Value>, text: &str, fail: &mut impl FnMut(String)) {
+        let length = text.chars().count() as u64;
+        if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+            if length < min {
+                fail(format!("Must be at least {} characters long", min));
+            }
+        }
+        if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+            if length > max {
+                fail(format!("Must be at most {} characters long", max));
+            }
+        }
+        if let Some(Value::String(pattern)) = schema.get("pattern") {
+            if !self.matches(pattern, text) {
+                fail(format!("Must match the pattern {}", pattern));
+            }
+        }
+    }
+
+    fn validate_object(
+        &self,
+        schema: &Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &mut String,
+        depth: usize,
+        out: &mut Vec<Violation>,
+    ) {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    with_segment(path, name, |path| {
+                        out.push(Violation { path: path.clone(), message: "Missing required property".to_string() });
+                    });
+                }
+            }
+        }
+        let count = object.len() as u64;
+        if let Some(min) = schema.get("minProperties").and_then(Value::as_u64) {
+            if count < min {
+                out.push(Violation { path: path.clone(), message: format!("Must have at least {} properties", min) });
+            }
+        }
+        if let Some(max) = schema.get("maxProperties").and_then(Value::as_u64) {
+            if count > max {
+                out.push(Violation { path: path.clone(), message: format!("Must have at most {} properties", max) });
+            }
+        }
+
+        let properties = schema.get("properties").and_then(Value::as_object);
+        let pattern_properties = schema.get("patternProperties").and_then(Value::as_object);
+        for (name, value) in object {
+            let mut described = false;
+            if let Some(sub_schema) = properties.and_then(|properties| properties.get(name)) {
+                described = true;
+                with_segment(path, name, |path| self.validate_at(sub_schema, value, path, depth + 1, out));
+            }
+            for (pattern, sub_schema) in pattern_properties.into_iter().flatten() {
+                if self.matches(pattern, name) {
+                    described = true;
+                    with_segment(path, name, |path| self.validate_at(sub_schema, value, path, depth + 1, out));
+                }
+            }
+            if described {
+                continue;
+            }
+            match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => with_segment(path, name, |path| {
+                    out.push(Violation { path: path.clone(), message: "Unexpected property".to_string() });
+                }),
+                Some(sub_schema) => {
+                    with_segment(path, name, |path| self.validate_at(sub_schema, value, path, depth + 1, out));
+                }
+                None => {}
+            }
+        }
+    }
+
+    fn validate_array(
+        &self,
+        schema: &Map<String, Value>,
+        items: &[Value],
+        path: &mut String,
+        depth: usize,
+        out: &mut Vec<Violation>,
+    ) {
+        let count = items.len() as u64;
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+            if count < min {
+                out.push(Violation { path: path.clone(), message: format!("Must have at least {} items", min) });
+            }
+        }
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+            if count > max {
+                out.push(Violation { path: path.clone(), message: format!("Must have at most {} items", max) });
+            }
+        }
+        if schema.get("uniqueItems") == Some(&Value::Bool(true)) {
+            let duplicate = items
+                .iter()
+                .enumerate()
+                .any(|(index, item)| items[..index].iter().any(|earlier| json_eq(earlier, item)));
+            if duplicate {
+                out.push(Violation { path: path.clone(), message: "Items must be unique".to_string() });
+            }
+        }
+
+        // Draft 2020-12 `prefixItems` + `items`, or draft-07 tuple `items` + `additionalItems`.
+        let (prefix, rest) = match (schema.get("prefixItems"), schema.get("items")) {
+            (Some(Value::Array(prefix)), rest) => (prefix.as_slice(), rest),
+            (None, Some(Value::Array(prefix))) => (prefix.as_slice(), schema.get("additionalItems")),
+            (_, rest) => (&[][..], rest),
+        };
+        for (index, item) in items.iter().enumerate() {
+            let sub_schema = match prefix.get(index) {
+                Some(sub_schema) => sub_schema,
+                None => match rest {
+                    Some(sub_schema) => sub_schema,
+                    None => continue,
+                },
+            };
+            with_segment(path, &index.to_string(), |path| self.validate_at(sub_schema, item, path, depth + 1, out));
+        }
+    }
+
+    fn matches(&self, pattern: &str, text: &str) -> bool {
+        // Every pattern was compiled by `Schema::compile`.
+        self.patterns.get(pattern).is_none_or(|regex| regex.is_match(text))
+    }
+
+    /// Looks up a `$ref` inside this schema: `#`, `#/json/pointer`, or the
+    /// `$anchor` / draft-07 `$id: "#name"` of a sub-schema.
+    fn resolve_ref(&self, reference: &str) -> Option<&Value> {
+        let fragment = reference.strip_prefix('#')?;
+        if fragment.is_empty() || fragment.starts_with('/') {
+            return self.root.pointer(&percent_decode(fragment));
+        }
+        find_anchor(&self.root, fragment)
+    }
+
+    fn check_refs(&self, schema: &Value) -> Result<(), String> {
+        match schema {
+            Value::Object(object) => {
+                if let Some(Value::String(reference)) = object.get("$ref") {
+                    if self.resolve_ref(reference).is_none() {
+                        return Err(format!("Unsupported or unresolvable $ref {:?}, only local references are allowed", reference));
+                    }
+                }
+                object
+                    .iter()
+                    .filter(|(keyword, _)| !DATA_KEYWORDS.contains(&keyword.as_str()))
+                    .try_for_each(|(_, value)| self.check_refs(value))
+            }
+            Value::Array(values) => values.iter().try_for_each(|value| self.check_refs(value)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn validate_number(schema: &Map<String, Value>, number: f64, fail: &mut impl FnMut(String)) {
+    let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+    if let Some(min) = bound("minimum") {
+        if number < min {
+            fail(format!("Must be at least {}", min));
+        }
+    }
+    if let Some(max) = bound("maximum") {
+        if number > max {
+            fail(format!("Must be at most {}", max));
+        }
+    }
+    if let Some(min) = bound("exclusiveMinimum") {
+        if number <= min {
+            fail(format!("Must be greater than {}", min));
+        }
+    }
+    if let Some(max) = bound("exclusiveMaximum") {
+        if number >= max {
+            fail(format!("Must be less than {}", max));
+        }
+    }
+    if let Some(divisor) = bound("multipleOf").filter(|divisor| *divisor > 0.0) {
+        let quotient = number / divisor;
+        if (quotient - quotient.round()).abs() > 1e-9 {
+            fail(format!("Must be a multiple of {}", divisor));
+        }
+    }
+}
+
+/// Runs `f` with `segment` appended to the JSON pointer `path`.
+fn with_segment<R>(path: &mut String, segment: &str, f: impl FnOnce(&mut String) -> R) -> R {
+    let length = path.len();
+    path.push('/');
+    path.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    let result = f(path);
+    path.truncate(length);
+    result
+}
+
+fn has_type(instance: &Value, name: &str) -> bool {
+    match name {
+        "integer" => instance.as_f64().is_some_and(|number| number.fract() == 0.0),
+        "number" => instance.is_number(),
+        other => type_name(instance) == other,
+    }
+}
+
+fn type_name(instance: &Value) -> &'static str {
+    match instance {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn type_list(expected: &Value) -> String {
+    match expected {
+        Value::Array(names) => names.iter().filter_map(Value::as_str).collect::<Vec<_>>().join(" or "),
+        other => other.as_str().unwrap_or_default().to_string(),
+    }
+}
+
+/// Equality where `1` and `1.0` are the same number, as JSON Schema requires.
+fn json_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (Value::Array(a), Value::Array(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| json_eq(a, b)),
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len() && a.iter().all(|(key, value)| b.get(key).is_some_and(|other| json_eq(value, other)))
+        }
+        (a, b) => a == b,
+    }
+}
+
+fn find_anchor<'a>(schema: &'a Value, name: &str) -> Option<&'a Value> {
+    match schema {
+        Value::Object(object) => {
+            let anchored = object.get("$anchor").and_then(Value::as_str) == Some(name)
+                || object.get("$id").and_then(Value::as_str).and_then(|id| id.strip_prefix('#')) == Some(name);
+            if anchored {
+                return Some(schema);
+            }
+            object.values().find_map(|value| find_anchor(value, name))
+        }
+        Value::Array(values) => values.iter().find_map(|value| find_anchor(value, name)),
+        _ => None,
+    }
+}
+
+/// Decodes the `%XX` escapes a URI fragment may use in a JSON pointer.
+fn percent_decode(fragment: &str) -> String {
+    let bytes = fragment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = (bytes[index] == b'%')
+            .then(|| fragment.get(index + 1..index + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Compiles every `pattern` and `patternProperties` key up front, so a bad
+/// regex is reported when the schema loads rather than on some payload.
+fn collect_patterns(schema: &Value, patterns: &mut HashMap<String, Regex>) -> Result<(), String> {
+    let mut add = |pattern: &str| -> Result<(), String> {
+        if !patterns.contains_key(pattern) {
+            let regex = Regex::new(pattern).map_err(|e| format!("Invalid pattern {:?}: {}", pattern, e))?;
+            patterns.insert(pattern.to_string(), regex);
+        }
+        Ok(())
+    };
+    match schema {
+        Value::Object(object) => {
+            if let Some(Value::String(pattern)) = object.get("pattern") {
+                add(pattern)?;
+            }
+            if let Some(Value::Object(pattern_properties)) = object.get("patternProperties") {
+                for pattern in pattern_properties.keys() {
+                    add(pattern)?;
+                }
+            }
+            object
+                .iter()
+                .filter(|(keyword, _)| !DATA_KEYWORDS.contains(&keyword.as_str()))
+                .try_for_each(|(_, value)| collect_patterns(value, patterns))
+        }
+        Value::Array(values) => values.iter().try_for_each(|value| collect_patterns(value, patterns)),
+        _ => Ok(()),
+    }
+}
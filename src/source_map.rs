@@ -0,0 +1,274 @@
+use std::{collections::HashSet, fs, ops::Range, path::Path};
+
+use handlebars::{
+    template::{TemplateElement, TemplateMapping},
+    Context, Handlebars, Output, RenderContext, RenderError, Renderable, Template,
+};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::escaping::strip_invalid_xml_chars;
+use crate::partials::partial_files;
+
+/// Where in a template file an MJML error comes from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SourceLocation {
+    /// Template or partial file, `inline` for MJML sent in the request.
+    pub file: String,
+    /// 1-based.
+    pub line: usize,
+    /// 1-based, in characters.
+    pub column: usize,
+    /// The lines around the error, with a caret under its column.
+    pub snippet: String,
+}
+
+/// Byte offset in `mjml` that an mrml parse error points at.
+pub fn error_offset(mjml: &str, error: &mrml::prelude::parser::Error) -> Option<usize> {
+    use mrml::prelude::parser::Error;
+    match error {
+        Error::UnexpectedAttribute(span)
+        | Error::UnexpectedElement(span)
+        | Error::UnexpectedToken(span)
+        | Error::MissingAttribute(_, span)
+        | Error::InvalidAttribute(span)
+        | Error::InvalidFormat(span)
+        | Error::IncludeLoaderError { position: span, .. } => Some(span.start),
+        Error::ParserError(error) => {
+            let position = error.pos();
+            offset_of(mjml, position.row as usize, position.col as usize)
+        }
+        _ => None,
+    }
+}
+
+/// Maps `offset` in the MJML that `template` expanded to with `data` back
+/// to `source`, the file it was compiled from, or to a partial of
+/// `template_dir` the text there came from. Must run with the locale of
+/// the failed render, so the expansion comes out the same.
+///
+/// Raw text maps character by character. Inside a block or a partial, the
+/// raw text around `offset` is looked up among the block's and the
+/// partial's own; failing that, the location is the `{{` of the expression.
+pub fn locate(
+    handlebars: &Handlebars<'static>,
+    template_dir: &Path,
+    template: &Template,
+    source: &str,
+    data: &Value,
+    offset: usize,
+) -> Option<SourceLocation> {
+    let file = template.name.as_deref().unwrap_or("inline");
+    TrackedRender::render(handlebars, template, data)
+        .ok()?
+        .locate(offset, file, source, handlebars, template_dir)
+}
+
+/// A template rendered element by element, remembering which top-level
+/// element produced each part of the output. Rendering is deterministic,
+/// so this reproduces the output mrml failed on.
+struct TrackedRender<'t> {
+    template: &'t Template,
+    /// The output as handed to mrml.
+    mjml: String,
+    /// Output range of each element of `template`.
+    segments: Vec<Range<usize>>,
+}
+
+impl<'t> TrackedRender<'t> {
+    fn render(handlebars: &Handlebars<'static>, template: &'t Template, data: &Value) -> Result<Self, RenderError> {
+        let context = Context::wraps(data)?;
+        let mut render_context = RenderContext::new(template.name.as_ref());
+        let mut output = SegmentOutput::default();
+        let mut mjml = String::new();
+        let mut segments = Vec::with_capacity(template.elements.len());
+        for element in &template.elements {
+            element.render(handlebars, &context, &mut render_context, &mut output)?;
+            let start = mjml.len();
+            mjml.push_str(&strip_invalid_xml_chars(&output.take()));
+            segments.push(start..mjml.len());
+        }
+        Ok(TrackedRender { template, mjml, segments })
+    }
+
+    fn locate(&self, offset: usize, file: &str, source: &str, handlebars: &Handlebars<'static>, template_dir: &Path) -> Option<SourceLocation> {
+        let index = self
+            .segments
+            .iter()
+            .position(|segment| segment.contains(&offset))
+            .or_else(|| self.segments.iter().rposition(|segment| segment.end == offset))?;
+        let segment = self.segments[index].clone();
+        let &TemplateMapping(line, column) = self.template.mapping.get(index)?;
+        let element_start = offset_of(source, line, column).unwrap_or(0);
+
+        let mut pieces = Vec::new();
+        let mut sources = vec![(file.to_string(), source.to_string())];
+        let mut visited = HashSet::new();
+        collect_element(&self.template.elements[index], element_start, 0, &mut Collector {
+            handlebars,
+            template_dir,
+            sources: &mut sources,
+            visited: &mut visited,
+            pieces: &mut pieces,
+        });
+
+        let output = &self.mjml[segment.clone()];
+        let relative = offset - segment.start;
+        let best = pieces
+            .iter()
+            .filter_map(|piece| {
+                output
+                    .match_indices(piece.text.as_str())
+                    .find(|(start, text)| *start <= relative && relative < start + text.len().max(1))
+                    .map(|(start, _)| (piece, relative - start))
+            })
+            .max_by_key(|(piece, _)| piece.text.len());
+        match best {
+            Some((piece, within)) => {
+                let (file, source) = &sources[piece.source];
+                Some(location(file, source, piece.offset + within))
+            }
+            None => Some(location(file, source, element_start)),
+        }
+    }
+}
+
+/// An `Output` that hands back what was written since the last `take`.
+#[derive(Default)]
+struct SegmentOutput {
+    buffer: String,
+}
+
+impl SegmentOutput {
+    fn take(&mut self) -> String {
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+impl Output for SegmentOutput {
+    fn write(&mut self, seg: &str) -> std::io::Result<()> {
+        self.buffer.push_str(seg);
+        Ok(())
+    }
+}
+
+/// One line of raw template text, and where it starts in its source.
+struct Piece {
+    text: String,
+    /// Index into the collected sources.
+    source: usize,
+    offset: usize,
+}
+
+struct Collector<'a> {
+    handlebars: &'a Handlebars<'static>,
+    template_dir: &'a Path,
+    /// File name and content of every source seen, the template first.
+    sources: &'a mut Vec<(String, String)>,
+    /// Partials already collected, so recursive partials end.
+    visited: &'a mut HashSet<String>,
+    pieces: &'a mut Vec<Piece>,
+}
+
+impl Collector<'_> {
+    fn template(&mut self, template: &Template, source: usize) {
+        for (index, element) in template.elements.iter().enumerate() {
+            let start = template
+                .mapping
+                .get(index)
+                .and_then(|&TemplateMapping(line, column)| offset_of(&self.sources[source].1, line, column))
+                .unwrap_or(0);
+            collect_element(element, start, source, self);
+        }
+    }
+
+    fn partial(&mut self, name: &str) {
+        if name.starts_with('@') || !self.visited.insert(name.to_string()) {
+            return;
+        }
+        let Some(template) = self.handlebars.get_template(name) else { return };
+        let found = partial_files(name).find_map(|id| {
+            let path = id.resolve_in(self.template_dir).ok()?;
+            Some((id.to_string(), fs::read_to_string(path).ok()?))
+        });
+        let Some(file) = found else { return };
+        self.sources.push(file);
+        let source = self.sources.len() - 1;
+        self.template(template, source);
+    }
+}
+
+fn collect_element(element: &TemplateElement, start: usize, source: usize, collector: &mut Collector<'_>) {
+    match element {
+        TemplateElement::RawString(text) => {
+            // Standalone lines may have lost leading whitespace.
+            let content = &collector.sources[source].1;
+            let start = content.get(start..).and_then(|rest| rest.find(text.as_str())).map_or(start, |found| start + found);
+            let mut line_start = start;
+            for line in text.split('\n') {
+                if !line.trim().is_empty() {
+                    collector.pieces.push(Piece { text: line.to_string(), source, offset: line_start });
+                }
+                line_start += line.len() + 1;
+            }
+        }
+        TemplateElement::HelperBlock(helper) => {
+            for inner in helper.template.iter().chain(&helper.inverse) {
+                collector.template(inner, source);
+            }
+        }
+        TemplateElement::PartialExpression(partial) | TemplateElement::PartialBlock(partial) => {
+            if let Some(inner) = &partial.template {
+                collector.template(inner, source);
+            }
+            if let Some(name) = partial.name.as_name() {
+                collector.partial(name);
+            }
+        }
+        TemplateElement::DecoratorBlock(decorator) => {
+            if let Some(inner) = &decorator.template {
+                collector.template(inner, source);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Byte offset of a 1-based line and character column.
+fn offset_of(text: &str, line: usize, column: usize) -> Option<usize> {
+    let line_start = if line <= 1 {
+        0
+    } else {
+        text.match_indices('\n').nth(line - 2).map(|(index, _)| index + 1)?
+    };
+    let rest = &text[line_start..];
+    let within = rest
+        .char_indices()
+        .nth(column.saturating_sub(1))
+        .map_or(rest.len(), |(index, _)| index);
+    Some(line_start + within)
+}
+
+/// Location of byte `offset` in `source`, with the two lines before and
+/// after it.
+fn location(file: &str, source: &str, offset: usize) -> SourceLocation {
+    let offset = (0..=offset.min(source.len())).rev().find(|index| source.is_char_boundary(*index)).unwrap_or(0);
+    let line = source[..offset].matches('\n').count() + 1;
+    let line_start = source[..offset].rfind('\n').map_or(0, |index| index + 1);
+    let column = source[line_start..offset].chars().count() + 1;
+
+    let lines: Vec<&str> = source.lines().collect();
+    let first = line.saturating_sub(2).max(1);
+    let last = (line + 2).min(lines.len().max(line));
+    let width = last.to_string().len();
+    let mut snippet = String::new();
+    for number in first..=last {
+        let text = lines.get(number - 1).copied().unwrap_or_default();
+        let marker = if number == line { '>' } else { ' ' };
+        snippet.push_str(&format!("{} {:>width$} | {}\n", marker, number, text, width = width));
+        if number == line {
+            snippet.push_str(&format!("  {:>width$} | {}^\n", "", " ".repeat(column - 1), width = width));
+        }
+    }
+    SourceLocation { file: file.to_string(), line, column, snippet }
+}
@@ -0,0 +1,115 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::template_id::TemplateId;
+
+/// Smallest number of entries a shard is sized for.
+const MIN_SHARD_CAPACITY: usize = 8;
+
+type Shard<V> = RwLock<HashMap<TemplateId, Entry<V>>>;
+
+/// Concurrent cache split into independently locked shards.
+///
+/// A hit only takes its shard's read lock and bumps an atomic timestamp, so
+/// concurrent readers never wait on each other. When a shard is full the
+/// entry with the oldest access time in that shard is evicted, which gives an
+/// approximate LRU over the whole cache.
+pub struct TemplateCache<V> {
+    shards: Box<[Shard<V>]>,
+    shard_capacity: usize,
+    hasher: RandomState,
+    epoch: Instant,
+}
+
+struct Entry<V> {
+    value: V,
+    /// Nanoseconds since `TemplateCache::epoch`.
+    last_accessed: AtomicU64,
+}
+
+impl<V: Clone> TemplateCache<V> {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        let cores = thread::available_parallelism().map(NonZeroUsize::get).unwrap_or(4);
+        // Enough shards to keep contention low, but never so many that a
+        // shard holds too few entries for its eviction order to mean anything.
+        let shard_count = (cores * 4)
+            .next_power_of_two()
+            .min(capacity.get() / MIN_SHARD_CAPACITY)
+            .max(1);
+        let shards = (0..shard_count).map(|_| RwLock::new(HashMap::new())).collect();
+        TemplateCache {
+            shards,
+            shard_capacity: capacity.get().div_ceil(shard_count),
+            hasher: RandomState::new(),
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn get(&self, key: &TemplateId) -> Option<V> {
+        let shard = self.shard(key).read().unwrap();
+        shard.get(key).map(|entry| {
+            entry.last_accessed.store(self.now(), Ordering::Relaxed);
+            entry.value.clone()
+        })
+    }
+
+    /// Whether `key` is cached, without counting as an access.
+    pub fn contains_key(&self, key: &TemplateId) -> bool {
+        self.shard(key).read().unwrap().contains_key(key)
+    }
+
+    pub fn insert(&self, key: TemplateId, value: V) {
+        let mut shard = self.shard(&key).write().unwrap();
+        if !shard.contains_key(&key) && shard.len() >= self.shard_capacity {
+            let oldest = shard
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_accessed.load(Ordering::Relaxed))
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                shard.remove(&oldest);
+            }
+        }
+        shard.insert(key, Entry { value, last_accessed: AtomicU64::new(self.now()) });
+    }
+
+    pub fn remove(&self, key: &TemplateId) -> Option<V> {
+        self.shard(key).write().unwrap().remove(key).map(|entry| entry.value)
+    }
+
+    /// Drops every entry that hasn't been read for `max_age`.
+    pub fn remove_idle(&self, max_age: Duration) {
+        let cutoff = match self.now().checked_sub(max_age.as_nanos() as u64) {
+            Some(cutoff) => cutoff,
+            // Nothing can be that old yet.
+            None => return,
+        };
+        for shard in self.shards.iter() {
+            shard
+                .write()
+                .unwrap()
+                .retain(|_, entry| entry.last_accessed.load(Ordering::Relaxed) > cutoff);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+
+    fn shard(&self, key: &TemplateId) -> &Shard<V> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_nanos() as u64
+    }
+}
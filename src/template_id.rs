@@ -0,0 +1,106 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::utils::get_relative_path;
+
+/// Canonical name of a template: its path relative to the template directory,
+/// with `/` separators and no empty or `.` segments.
+///
+/// A name can never point outside the template directory: `..` segments,
+/// absolute paths and NUL bytes are rejected up front, and [`resolve_in`]
+/// refuses symlinks that lead out of the directory.
+///
+/// [`resolve_in`]: TemplateId::resolve_in
+///
+/// Requests, the cache and the watcher all key templates by this, so
+/// `./billing//invoice.mjml` from a client and `/srv/templates/billing/invoice.mjml`
+/// from notify refer to the same entry.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TemplateId(String);
+
+impl TemplateId {
+    /// Normalizes a template name as sent by a client.
+    pub fn new(name: &str) -> Result<Self, String> {
+        if name.contains('\0') {
+            return Err(format!("Invalid template name {:?}: contains a NUL byte", name));
+        }
+        if is_absolute(name) {
+            return Err(format!("Invalid template name {:?}: absolute paths are not allowed", name));
+        }
+        let segments: Vec<&str> = name
+            .split(['/', '\\'])
+            .filter(|segment| !segment.is_empty() && *segment != ".")
+            .collect();
+        if segments.contains(&"..") {
+            return Err(format!("Invalid template name {:?}: '..' is not allowed", name));
+        }
+        if segments.is_empty() {
+            return Err(format!("Invalid template name {:?}", name));
+        }
+        Ok(TemplateId(segments.join("/")))
+    }
+
+    /// Derives the id of a file inside `template_dir`, such as a path
+    /// reported by the file watcher.
+    pub fn from_path(template_dir: &Path, path: &Path) -> Result<Self, String> {
+        let relative = get_relative_path(template_dir, path)
+            .map_err(|e| format!("{} is not inside {}: {}", path.display(), template_dir.display(), e))?;
+        let name = relative
+            .to_str()
+            .ok_or_else(|| format!("Template path {:?} is not valid UTF-8", path))?;
+        Self::new(name)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Location of the template file under `template_dir`.
+    pub fn path_in(&self, template_dir: &Path) -> PathBuf {
+        template_dir.join(&self.0)
+    }
+
+    /// Location of the template file under `template_dir`, checked not to
+    /// leave it through a symlink. The file itself doesn't have to exist.
+    pub fn resolve_in(&self, template_dir: &Path) -> Result<PathBuf, String> {
+        let escapes = || format!("Invalid template name {:?}: resolves outside the template directory", self.0);
+        let root = fs::canonicalize(template_dir)
+            .map_err(|e| format!("Failed to resolve template directory {}: {}", template_dir.display(), e))?;
+        let path = self.path_in(template_dir);
+        // The deepest part of the path that exists is where a symlink could
+        // lead; whatever follows it can't, since ids contain no `..`.
+        for existing in path.ancestors() {
+            match fs::symlink_metadata(existing) {
+                Ok(_) => {
+                    let resolved = fs::canonicalize(existing).map_err(|e| match e.kind() {
+                        // A dangling symlink: writing through it would create its target.
+                        io::ErrorKind::NotFound => escapes(),
+                        _ => format!("Failed to resolve template {}: {}", self.0, e),
+                    })?;
+                    if !resolved.starts_with(&root) {
+                        return Err(escapes());
+                    }
+                    return Ok(path);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(format!("Failed to resolve template {}: {}", self.0, e)),
+            }
+        }
+        Err(escapes())
+    }
+}
+
+/// Whether `name` is rooted, on either Unix or Windows (`C:`, `\\server`).
+fn is_absolute(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    name.starts_with(['/', '\\'])
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+}
+
+impl fmt::Display for TemplateId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
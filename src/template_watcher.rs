@@ -1,25 +1,17 @@
 use std::{
     path::PathBuf,
-    future::Future,
     time::Duration,
     collections::HashMap,
     sync::{Arc, Mutex},
 };
 
-use notify::{Config, Event, RecursiveMode, RecommendedWatcher, Result as NotifyResult, Watcher, EventKind};
-use tracing::{info, error, debug, Level};
+use notify::{Event, EventKind};
+use tracing::{info, error, debug};
 use crate::app_state::AppState;
-use crate::utils::get_relative_path;
+use crate::history::is_history;
+use crate::partials::partial_name;
+use crate::template_id::TemplateId;
 use tokio::sync::mpsc::Receiver;
-use tokio::task;
-
-// Function to clean the template cache
-async fn clean_template_cache(app_state: &AppState) {
-    use std::time::Duration;
-    let expiration_duration = Duration::from_secs(3600); // 1 hour expiration
-                                                           // Clean old templates (e.g., older than 1 hour)
-    app_state.clean_old_templates(expiration_duration).await;
-}
 
 // Helper function to process events
 pub async fn watch_templates(
@@ -30,24 +22,23 @@ pub async fn watch_templates(
 
     info!("Watching directory in separate task: {:?}", template_dir);
 
-    let mut pending_events: Arc<Mutex<HashMap<PathBuf, EventKind>>> = Arc::new(Mutex::new(HashMap::new()));
+    let pending_events: Arc<Mutex<HashMap<PathBuf, EventKind>>> = Arc::new(Mutex::new(HashMap::new()));
     let debounce_duration = Duration::from_millis(200); // Adjust as needed
 
     while let Some(event) = rx.recv().await {
         debug!("Received event in watch_templates: {:?}", event);
+        // Any file may be pulled in through <mj-include>, not just templates.
         for path in event.paths {
-            if path.extension().map_or(false, |ext| ext == "mjml") {
-                let mut events = pending_events.lock().unwrap();
-                match event.kind {
-                    EventKind::Create(_) | EventKind::Modify(_) => {
-                        events.insert(path.clone(), EventKind::Modify(notify::event::ModifyKind::Data(notify::event::DataChange::Any))); // Store modify event
-                    }
-                    EventKind::Remove(_) => {
-                        events.insert(path.clone(), EventKind::Remove(notify::event::RemoveKind::File)); // Store remove event
-                    }
-                    _ => continue, // Skip Access, Other, Any events
-                };
-            }
+            let mut events = pending_events.lock().unwrap();
+            match event.kind {
+                EventKind::Create(_) | EventKind::Modify(_) => {
+                    events.insert(path.clone(), EventKind::Modify(notify::event::ModifyKind::Data(notify::event::DataChange::Any))); // Store modify event
+                }
+                EventKind::Remove(_) => {
+                    events.insert(path.clone(), EventKind::Remove(notify::event::RemoveKind::File)); // Store remove event
+                }
+                _ => continue, // Skip Access, Other, Any events
+            };
         }
 
         // Debounce task
@@ -63,48 +54,37 @@ pub async fn watch_templates(
 
             for (path, event_kind) in aggregated_events {
                 let app_state_clone2 = app_state_clone.clone();
-                let template_dir_clone2 = template_dir_clone.clone();
-                let path_clone = path.clone();
-
-                // Calculate the relative path here. Handle errors gracefully.
-                let relative_path_result = get_relative_path(&template_dir_clone2, &path_clone);
 
-
-                match event_kind {
-                    EventKind::Create(_) | EventKind::Modify(_) => {
-                        info!("Disk updating cache - Reloading template");
-
-                        if let Ok(relative_path) = relative_path_result {
-                            let path_str = path_clone.display().to_string();
-                            let app_state_clone3 = app_state_clone2.clone();
-                            tokio::spawn(async move {
-                                if let Err(e) = app_state_clone3.reload_template(&path_str).await {
-                                    error!("Failed to reload template {}: {}", path_str, e);
-                                }
-                            });
-                        } else {
-                            error!("Failed to get relative path for reload: {:?}", path_clone);
-                        }
-                    }
-                    EventKind::Remove(_) => {
-                        info!("Disk updating cache, removing file");
-
-                        if let Ok(relative_path) = relative_path_result {
-                            let app_state_clone3 = app_state_clone2.clone();
-                            let relative_path_string = relative_path.to_string();
-
-                            tokio::spawn(async move {
-                                if let Err(e) = app_state_clone3.remove_template_from_cache(&relative_path_string).await {
-                                    error!("Failed to remove template from cache {}: {}", relative_path_string, e);
-                                }
-                            });
-                        } else {
-                            error!("Failed to get relative path for removal: {:?}", path_clone);
-                        }
+                // Map the event path onto the id requests use. Handle errors gracefully.
+                let id = match TemplateId::from_path(&template_dir_clone, &path) {
+                    Ok(id) => id,
+                    Err(e) => {
+                        error!("Failed to get template id for {:?}: {}", path, e);
+                        continue;
                     }
-                    _ => continue, // Skip Access, Other, Any events.  This should never happen but kept for safety.
                 };
 
+                // Revisions are recorded by the API, nothing is built from them.
+                if is_history(&id) {
+                    continue;
+                }
+
+                let removed = matches!(event_kind, EventKind::Remove(_));
+                if let Err(e) = app_state_clone2.file_changed(&id, removed) {
+                    error!("Failed to reload {}: {}", id, e);
+                }
+
+                // Partials and catalogs are reloaded already; templates are
+                // compiled again ahead of their next use.
+                if removed || partial_name(&id).is_some() || path.extension().is_none_or(|ext| ext != "mjml") {
+                    continue;
+                }
+                info!("Disk updating cache - Reloading template");
+                tokio::spawn(async move {
+                    if let Err(e) = app_state_clone2.reload_template(&id).await {
+                        error!("Failed to reload template {}: {}", id, e);
+                    }
+                });
             }
         });
     }
@@ -1,13 +1,42 @@
-use super::*;
 use std::path::PathBuf;
-use axum::{Json, extract::State, response::{Response, IntoResponse}};
-use serde_json::json;
-use hyper::{StatusCode};
-use handlebars::Handlebars;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use axum::{Json, body::Body, extract::{FromRequest, Multipart, Path as AxumPath, Query, State}, http::Request, response::{Response, IntoResponse}};
+use serde_json::{json, Value};
+use hyper::{header, HeaderMap, StatusCode, Uri};
 
-use crate::{convert_mjml, list_templates};
-use crate::models::MjmlInput;
-use crate::app_state::AppState;
+use crate::{
+    convert_batch, convert_mjml, delete_template_file, dependency_graph, get_template_file, list_templates, post_template_file,
+    put_template_file, upload_template,
+};
+use crate::atomic_write::StagedFile;
+use crate::dependency_graph::DependencyGraph;
+use crate::schema::{Schema, Violation};
+use crate::config::ServerConfig;
+use crate::eml::encode_quoted_printable;
+use crate::escaping::{escape_mjml, sanitize_html};
+use crate::listing::ListQuery;
+use crate::locales::{localized_names, LocaleTag};
+use crate::missing_variables::MissingVariables;
+use crate::models::{EmailHeaders, MjmlInput, OutputFormat, RenderOptionsInput};
+use crate::text_renderer::html_to_text;
+use crate::app_state::{initialize_state, AppState};
+use crate::template_cache::TemplateCache;
+use crate::template_id::TemplateId;
+
+/// Creates an empty template directory unique to one test.
+fn temp_template_dir(test_name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mjml-tests-{}-{}", std::process::id(), test_name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+async fn body_string(response: Response) -> String {
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
 
 #[tokio::test]
 async fn test_convert_mjml_with_template() -> Result<(), Box<dyn std::error::Error>> {
@@ -17,9 +46,10 @@ async fn test_convert_mjml_with_template() -> Result<(), Box<dyn std::error::Err
         mjml: None,
         payload: json!({"name": "World"}),
         template: Some("test.mjml".to_string()),
+        ..Default::default()
     };
 
-    let response = convert_mjml(State(AppState::new(100, template_dir)), Json(mjml_input)).await;
+    let response = convert_mjml(State(AppState::new(100, template_dir)), HeaderMap::new(), Json(mjml_input)).await;
     assert_eq!(response.unwrap().status(), StatusCode::OK);
     Ok(())
 }
@@ -32,9 +62,10 @@ async fn test_convert_mjml_with_mjml() -> Result<(), Box<dyn std::error::Error>>
         mjml: Some(r#"<mjml><mj-body><mj-text>Hello, World!</mj-text></mj-body></mjml>"#.to_string()),
         payload: json!({}),
         template: None,
+        ..Default::default()
     };
 
-    let response = convert_mjml(State(AppState::new(100, template_dir)), Json(mjml_input)).await;
+    let response = convert_mjml(State(AppState::new(100, template_dir)), HeaderMap::new(), Json(mjml_input)).await;
     assert_eq!(response.unwrap().status(), StatusCode::OK);
     Ok(())
 }
@@ -42,8 +73,2115 @@ async fn test_convert_mjml_with_mjml() -> Result<(), Box<dyn std::error::Error>>
 #[tokio::test]
 async fn test_list_templates() -> Result<(), Box<dyn std::error::Error>> {
     let template_dir = PathBuf::from("templates"); // Create a 'templates' directory in your project
-    let response_result = list_templates(State(AppState::new(100, template_dir))).await;
+    let response_result = list_templates(State(AppState::new(100, template_dir)), Query(ListQuery::default())).await;
     let response: Response = response_result.unwrap().into_response();
     assert_eq!(response.status(), StatusCode::OK);
     Ok(())
 }
+
+#[tokio::test]
+async fn test_convert_mjml_with_render_options() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = PathBuf::from("templates");
+    let mjml = r#"<mjml><mj-body><mj-social><mj-social-element name="facebook" href="https://example.com">Share</mj-social-element></mj-social><!-- internal note --></mj-body></mjml>"#;
+
+    let mjml_input = MjmlInput {
+        mjml: Some(mjml.to_string()),
+        payload: json!({}),
+        template: None,
+        render_options: Some(json!({
+            "disable_comments": true,
+            "social_icon_origin": "https://cdn.example.com/icons/",
+        })),
+        ..Default::default()
+    };
+
+    let response = convert_mjml(State(AppState::new(100, template_dir)), HeaderMap::new(), Json(mjml_input)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let html = body_string(response).await;
+    assert!(html.contains("https://cdn.example.com/icons/facebook.png"));
+    assert!(!html.contains("internal note"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_convert_mjml_render_options_from_config() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = PathBuf::from("templates");
+    let config = ServerConfig {
+        render_options: RenderOptionsInput {
+            disable_comments: Some(true),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let mjml_input = MjmlInput {
+        mjml: Some(r#"<mjml><mj-body><!-- internal note --><mj-text>Hello</mj-text></mj-body></mjml>"#.to_string()),
+        payload: json!({}),
+        template: None,
+        ..Default::default()
+    };
+
+    let response = convert_mjml(State(AppState::with_config(100, template_dir, config)), HeaderMap::new(), Json(mjml_input)).await.unwrap();
+    let html = body_string(response).await;
+    assert!(!html.contains("internal note"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_convert_mjml_rejects_unknown_render_option() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = PathBuf::from("templates");
+    let mjml_input = MjmlInput {
+        mjml: Some(r#"<mjml><mj-body><mj-text>Hello</mj-text></mj-body></mjml>"#.to_string()),
+        payload: json!({}),
+        template: None,
+        render_options: Some(json!({"disable_comment": true})),
+        ..Default::default()
+    };
+
+    let (status, message) = convert_mjml(State(AppState::new(100, template_dir)), HeaderMap::new(), Json(mjml_input)).await.unwrap_err();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(message.contains("unknown field `disable_comment`"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_convert_mjml_text_output() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = PathBuf::from("templates");
+    let mjml = r#"<mjml><mj-head><mj-preview>Hidden preheader</mj-preview></mj-head><mj-body><mj-section><mj-column><mj-text><h1>Welcome</h1><p>Read the <a href="https://example.com/docs">docs</a>.</p></mj-text><mj-button href="https://example.com/start">Get started</mj-button></mj-column></mj-section></mj-body></mjml>"#;
+
+    let mjml_input = MjmlInput {
+        mjml: Some(mjml.to_string()),
+        payload: json!({}),
+        output: Some(OutputFormat::Text),
+        ..Default::default()
+    };
+
+    let response = convert_mjml(State(AppState::new(100, template_dir)), HeaderMap::new(), Json(mjml_input)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let text = body_string(response).await;
+    assert_eq!(
+        text,
+        "Welcome\n=======\n\nRead the docs [1].\n\nGet started [2]\n\n[1] https://example.com/docs\n[2] https://example.com/start"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_convert_mjml_json_output() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = PathBuf::from("templates");
+    let mjml = r#"<mjml><mj-head><mj-title>Order shipped</mj-title><mj-preview>On its way</mj-preview></mj-head><mj-body><mj-text>Hello {{name}}</mj-text></mj-body></mjml>"#;
+
+    let mjml_input = MjmlInput {
+        mjml: Some(mjml.to_string()),
+        payload: json!({"name": "World"}),
+        ..Default::default()
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT, "application/json".parse()?);
+
+    let response = convert_mjml(State(AppState::new(100, template_dir)), headers, Json(mjml_input)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_str(&body_string(response).await)?;
+    assert_eq!(body["title"], "Order shipped");
+    assert_eq!(body["preview"], "On its way");
+    assert_eq!(body["text"], "Hello World");
+    assert_eq!(body["size_bytes"], body["html"].as_str().unwrap().len());
+    assert_eq!(body["warnings"], json!([]));
+    assert!(body["template"].is_null());
+    assert!(body["render_ms"].is_number());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_convert_mjml_defaults_to_html() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = PathBuf::from("templates");
+    let mjml_input = MjmlInput {
+        mjml: Some(r#"<mjml><mj-body><mj-text>Hello</mj-text></mj-body></mjml>"#.to_string()),
+        payload: json!({}),
+        ..Default::default()
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT, "*/*".parse()?);
+
+    let response = convert_mjml(State(AppState::new(100, template_dir)), headers, Json(mjml_input)).await.unwrap();
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html; charset=utf-8");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_convert_mjml_accept_weights() -> Result<(), Box<dyn std::error::Error>> {
+    let app_state = AppState::new(100, PathBuf::from("templates"));
+    let cases = [
+        ("text/html;q=0.1, text/plain", "text/plain; charset=utf-8"),
+        ("text/html;q=0.5, application/json;q=0.8", "application/json"),
+        ("application/json;q=0, */*", "text/html; charset=utf-8"),
+        ("text/plain, application/json", "text/plain; charset=utf-8"),
+        ("application/json; q=0.9, message/rfc822; q=0.9", "application/json"),
+    ];
+    for (accept, content_type) in cases {
+        let mjml_input = MjmlInput {
+            mjml: Some(r#"<mjml><mj-body><mj-text>Hello</mj-text></mj-body></mjml>"#.to_string()),
+            payload: json!({}),
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, accept.parse()?);
+        let response = convert_mjml(State(app_state.clone()), headers, Json(mjml_input)).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], content_type, "Accept: {}", accept);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_convert_mjml_eml_output() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = PathBuf::from("templates");
+    let mjml = r#"<mjml><mj-head><mj-title>Votre commande a été expédiée</mj-title></mj-head><mj-body><mj-text>Bonjour {{name}}</mj-text></mj-body></mjml>"#;
+
+    let mjml_input = MjmlInput {
+        mjml: Some(mjml.to_string()),
+        payload: json!({"name": "Zoé"}),
+        output: Some(OutputFormat::Eml),
+        email: Some(EmailHeaders {
+            from: Some("Shop <shop@example.com>".to_string()),
+            to: vec!["zoe@example.com".to_string(), "Zoé <zoe2@example.com>".to_string()],
+            reply_to: Some("support@example.com".to_string()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let response = convert_mjml(State(AppState::new(100, template_dir)), HeaderMap::new(), Json(mjml_input)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "message/rfc822");
+    let message = body_string(response).await;
+
+    assert!(message.contains("From: Shop <shop@example.com>\r\n"));
+    assert!(message.contains("To: zoe@example.com, =?UTF-8?Q?Zo=C3=A9?= <zoe2@example.com>\r\n"));
+    assert!(message.contains("Reply-To: support@example.com\r\n"));
+    assert!(message.contains("Subject: =?UTF-8?Q?Votre_commande_a_=C3=A9t=C3=A9_exp=C3=A9di=C3=A9e?=\r\n"));
+    assert!(message.contains("Content-Type: multipart/alternative; boundary="));
+    assert!(message.contains("Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\nBonjour Zo=C3=A9\r\n"));
+    assert!(message.contains("Content-Type: text/html; charset=utf-8"));
+    assert!(message.split("\r\n").all(|line| line.len() <= 78));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_convert_mjml_eml_message_id_and_display_names() -> Result<(), Box<dyn std::error::Error>> {
+    let app_state = AppState::new(100, PathBuf::from("templates"));
+    let eml = |from: &str, to: &str| {
+        let mjml_input = MjmlInput {
+            mjml: Some("<mjml><mj-body><mj-text>Hello</mj-text></mj-body></mjml>".to_string()),
+            output: Some(OutputFormat::Eml),
+            email: Some(EmailHeaders { from: Some(from.to_string()), to: vec![to.to_string()], ..Default::default() }),
+            ..Default::default()
+        };
+        let app_state = app_state.clone();
+        async move { body_string(convert_mjml(State(app_state), HeaderMap::new(), Json(mjml_input)).await.unwrap()).await }
+    };
+    let header = |message: &str, name: &str| {
+        let prefix = format!("{}: ", name);
+        message.split("\r\n").find_map(|line| line.strip_prefix(prefix.as_str()).map(str::to_string)).unwrap()
+    };
+
+    let message = eml("Doe, John <john@example.com>", "Ann \"Nan\" O'Neil <ann@example.com>").await;
+    assert_eq!(header(&message, "From"), "\"Doe, John\" <john@example.com>");
+    assert_eq!(header(&message, "To"), "\"Ann \\\"Nan\\\" O'Neil\" <ann@example.com>");
+    let message_id = header(&message, "Message-ID");
+    assert!(message_id.starts_with('<') && message_id.ends_with("@example.com>"), "{}", message_id);
+    assert_ne!(header(&eml("shop@example.com", "ann@example.com").await, "Message-ID"), message_id);
+
+    // Names that are already quoted, or encoded, are kept whole.
+    let message = eml("\"Shop, Inc.\" <shop@example.com>", "Doe, Zoé <zoe@example.com>").await;
+    assert_eq!(header(&message, "From"), "\"Shop, Inc.\" <shop@example.com>");
+    assert_eq!(header(&message, "To"), "=?UTF-8?Q?Doe=2C_Zo=C3=A9?= <zoe@example.com>");
+    assert!(header(&message, "Message-ID").ends_with("@example.com>"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_convert_mjml_eml_requires_from() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = PathBuf::from("templates");
+    let mjml_input = MjmlInput {
+        mjml: Some(r#"<mjml><mj-body><mj-text>Hello</mj-text></mj-body></mjml>"#.to_string()),
+        payload: json!({}),
+        output: Some(OutputFormat::Eml),
+        ..Default::default()
+    };
+
+    let (status, _) = convert_mjml(State(AppState::new(100, template_dir)), HeaderMap::new(), Json(mjml_input)).await.unwrap_err();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_template_precompiles() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("precompiles");
+    std::fs::write(template_dir.join("static.mjml"), "<mjml><mj-body><mj-text>Static {{! note }}</mj-text></mj-body></mjml>")?;
+    std::fs::write(template_dir.join("dynamic.mjml"), "<mjml><mj-body><mj-text>Hi {{name}}</mj-text></mj-body></mjml>")?;
+    let app_state = AppState::new(100, template_dir.clone());
+
+    let static_path = TemplateId::new("static.mjml")?;
+    let static_template = app_state.fetch_template(&static_path).await.map(|(t, _)| t)?;
+    let static_render = static_template.static_render.as_ref().expect("static template is prerendered");
+    assert!(static_render.html.contains("Static"));
+    assert!(Arc::ptr_eq(&static_template, &app_state.fetch_template(&static_path).await.map(|(t, _)| t)?));
+
+    let dynamic_path = TemplateId::new("dynamic.mjml")?;
+    let dynamic_template = app_state.fetch_template(&dynamic_path).await.map(|(t, _)| t)?;
+    assert!(dynamic_template.static_render.is_none());
+
+    let mjml_input = MjmlInput {
+        payload: json!({}),
+        template: Some("static.mjml".to_string()),
+        ..Default::default()
+    };
+    let response = convert_mjml(State(app_state), HeaderMap::new(), Json(mjml_input)).await.unwrap();
+    assert_eq!(body_string(response).await, static_render.html);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reload_template_rebuilds_compiled_template() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("reload");
+    let path = template_dir.join("greeting.mjml");
+    std::fs::write(&path, "<mjml><mj-body><mj-text>Hello {{name}}</mj-text></mj-body></mjml>")?;
+    let app_state = AppState::new(100, template_dir.clone());
+    let key = TemplateId::new("greeting.mjml")?;
+
+    let render = |app_state: AppState| async move {
+        let mjml_input = MjmlInput {
+            payload: json!({"name": "Ada"}),
+            template: Some("greeting.mjml".to_string()),
+            output: Some(OutputFormat::Text),
+            ..Default::default()
+        };
+        body_string(convert_mjml(State(app_state), HeaderMap::new(), Json(mjml_input)).await.unwrap()).await
+    };
+    assert_eq!(render(app_state.clone()).await, "Hello Ada");
+
+    std::fs::write(&path, "<mjml><mj-body><mj-text>Goodbye {{name}}</mj-text></mj-body></mjml>")?;
+    app_state.reload_template(&key).await?;
+    assert_eq!(render(app_state.clone()).await, "Goodbye Ada");
+
+    std::fs::write(&path, "<mjml><mj-body><mj-text>Broken {{#if}}</mj-text></mj-body></mjml>")?;
+    assert!(app_state.reload_template(&key).await.is_err());
+    assert!(app_state.fetch_template(&key).await.is_err());
+    Ok(())
+}
+
+#[test]
+fn test_template_cache_evicts_least_recently_used() {
+    let cache = TemplateCache::new(NonZeroUsize::new(3).unwrap());
+    let id = |name: &str| TemplateId::new(name).unwrap();
+    cache.insert(id("a"), 1);
+    cache.insert(id("b"), 2);
+    cache.insert(id("c"), 3);
+    std::thread::sleep(Duration::from_millis(1));
+    assert_eq!(cache.get(&id("a")), Some(1));
+
+    cache.insert(id("d"), 4);
+    assert_eq!(cache.len(), 3);
+    assert_eq!(cache.get(&id("a")), Some(1));
+    assert_eq!(cache.get(&id("b")), None);
+
+    cache.remove_idle(Duration::ZERO);
+    assert_eq!(cache.len(), 0);
+}
+
+/// Shows that cache hits scale with threads instead of queueing on a lock.
+/// Timing dependent, run with `cargo test -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn test_template_cache_hit_throughput_scales() {
+    const HITS_PER_THREAD: usize = 2_000_000;
+    let cache = Arc::new(TemplateCache::new(NonZeroUsize::new(1000).unwrap()));
+    let ids: Arc<Vec<TemplateId>> = Arc::new((0..100).map(|index| TemplateId::new(&format!("{}.mjml", index)).unwrap()).collect());
+    for (index, id) in ids.iter().enumerate() {
+        cache.insert(id.clone(), Arc::new(index));
+    }
+
+    let hits_per_second = |threads: usize| {
+        let started = Instant::now();
+        let handles: Vec<_> = (0..threads)
+            .map(|thread| {
+                let cache = cache.clone();
+                let ids = ids.clone();
+                std::thread::spawn(move || {
+                    for hit in 0..HITS_PER_THREAD {
+                        assert!(cache.get(&ids[(thread + hit) % ids.len()]).is_some());
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|handle| handle.join().unwrap());
+        (threads * HITS_PER_THREAD) as f64 / started.elapsed().as_secs_f64()
+    };
+
+    let cores = std::thread::available_parallelism().map(NonZeroUsize::get).unwrap_or(1);
+    let single = hits_per_second(1);
+    let parallel = hits_per_second(cores);
+    println!("1 thread: {:.0} hits/s, {} threads: {:.0} hits/s ({:.1}x)", single, cores, parallel, parallel / single);
+    if cores >= 4 {
+        assert!(parallel > single * 2.0);
+    }
+}
+
+#[test]
+fn test_template_id_normalization() -> Result<(), Box<dyn std::error::Error>> {
+    let id = TemplateId::new("./billing//invoice.mjml")?;
+    assert_eq!(id.as_str(), "billing/invoice.mjml");
+    assert_eq!(TemplateId::new("billing\\invoice.mjml")?, id);
+
+    let template_dir = PathBuf::from("templates");
+    assert_eq!(TemplateId::from_path(&template_dir, &template_dir.join("billing/invoice.mjml"))?, id);
+    let absolute = std::env::current_dir()?.join("templates/billing/invoice.mjml");
+    assert_eq!(TemplateId::from_path(&template_dir, &absolute)?, id);
+
+    assert!(TemplateId::new("/./").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_template_id_rejects_escaping_names() {
+    for name in ["../secret.mjml", "billing/../../secret.mjml", "..\\secret.mjml", "/etc/passwd", "\\\\server\\share.mjml", "C:\\secret.mjml", "c:secret.mjml", "welcome\0.mjml"] {
+        assert!(TemplateId::new(name).is_err(), "{:?} should be rejected", name);
+    }
+    assert!(TemplateId::new("billing/..invoice.mjml").is_ok());
+}
+
+/// Builds the multipart body of an upload with one text/plain part per file.
+async fn multipart(files: &[(&str, &str)]) -> Multipart {
+    let boundary = "upload-boundary";
+    let mut body = String::new();
+    for (file_name, content) in files {
+        body.push_str(&format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: text/plain\r\n\r\n{}\r\n",
+            boundary, file_name, content
+        ));
+    }
+    body.push_str(&format!("--{}--\r\n", boundary));
+    let request = Request::builder()
+        .method("POST")
+        .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+        .body(Body::from(body))
+        .unwrap();
+    Multipart::from_request(request, &()).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_traversal_names_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let root = temp_template_dir("traversal");
+    let template_dir = root.join("templates");
+    std::fs::create_dir_all(&template_dir)?;
+    std::fs::write(root.join("secret.mjml"), "<mjml><mj-body><mj-text>secret</mj-text></mj-body></mjml>")?;
+    let app_state = AppState::with_config(100, template_dir.clone(), admin_config());
+
+    for name in ["../secret.mjml", "/etc/passwd", "a/../../secret.mjml"] {
+        let mjml_input = MjmlInput { template: Some(name.to_string()), ..Default::default() };
+        let (status, _) = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", name);
+    }
+
+    let upload = "<mjml><mj-body><mj-text>overwritten</mj-text></mj-body></mjml>";
+    for name in ["../secret.mjml", "../evil.mjml", "/tmp/evil.mjml"] {
+        let (status, _) = upload_template(State(app_state.clone()), upload_headers(), multipart(&[(name, upload)]).await)
+            .await
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", name);
+    }
+    assert!(std::fs::read_to_string(root.join("secret.mjml"))?.contains("secret"));
+    assert!(!root.join("evil.mjml").exists());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_symlinks_escaping_the_template_dir_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::symlink;
+
+    let root = temp_template_dir("symlinks");
+    let template_dir = root.join("templates");
+    let outside = root.join("outside");
+    std::fs::create_dir_all(&template_dir)?;
+    std::fs::create_dir_all(&outside)?;
+    let secret = "<mjml><mj-body><mj-text>secret</mj-text></mj-body></mjml>";
+    std::fs::write(outside.join("secret.mjml"), secret)?;
+    std::fs::write(template_dir.join("inside.mjml"), "<mjml><mj-body><mj-text>inside</mj-text></mj-body></mjml>")?;
+    symlink(outside.join("secret.mjml"), template_dir.join("linked.mjml"))?;
+    symlink(&outside, template_dir.join("linked-dir"))?;
+    symlink(outside.join("missing.mjml"), template_dir.join("dangling.mjml"))?;
+    // Links that stay inside the directory keep working.
+    symlink(template_dir.join("inside.mjml"), template_dir.join("alias.mjml"))?;
+    let app_state = AppState::with_config(100, template_dir.clone(), admin_config());
+
+    for name in ["linked.mjml", "linked-dir/secret.mjml"] {
+        let mjml_input = MjmlInput { template: Some(name.to_string()), ..Default::default() };
+        let (status, _) = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", name);
+    }
+    let mjml_input = MjmlInput { template: Some("alias.mjml".to_string()), output: Some(OutputFormat::Text), ..Default::default() };
+    let response = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await.unwrap();
+    assert_eq!(body_string(response).await, "inside");
+    // A cached template skips the disk check, so a link changed to lead out
+    // must leave the cache.
+    std::fs::remove_file(template_dir.join("alias.mjml"))?;
+    symlink(outside.join("secret.mjml"), template_dir.join("alias.mjml"))?;
+    assert!(app_state.is_cached(&TemplateId::new("alias.mjml")?));
+    app_state.file_changed(&TemplateId::new("alias.mjml")?, false)?;
+    let mjml_input = MjmlInput { template: Some("alias.mjml".to_string()), ..Default::default() };
+    let (status, _) = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await.unwrap_err();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let upload = "<mjml><mj-body><mj-text>overwritten</mj-text></mj-body></mjml>";
+    for name in ["linked.mjml", "linked-dir/secret.mjml", "linked-dir/new.mjml", "dangling.mjml"] {
+        let (status, _) = upload_template(State(app_state.clone()), upload_headers(), multipart(&[(name, upload)]).await)
+            .await
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", name);
+    }
+    assert_eq!(std::fs::read_to_string(outside.join("secret.mjml"))?, secret);
+    assert!(!outside.join("new.mjml").exists());
+    assert!(!outside.join("missing.mjml").exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_partials_and_layouts() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("partials");
+    std::fs::create_dir_all(template_dir.join("partials/brand"))?;
+    std::fs::create_dir_all(template_dir.join("layouts"))?;
+    std::fs::write(template_dir.join("partials/brand/footer.mjml"), "<mj-text>Sent by {{company}}</mj-text>")?;
+    std::fs::write(
+        template_dir.join("layouts/base.mjml"),
+        "<mjml><mj-body>{{> @partial-block}}{{> brand/footer}}</mj-body></mjml>",
+    )?;
+    std::fs::write(template_dir.join("welcome.mjml"), "{{#> base}}<mj-text>Hello {{name}}</mj-text>{{/base}}")?;
+    let app_state = AppState::new(100, template_dir.clone());
+    assert_eq!(app_state.register_partials(), 2);
+
+    let payload = json!({"name": "Ada", "company": "Acme"});
+    assert_eq!(convert_text(&app_state, "welcome.mjml", payload.clone()).await?, "Hello Ada\nSent by Acme");
+
+    // Editing the partial changes already cached templates.
+    std::fs::write(template_dir.join("partials/brand/footer.mjml"), "<mj-text>Acme Inc.</mj-text>")?;
+    let footer = TemplateId::new("partials/brand/footer.mjml")?;
+    app_state.reload_partial(&footer, "brand/footer")?;
+    assert_eq!(convert_text(&app_state, "welcome.mjml", payload.clone()).await?, "Hello Ada\nAcme Inc.");
+
+    // Outside strict mode Handlebars renders a missing partial as nothing.
+    app_state.remove_partial(&footer, "brand/footer");
+    assert_eq!(convert_text(&app_state, "welcome.mjml", payload).await?, "Hello Ada");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_partial_edit_on_disk_is_visible_to_next_convert() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("watch-partial");
+    std::fs::create_dir_all(template_dir.join("partials"))?;
+    std::fs::write(template_dir.join("partials/greeting.mjml"), "<mj-text>Hello {{name}}</mj-text>")?;
+    std::fs::write(template_dir.join("welcome.mjml"), "<mjml><mj-body>{{> greeting}}</mj-body></mjml>")?;
+    let app_state = initialize_state(template_dir.to_str().unwrap(), ServerConfig::default()).await.unwrap();
+    assert_eq!(convert_text(&app_state, "welcome.mjml", json!({"name": "Ada"})).await?, "Hello Ada");
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    std::fs::write(template_dir.join("partials/greeting.mjml"), "<mj-text>Hi {{name}}</mj-text>")?;
+    wait_for_convert(&app_state, "welcome.mjml", |result| result.as_deref() == Ok("Hi Ada")).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mj_include() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("mj-include");
+    std::fs::create_dir_all(template_dir.join("sections"))?;
+    std::fs::write(template_dir.join("sections/header.mjml"), "<mj-text>Header</mj-text>")?;
+    std::fs::write(
+        template_dir.join("static.mjml"),
+        "<mjml><mj-body><mj-include path=\"./sections/header.mjml\" /><mj-text>Static</mj-text></mj-body></mjml>",
+    )?;
+    std::fs::write(
+        template_dir.join("dynamic.mjml"),
+        "<mjml><mj-body><mj-include path=\"file:///sections/header.mjml\" /><mj-text>Hi {{name}}</mj-text></mj-body></mjml>",
+    )?;
+    let app_state = AppState::new(100, template_dir.clone());
+
+    assert_eq!(convert_text(&app_state, "static.mjml", json!({})).await?, "Header\nStatic");
+    assert_eq!(convert_text(&app_state, "dynamic.mjml", json!({"name": "Ada"})).await?, "Header\nHi Ada");
+    let mjml_input = MjmlInput {
+        mjml: Some("<mjml><mj-body><mj-include path=\"sections/header.mjml\" /></mj-body></mjml>".to_string()),
+        output: Some(OutputFormat::Text),
+        ..Default::default()
+    };
+    let response = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await.unwrap();
+    assert_eq!(body_string(response).await, "Header");
+
+    // Both the pre-rendered static template and the included file are refreshed.
+    std::fs::write(template_dir.join("sections/header.mjml"), "<mj-text>New header</mj-text>")?;
+    app_state.invalidate_dependents(&TemplateId::new("sections/header.mjml")?);
+    assert_eq!(convert_text(&app_state, "static.mjml", json!({})).await?, "New header\nStatic");
+    assert_eq!(convert_text(&app_state, "dynamic.mjml", json!({"name": "Ada"})).await?, "New header\nHi Ada");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mj_include_stays_in_template_dir() -> Result<(), Box<dyn std::error::Error>> {
+    let root = temp_template_dir("mj-include-sandbox");
+    let template_dir = root.join("templates");
+    std::fs::create_dir_all(&template_dir)?;
+    std::fs::write(root.join("secret.mjml"), "<mj-text>secret</mj-text>")?;
+    let app_state = AppState::new(100, template_dir.clone());
+
+    for path in ["../secret.mjml", "file:///../secret.mjml", "/etc/passwd", &root.join("secret.mjml").display().to_string()] {
+        let mjml_input = MjmlInput {
+            mjml: Some(format!("<mjml><mj-body><mj-include path=\"{}\" /></mj-body></mjml>", path)),
+            ..Default::default()
+        };
+        let response = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+        let error: Value = serde_json::from_str(&body_string(response).await)?;
+        assert!(!error["error"].as_str().unwrap().contains("secret"), "{}", error);
+        // The snippet quotes the request, never the file.
+        assert!(!error.to_string().contains("<mj-text>secret"), "{}", error);
+        assert_eq!(error["location"]["file"], "inline");
+        assert_eq!(error["location"]["line"], 1);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_include_edit_on_disk_is_visible_to_next_convert() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("watch-include");
+    std::fs::write(template_dir.join("header.mjml"), "<mj-text>Header</mj-text>")?;
+    std::fs::write(template_dir.join("static.mjml"), "<mjml><mj-body><mj-include path=\"header.mjml\" /></mj-body></mjml>")?;
+    let app_state = initialize_state(template_dir.to_str().unwrap(), ServerConfig::default()).await.unwrap();
+    assert_eq!(convert_text(&app_state, "static.mjml", json!({})).await?, "Header");
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    std::fs::write(template_dir.join("header.mjml"), "<mj-text>New header</mj-text>")?;
+    wait_for_convert(&app_state, "static.mjml", |result| result.as_deref() == Ok("New header")).await;
+    Ok(())
+}
+
+#[test]
+fn test_dependency_graph_is_transitive() -> Result<(), Box<dyn std::error::Error>> {
+    let graph = DependencyGraph::default();
+    let welcome = TemplateId::new("welcome.mjml")?;
+    let layout = TemplateId::new("layouts/base.mjml")?;
+    let footer = TemplateId::new("partials/footer.mjml")?;
+    graph.record(&welcome, &layout);
+    graph.record(&layout, &footer);
+    graph.record(&welcome, &footer);
+
+    assert_eq!(graph.transitive_dependents(&footer).len(), 2);
+    assert_eq!(graph.transitive_dependents(&layout), vec![welcome.clone()]);
+    assert!(graph.transitive_dependents(&welcome).is_empty());
+
+    graph.clear(&welcome);
+    assert_eq!(graph.transitive_dependents(&footer), vec![layout]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dependency_graph_endpoint() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("dependency-graph");
+    std::fs::create_dir_all(template_dir.join("partials"))?;
+    std::fs::create_dir_all(template_dir.join("layouts"))?;
+    std::fs::write(template_dir.join("partials/footer.mjml"), "<mj-text>Footer</mj-text>")?;
+    std::fs::write(template_dir.join("layouts/base.mjml"), "<mjml><mj-body>{{> @partial-block}}{{> footer}}</mj-body></mjml>")?;
+    std::fs::write(template_dir.join("header.mjml"), "<mj-text>Header</mj-text>")?;
+    std::fs::write(template_dir.join("welcome.mjml"), "{{#> base}}<mj-include path=\"header.mjml\" />{{/base}}")?;
+    let config = ServerConfig { admin_token: Some("secret".to_string()), ..Default::default() };
+    let app_state = AppState::with_config(100, template_dir.clone(), config);
+    app_state.register_partials();
+    assert_eq!(convert_text(&app_state, "welcome.mjml", json!({})).await?, "Header\nFooter");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::AUTHORIZATION, "Bearer secret".parse()?);
+    let response = dependency_graph(State(app_state.clone()), headers).await.unwrap();
+    let graph: serde_json::Value = serde_json::from_str(&body_string(response).await)?;
+    assert_eq!(
+        graph["dependencies"]["welcome.mjml"],
+        json!(["header.mjml", "layouts/base.mjml", "partials/base.mjml", "welcome.schema.json"])
+    );
+    assert_eq!(graph["dependencies"]["layouts/base.mjml"], json!(["layouts/footer.mjml", "partials/footer.mjml"]));
+    assert_eq!(graph["dependents"]["partials/footer.mjml"], json!(["layouts/base.mjml"]));
+
+    // A deleted template leaves the graph.
+    std::fs::remove_file(template_dir.join("welcome.mjml"))?;
+    app_state.file_changed(&TemplateId::new("welcome.mjml")?, true)?;
+    let graph = serde_json::to_value(app_state.dependencies.snapshot())?;
+    assert!(graph["dependencies"].get("welcome.mjml").is_none(), "{}", graph);
+    assert!(graph["dependents"].get("header.mjml").is_none(), "{}", graph);
+    assert_eq!(graph["dependents"]["partials/footer.mjml"], json!(["layouts/base.mjml"]));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::AUTHORIZATION, "Bearer wrong".parse()?);
+    let (status, _) = dependency_graph(State(app_state.clone()), headers).await.unwrap_err();
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = dependency_graph(State(AppState::new(100, template_dir)), HeaderMap::new()).await.unwrap_err();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_include_in_partial_edit_on_disk_is_visible_to_next_convert() -> Result<(), Box<dyn std::error::Error>> {
+    // header.mjml reaches welcome.mjml only through the nav partial.
+    let template_dir = temp_template_dir("watch-transitive");
+    std::fs::create_dir_all(template_dir.join("partials"))?;
+    std::fs::write(template_dir.join("header.mjml"), "<mj-text>Header</mj-text>")?;
+    std::fs::write(template_dir.join("partials/nav.mjml"), "<mj-include path=\"header.mjml\" />")?;
+    std::fs::write(template_dir.join("welcome.mjml"), "<mjml><mj-body>{{> nav}}<mj-text>Hi</mj-text></mj-body></mjml>")?;
+    let app_state = initialize_state(template_dir.to_str().unwrap(), ServerConfig::default()).await.unwrap();
+    assert_eq!(convert_text(&app_state, "welcome.mjml", json!({})).await?, "Header\nHi");
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    std::fs::write(template_dir.join("header.mjml"), "<mj-text>New header</mj-text>")?;
+    wait_for_convert(&app_state, "welcome.mjml", |result| result.as_deref() == Ok("New header\nHi")).await;
+    Ok(())
+}
+
+fn violation(path: &str, message: &str) -> Violation {
+    Violation { path: path.to_string(), message: message.to_string() }
+}
+
+#[test]
+fn test_schema_validation() -> Result<(), Box<dyn std::error::Error>> {
+    let schema = Schema::compile(json!({
+        "type": "object",
+        "required": ["order"],
+        "properties": {
+            "order": {
+                "type": "object",
+                "required": ["total", "currency"],
+                "additionalProperties": false,
+                "properties": {
+                    "total": {"type": "number", "minimum": 0},
+                    "currency": {"enum": ["EUR", "USD"]},
+                    "items": {"type": "array", "minItems": 1, "items": {"$ref": "#/$defs/item"}}
+                }
+            },
+            "a/b~c": {"type": "string", "pattern": "^[a-z]+$"}
+        },
+        "$defs": {
+            "item": {"type": "object", "required": ["sku"], "properties": {"sku": {"type": "string", "minLength": 3}}}
+        }
+    }))?;
+
+    assert!(schema.validate(&json!({"order": {"total": 1.5, "currency": "EUR", "items": [{"sku": "abc"}]}})).is_empty());
+    assert_eq!(
+        schema.validate(&json!({"order": {"currency": "GBP", "items": [{"sku": "ab"}, {}], "note": 1}, "a/b~c": "A"})),
+        vec![
+            violation("/a~1b~0c", "Must match the pattern ^[a-z]+$"),
+            violation("/order/total", "Missing required property"),
+            violation("/order/currency", "Must be one of [\"EUR\",\"USD\"]"),
+            violation("/order/items/0/sku", "Must be at least 3 characters long"),
+            violation("/order/items/1/sku", "Missing required property"),
+            violation("/order/note", "Unexpected property"),
+        ]
+    );
+    assert_eq!(schema.validate(&json!([])), vec![violation("", "Expected object, found array")]);
+
+    assert!(Schema::compile(json!({"pattern": "("})).is_err());
+    assert!(Schema::compile(json!({"$ref": "https://example.com/schema.json"})).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_convert_validates_payload_against_schema() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("schema");
+    std::fs::create_dir_all(template_dir.join("billing"))?;
+    std::fs::write(
+        template_dir.join("billing/receipt.mjml"),
+        "<mjml><mj-body><mj-text>Total {{order.total}}</mj-text></mj-body></mjml>",
+    )?;
+    std::fs::write(
+        template_dir.join("billing/receipt.schema.json"),
+        r#"{"type": "object", "required": ["order"], "properties": {"order": {"required": ["total"], "properties": {"total": {"type": "number"}}}}}"#,
+    )?;
+    let app_state = AppState::new(100, template_dir.clone());
+
+    assert_eq!(convert_text(&app_state, "billing/receipt.mjml", json!({"order": {"total": 12}})).await?, "Total 12");
+
+    let mjml_input = MjmlInput {
+        payload: json!({"order": {"total": "12"}}),
+        template: Some("billing/receipt.mjml".to_string()),
+        ..Default::default()
+    };
+    let response = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = serde_json::from_str(&body_string(response).await)?;
+    assert_eq!(body["violations"], json!([{"path": "/order/total", "message": "Expected number, found string"}]));
+
+    let body = json!({"template": "billing/receipt.mjml", "payloads": [{"order": {"total": 1}}, {}]});
+    let request = Request::builder().body(Body::from(body.to_string()))?;
+    let response = convert_batch(State(app_state.clone()), request).await.unwrap();
+    let lines = batch_lines(&body_string(response).await);
+    assert_eq!(lines[0]["ok"], true);
+    assert_eq!(lines[1]["error"], "Payload does not match the template schema: /order: Missing required property");
+
+    // A broken schema fails loudly instead of letting every payload through.
+    std::fs::write(template_dir.join("broken.mjml"), "<mjml><mj-body><mj-text>Hi</mj-text></mj-body></mjml>")?;
+    std::fs::write(template_dir.join("broken.schema.json"), "{\"type\": ")?;
+    let message = convert_text(&app_state, "broken.mjml", json!({})).await.unwrap_err();
+    assert!(message.contains("Invalid JSON in schema broken.schema.json"), "{}", message);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_schema_edit_on_disk_is_visible_to_next_convert() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("watch-schema");
+    std::fs::write(template_dir.join("welcome.mjml"), "<mjml><mj-body><mj-text>Hello {{name}}</mj-text></mj-body></mjml>")?;
+    let app_state = initialize_state(template_dir.to_str().unwrap(), ServerConfig::default()).await.unwrap();
+    assert_eq!(convert_text(&app_state, "welcome.mjml", json!({"name": "Ada"})).await?, "Hello Ada");
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    std::fs::write(template_dir.join("welcome.schema.json"), r#"{"properties": {"name": {"maxLength": 2}}}"#)?;
+    wait_for_convert(&app_state, "welcome.mjml", |result| result.is_err()).await;
+
+    std::fs::write(template_dir.join("welcome.schema.json"), r#"{"properties": {"name": {"maxLength": 3}}}"#)?;
+    wait_for_convert(&app_state, "welcome.mjml", |result| result.as_deref() == Ok("Hello Ada")).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_missing_variables_modes() -> Result<(), Box<dyn std::error::Error>> {
+    let mjml = "<mjml><mj-body><mj-text>Hi {{name}} {{order.total}}{{#each items}} {{sku}}{{/each}}</mj-text></mj-body></mjml>";
+    let payload = json!({"name": "Ada", "items": [{"sku": "A1"}, {}]});
+    let input = |mode: Option<MissingVariables>, output| MjmlInput {
+        mjml: Some(mjml.to_string()),
+        payload: payload.clone(),
+        output: Some(output),
+        missing_variables: mode,
+        ..Default::default()
+    };
+    let app_state = AppState::new(100, PathBuf::from("templates"));
+
+    // Lenient by default: blanks, no warnings.
+    let response = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(input(None, OutputFormat::Json))).await.unwrap();
+    assert!(response.headers().get("x-unresolved-variables").is_none());
+    let body: serde_json::Value = serde_json::from_str(&body_string(response).await)?;
+    assert!(!body["warnings"].to_string().contains("Unresolved"));
+
+    let response = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(input(Some(MissingVariables::Report), OutputFormat::Json))).await.unwrap();
+    assert_eq!(response.headers()["x-unresolved-variables"], "order.total, items.1.sku");
+    let body: serde_json::Value = serde_json::from_str(&body_string(response).await)?;
+    assert_eq!(body["text"], "Hi Ada A1");
+    let warnings = body["warnings"].as_array().unwrap();
+    assert!(warnings.contains(&json!("Unresolved variable: order.total")));
+    assert!(warnings.contains(&json!("Unresolved variable: items.1.sku")));
+
+    let (status, message) = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(input(Some(MissingVariables::Strict), OutputFormat::Html))).await.unwrap_err();
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(message.contains("order.total"), "{}", message);
+    // Helpers and blocks that miss a value are the payload's fault as well.
+    for snippet in ["{{#with order}}{{total}}{{/with}}", "{{#each lines}}x{{/each}}", "{{lookup items 5}}"] {
+        let mjml_input = MjmlInput {
+            mjml: Some(format!("<mjml><mj-body><mj-text>{}</mj-text></mj-body></mjml>", snippet)),
+            payload: payload.clone(),
+            missing_variables: Some(MissingVariables::Strict),
+            ..Default::default()
+        };
+        let (status, message) = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}: {}", snippet, message);
+    }
+
+    // Server-wide strict mode, relaxed by one request.
+    let config = ServerConfig { missing_variables: MissingVariables::Strict, ..Default::default() };
+    let strict_state = AppState::with_config(100, PathBuf::from("templates"), config);
+    let (status, _) = convert_mjml(State(strict_state.clone()), HeaderMap::new(), Json(input(None, OutputFormat::Html))).await.unwrap_err();
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let response = convert_mjml(State(strict_state), HeaderMap::new(), Json(input(Some(MissingVariables::Ignore), OutputFormat::Text))).await.unwrap();
+    assert_eq!(body_string(response).await, "Hi Ada A1");
+
+    // Unknown helpers still fail instead of being reported as variables.
+    let mjml_input = MjmlInput {
+        mjml: Some("<mjml><mj-body><mj-text>{{shout name}}</mj-text></mj-body></mjml>".to_string()),
+        payload: payload.clone(),
+        missing_variables: Some(MissingVariables::Report),
+        ..Default::default()
+    };
+    let (status, message) = convert_mjml(State(app_state), HeaderMap::new(), Json(mjml_input)).await.unwrap_err();
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(message.contains("Helper not defined"), "{}", message);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_missing_variables_in_templates_and_batches() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("missing-variables");
+    std::fs::create_dir_all(template_dir.join("partials"))?;
+    std::fs::write(template_dir.join("partials/sign.mjml"), "<mj-text>{{sender}}</mj-text>")?;
+    std::fs::write(template_dir.join("welcome.mjml"), "<mjml><mj-body><mj-text>Hi {{name}}</mj-text>{{> sign}}</mj-body></mjml>")?;
+    let app_state = AppState::new(100, template_dir);
+    app_state.register_partials();
+
+    // Partials are registered in the strict registry as well.
+    let mjml_input = MjmlInput {
+        payload: json!({"name": "Ada"}),
+        template: Some("welcome.mjml".to_string()),
+        missing_variables: Some(MissingVariables::Strict),
+        ..Default::default()
+    };
+    let (status, message) = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await.unwrap_err();
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(message.contains("sender"), "{}", message);
+
+    let body = json!({"template": "welcome.mjml", "missing_variables": "report", "payloads": [{"name": "Ada"}, {"sender": "Bob"}]});
+    let request = Request::builder().body(Body::from(body.to_string()))?;
+    let response = convert_batch(State(app_state), request).await.unwrap();
+    let lines = batch_lines(&body_string(response).await);
+    assert!(lines[0]["result"]["warnings"].as_array().unwrap().contains(&json!("Unresolved variable: sender")));
+    assert!(lines[1]["result"]["warnings"].as_array().unwrap().contains(&json!("Unresolved variable: name")));
+    Ok(())
+}
+
+/// Renders a Handlebars snippet with the helpers of `app_state`.
+fn render_snippet(app_state: &AppState, template: &str, data: serde_json::Value) -> Result<String, String> {
+    let handlebars = app_state.handlebars.read().unwrap();
+    handlebars.render_template(template, &data).map_err(|e| e.to_string())
+}
+
+#[test]
+fn test_date_helpers() {
+    let app_state = AppState::new(100, PathBuf::from("templates"));
+    let render = |template: &str, data| render_snippet(&app_state, template, data);
+    let data = json!({"sent": "2024-07-14T18:30:00Z", "winter": "2024-01-05T09:05:00+01:00", "epoch": 1_700_000_000, "day": "2024-03-05"});
+
+    assert_eq!(render("{{format_date sent}}", data.clone()).unwrap(), "2024-07-14");
+    assert_eq!(render(r#"{{format_date sent "%A %e %B %Y, %H:%M %Z"}}"#, data.clone()).unwrap(), "Sunday 14 July 2024, 18:30 UTC");
+    // Daylight and standard time of an IANA zone.
+    assert_eq!(render(r#"{{format_date sent "%H:%M %Z %z" tz="Europe/Paris"}}"#, data.clone()).unwrap(), "20:30 CEST +0200");
+    assert_eq!(render(r#"{{format_date winter "%H:%M %Z" tz="Europe/Paris"}}"#, data.clone()).unwrap(), "09:05 CET");
+    assert_eq!(render(r#"{{format_date sent "%b %d %I:%M %p" tz="America/New_York"}}"#, data.clone()).unwrap(), "Jul 14 02:30 PM");
+    // Past the zone's last transition, its POSIX rule applies.
+    let future = json!({"at": "2090-07-01T12:00:00Z"});
+    assert_eq!(render(r#"{{format_date at "%H:%M %Z" tz="America/New_York"}}"#, future).unwrap(), "08:00 EDT");
+    let southern_summer = json!({"at": "2090-01-15T00:00:00Z"});
+    assert_eq!(render(r#"{{format_date at "%H:%M %Z" tz="Australia/Sydney"}}"#, southern_summer).unwrap(), "11:00 AEDT");
+    assert_eq!(render(r#"{{format_date epoch "%Y-%m-%d %H:%M:%S" tz="+05:30"}}"#, data.clone()).unwrap(), "2023-11-15 03:43:20");
+    // A bare date is a calendar day, whatever the zone.
+    assert_eq!(render(r#"{{format_date day "%d/%m/%Y" tz="Pacific/Auckland"}}"#, data.clone()).unwrap(), "05/03/2024");
+
+    let error = render(r#"{{format_date sent tz="Mars/Olympus"}}"#, data.clone()).unwrap_err();
+    assert!(error.contains("Unknown time zone"), "{}", error);
+    assert!(render(r#"{{format_date sent tz="../etc/passwd"}}"#, data.clone()).unwrap_err().contains("Invalid time zone"));
+    // Offsets that would overflow, or wrap round to a plausible one.
+    for tz in ["+9999999:00", "+1193047", "+99999999999", "+01:60", "+24:00"] {
+        let error = render(&format!(r#"{{{{format_date sent tz="{}"}}}}"#, tz), data.clone()).unwrap_err();
+        assert!(error.contains("Invalid UTC offset"), "{}: {}", tz, error);
+    }
+    assert!(render("{{format_date day}}", json!({"day": "2024-02-30"})).unwrap_err().contains("invalid date"));
+}
+
+#[test]
+fn test_date_helpers_reject_out_of_range_timestamps() {
+    let app_state = AppState::new(100, PathBuf::from("templates"));
+    let render = |template: &str, data| render_snippet(&app_state, template, data);
+    for value in [json!(1e300), json!(-1e300), json!(i64::MAX), json!(u64::MAX), json!(253_402_300_800_i64), json!("0000-01-01T00:30:00+01:00")] {
+        let error = render(r#"{{format_date value tz="Europe/Paris"}}"#, json!({"value": value})).unwrap_err();
+        assert!(error.contains("outside the years 0 to 9999"), "{}: {}", value, error);
+    }
+    assert_eq!(render("{{format_date value}}", json!({"value": 253_402_300_799_i64})).unwrap(), "9999-12-31");
+    assert_eq!(render(r#"{{format_date value "%m-%d %H:%M"}}"#, json!({"value": -62_167_219_200_i64})).unwrap(), "01-01 00:00");
+}
+
+#[test]
+fn test_number_helpers() {
+    let app_state = AppState::new(100, PathBuf::from("templates"));
+    let render = |template: &str| render_snippet(&app_state, template, json!({"total": 1234567.891, "count": 1200, "debt": -5.5}));
+
+    assert_eq!(render("{{format_number count}}").unwrap(), "1,200");
+    assert_eq!(render("{{format_number total}}").unwrap(), "1,234,567.89");
+    assert_eq!(render(r#"{{format_number total decimals=1 locale="de-DE"}}"#).unwrap(), "1.234.567,9");
+    assert_eq!(render(r#"{{format_number total decimals=0 locale="fr"}}"#).unwrap(), "1\u{202f}234\u{202f}568");
+    assert_eq!(render(r#"{{format_number "42.5" locale="de_CH"}}"#).unwrap(), "42.50");
+
+    assert_eq!(render(r#"{{format_currency total "USD"}}"#).unwrap(), "$1,234,567.89");
+    assert_eq!(render(r#"{{format_currency debt "usd"}}"#).unwrap(), "-$5.50");
+    assert_eq!(render(r#"{{format_currency total "EUR" locale="fr-FR"}}"#).unwrap(), "1\u{202f}234\u{202f}567,89\u{a0}€");
+    assert_eq!(render(r#"{{format_currency count "EUR" locale="nl-NL"}}"#).unwrap(), "€\u{a0}1.200,00");
+    assert_eq!(render(r#"{{format_currency total "JPY" locale="ja"}}"#).unwrap(), "¥1,234,568");
+
+    assert!(render(r#"{{format_number total locale="xx"}}"#).unwrap_err().contains("Unsupported locale"));
+    assert!(render(r#"{{format_currency total "EURO"}}"#).unwrap_err().contains("invalid currency code"));
+    assert!(render(r#"{{format_number "lots"}}"#).unwrap_err().contains("expected a number"));
+}
+
+#[test]
+fn test_text_helpers() {
+    let app_state = AppState::new(100, PathBuf::from("templates"));
+    let data = json!({"one": 1, "many": 3, "title": "A very long subject line", "nickname": "", "name": "Ada"});
+    let render = |template: &str| render_snippet(&app_state, template, data.clone());
+
+    assert_eq!(render(r#"{{one}} {{pluralize one "item"}}, {{many}} {{pluralize many "item"}}"#).unwrap(), "1 item, 3 items");
+    assert_eq!(render(r#"{{pluralize many "child" "children"}}"#).unwrap(), "children");
+    assert_eq!(render("{{truncate title 11}}").unwrap(), "A very long…");
+    assert_eq!(render(r#"{{truncate title 7 suffix="..."}}"#).unwrap(), "A very...");
+    assert_eq!(render("{{truncate title 100}}").unwrap(), "A very long subject line");
+    assert_eq!(render(r#"Hi {{default nickname name "there"}}"#).unwrap(), "Hi Ada");
+    assert_eq!(render(r#"Hi {{default missing "there"}}"#).unwrap(), "Hi there");
+    // Helpers compose as subexpressions.
+    assert_eq!(render(r#"{{truncate (default nickname title) 6}}"#).unwrap(), "A very…");
+}
+
+#[test]
+fn test_comparison_helpers() {
+    let app_state = AppState::new(100, PathBuf::from("templates"));
+    let data = json!({"total": 49.99, "threshold": 50, "count": 2, "plan": "pro", "tags": []});
+    let render = |template: &str| render_snippet(&app_state, template, data.clone());
+
+    assert_eq!(render("{{#if (lt total threshold)}}under{{else}}over{{/if}}").unwrap(), "under");
+    assert_eq!(render("{{gte threshold 50.0}} {{eq threshold 50.0}} {{ne count 2}}").unwrap(), "true true false");
+    assert_eq!(render(r#"{{gt plan "basic"}} {{eq plan "pro"}}"#).unwrap(), "true true");
+    assert_eq!(render(r#"{{and count plan (eq plan "pro")}} {{or tags missing}} {{not tags}}"#).unwrap(), "true false true");
+    assert!(render(r#"{{gt total "10"}}"#).unwrap_err().contains("can't compare"));
+
+    // The strict registry has the same helpers.
+    let strict = app_state.strict_handlebars.read().unwrap();
+    assert_eq!(strict.render_template("{{#if (gt total 10)}}yes{{/if}}", &data).unwrap(), "yes");
+}
+
+#[test]
+fn test_helper_groups_can_be_disabled() {
+    let mut config = ServerConfig::default();
+    config.helpers.text = false;
+    config.helpers.comparison = false;
+    config.helpers.default_locale = "de".to_string();
+    let app_state = AppState::with_config(100, PathBuf::from("templates"), config);
+    let render = |template: &str| render_snippet(&app_state, template, json!({"count": 2, "total": 1.5}));
+
+    assert!(render(r#"{{pluralize count "item"}}"#).unwrap_err().contains("pluralize"));
+    // Handlebars' own comparisons remain, integers only.
+    assert_eq!(render("{{gt count 1}}").unwrap(), "true");
+    assert!(render("{{gt total 1}}").is_err());
+    assert_eq!(render("{{format_number total}}").unwrap(), "1,50");
+}
+
+/// Renders `name` in `locale` through `/convert` as JSON.
+async fn convert_localized(app_state: &AppState, name: &str, locale: Option<&str>, payload: serde_json::Value) -> Result<serde_json::Value, (StatusCode, String)> {
+    let mjml_input = MjmlInput {
+        payload,
+        template: Some(name.to_string()),
+        output: Some(OutputFormat::Json),
+        locale: locale.map(str::to_string),
+        ..Default::default()
+    };
+    let response = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await?;
+    Ok(serde_json::from_str(&body_string(response).await).unwrap())
+}
+
+#[test]
+fn test_locale_tags() {
+    assert_eq!(LocaleTag::new("fr_ca").unwrap().as_str(), "fr-CA");
+    assert_eq!(LocaleTag::new("ZH-hant-tw").unwrap().as_str(), "zh-Hant-TW");
+    let tag = LocaleTag::new("zh-Hant-TW").unwrap();
+    assert_eq!(tag.fallbacks().collect::<Vec<_>>(), ["zh-Hant-TW", "zh-Hant", "zh"]);
+    for invalid in ["", "f", "french", "fr-", "fr/../x", "fr CA"] {
+        assert!(LocaleTag::new(invalid).is_err(), "{:?}", invalid);
+    }
+    assert_eq!(localized_names("auth/welcome.mjml", Some(&LocaleTag::new("fr-CA").unwrap())), ["auth/welcome.fr-CA.mjml", "auth/welcome.fr.mjml", "auth/welcome.mjml"]);
+    assert_eq!(localized_names("welcome.mjml", None), ["welcome.mjml"]);
+}
+
+#[tokio::test]
+async fn test_locale_template_variants() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("locale-variants");
+    std::fs::write(template_dir.join("welcome.mjml"), "<mjml><mj-body><mj-text>Hello {{name}}</mj-text></mj-body></mjml>")?;
+    std::fs::write(template_dir.join("welcome.fr.mjml"), "<mjml><mj-body><mj-text>Bonjour {{name}}</mj-text></mj-body></mjml>")?;
+    let app_state = AppState::new(100, template_dir.clone());
+    let payload = json!({"name": "Ada"});
+
+    let body = convert_localized(&app_state, "welcome.mjml", Some("fr-CA"), payload.clone()).await.unwrap();
+    assert_eq!((body["text"].as_str(), body["template"].as_str()), (Some("Bonjour Ada"), Some("welcome.fr.mjml")));
+    let body = convert_localized(&app_state, "welcome.mjml", Some("de"), payload.clone()).await.unwrap();
+    assert_eq!((body["text"].as_str(), body["template"].as_str()), (Some("Hello Ada"), Some("welcome.mjml")));
+    let body = convert_localized(&app_state, "welcome.mjml", None, payload.clone()).await.unwrap();
+    assert_eq!(body["text"], "Hello Ada");
+
+    // A more specific variant added later wins over the cached fallback,
+    // once the watcher reports it: lookups that found nothing are remembered.
+    std::fs::write(template_dir.join("welcome.fr-CA.mjml"), "<mjml><mj-body><mj-text>Allô {{name}}</mj-text></mj-body></mjml>")?;
+    let body = convert_localized(&app_state, "welcome.mjml", Some("fr-CA"), payload.clone()).await.unwrap();
+    assert_eq!(body["text"], "Bonjour Ada");
+    app_state.file_changed(&TemplateId::new("welcome.fr-CA.mjml")?, false)?;
+    let body = convert_localized(&app_state, "welcome.mjml", Some("fr-CA"), payload.clone()).await.unwrap();
+    assert_eq!(body["text"], "Allô Ada");
+
+    let (status, message) = convert_localized(&app_state, "welcome.mjml", Some("fr CA"), payload.clone()).await.unwrap_err();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(message.contains("Invalid locale"), "{}", message);
+
+    let body = json!({"template": "welcome.mjml", "locale": "fr", "payloads": [{"name": "Ada"}]});
+    let request = Request::builder().body(Body::from(body.to_string()))?;
+    let lines = batch_lines(&body_string(convert_batch(State(app_state), request).await.unwrap()).await);
+    assert_eq!(lines[0]["result"]["text"], "Bonjour Ada");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_message_catalogs() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("catalogs");
+    std::fs::create_dir_all(template_dir.join("locales"))?;
+    std::fs::write(template_dir.join("locales/en.json"), r#"{
+        "greeting": "Hello {name}",
+        "footer": "Sent with care",
+        "cart": {"items": {"one": "{count} item", "other": "{count} items"}}
+    }"#)?;
+    std::fs::write(template_dir.join("locales/fr.json"), r#"{
+        "greeting": "Bonjour {name}",
+        "cart.items": {"zero": "Panier vide", "one": "{count} article", "other": "{count} articles"}
+    }"#)?;
+    std::fs::write(template_dir.join("locales/fr-CA.json"), r#"{"greeting": "Allô {name}"}"#)?;
+    std::fs::write(template_dir.join("locales/ru.json"), r#"{"cart": {"items": {"one": "{count} товар", "few": "{count} товара", "many": "{count} товаров"}}}"#)?;
+    std::fs::write(template_dir.join("locales/notes.txt"), "not a catalog")?;
+    std::fs::write(
+        template_dir.join("cart.mjml"),
+        r#"<mjml><mj-body><mj-text>{{t "greeting" name=name}}, {{t "cart.items" count=count}}, {{t "footer"}}, {{t "missing.key"}}, {{format_number total}}</mj-text></mj-body></mjml>"#,
+    )?;
+    let app_state = AppState::new(100, template_dir);
+    assert_eq!(app_state.catalogs.load_all(), 4);
+    let payload = |count: u32| json!({"name": "Ada", "count": count, "total": 1234.5});
+    let text = |body: serde_json::Value| body["text"].as_str().unwrap().to_string();
+
+    // fr-CA, then fr, then the server's default locale.
+    let body = convert_localized(&app_state, "cart.mjml", Some("fr-CA"), payload(0)).await.unwrap();
+    // The plain-text alternative turns the narrow no-break space into a space.
+    assert_eq!(text(body), "Allô Ada, Panier vide, Sent with care, missing.key, 1 234,50");
+    let body = convert_localized(&app_state, "cart.mjml", Some("fr"), payload(1)).await.unwrap();
+    assert!(text(body).starts_with("Bonjour Ada, 1 article,"));
+    let body = convert_localized(&app_state, "cart.mjml", None, payload(2)).await.unwrap();
+    assert_eq!(text(body), "Hello Ada, 2 items, Sent with care, missing.key, 1,234.50");
+    for (count, expected) in [(1, "1 товар"), (3, "3 товара"), (11, "11 товаров"), (22, "22 товара")] {
+        let body = convert_localized(&app_state, "cart.mjml", Some("ru"), payload(count)).await.unwrap();
+        assert!(text(body).contains(expected), "{}", expected);
+    }
+
+    // Strict mode reports missing messages.
+    let mjml_input = MjmlInput {
+        payload: payload(1),
+        template: Some("cart.mjml".to_string()),
+        locale: Some("fr".to_string()),
+        missing_variables: Some(MissingVariables::Strict),
+        ..Default::default()
+    };
+    let (_, message) = convert_mjml(State(app_state), HeaderMap::new(), Json(mjml_input)).await.unwrap_err();
+    assert!(message.contains("no message \"missing.key\" for fr"), "{}", message);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_catalog_edit_on_disk_is_visible_to_next_convert() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("watch-catalog");
+    std::fs::create_dir_all(template_dir.join("locales"))?;
+    let path = template_dir.join("locales/en.json");
+    std::fs::write(&path, r#"{"greeting": "Hello {name}"}"#)?;
+    std::fs::write(template_dir.join("welcome.mjml"), r#"<mjml><mj-body><mj-text>{{t "greeting" name=name}}</mj-text></mj-body></mjml>"#)?;
+    let app_state = initialize_state(template_dir.to_str().unwrap(), ServerConfig::default()).await.unwrap();
+    assert_eq!(convert_text(&app_state, "welcome.mjml", json!({"name": "Ada"})).await?, "Hello Ada");
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    std::fs::write(&path, r#"{"greeting": "Welcome back {name}"}"#)?;
+    wait_for_convert(&app_state, "welcome.mjml", |result| result.as_deref() == Ok("Welcome back Ada")).await;
+
+    std::fs::remove_file(&path)?;
+    wait_for_convert(&app_state, "welcome.mjml", |result| result.as_deref() == Ok("greeting")).await;
+    Ok(())
+}
+
+/// Renders `name` through `/convert` as text, or returns the error message.
+async fn convert_text(app_state: &AppState, name: &str, payload: serde_json::Value) -> Result<String, String> {
+    let mjml_input = MjmlInput {
+        payload,
+        template: Some(name.to_string()),
+        output: Some(OutputFormat::Text),
+        ..Default::default()
+    };
+    match convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await {
+        Ok(response) if response.status() == StatusCode::OK => Ok(body_string(response).await),
+        Ok(response) => Err(body_string(response).await),
+        Err((_, message)) => Err(message),
+    }
+}
+
+/// Polls `/convert` until `check` accepts the result, failing after a few seconds.
+async fn wait_for_convert<F>(app_state: &AppState, name: &str, check: F)
+where
+    F: Fn(&Result<String, String>) -> bool,
+{
+    for _ in 0..100 {
+        if check(&convert_text(app_state, name, json!({"name": "Ada"})).await) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("the watcher never applied the change to {}", name);
+}
+
+#[tokio::test]
+async fn test_edit_on_disk_is_visible_to_next_convert() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("watch-edit");
+    std::fs::create_dir_all(template_dir.join("auth"))?;
+    let path = template_dir.join("auth/welcome.mjml");
+    std::fs::write(&path, "<mjml><mj-body><mj-text>Hello {{name}}</mj-text></mj-body></mjml>")?;
+    let app_state = initialize_state(template_dir.to_str().unwrap(), ServerConfig::default()).await.unwrap();
+
+    // Both spellings of the name share one cache entry.
+    assert_eq!(convert_text(&app_state, "auth/welcome.mjml", json!({"name": "Ada"})).await?, "Hello Ada");
+    assert_eq!(convert_text(&app_state, "./auth//welcome.mjml", json!({"name": "Ada"})).await?, "Hello Ada");
+
+    // Give the watcher time to start before touching the file.
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    std::fs::write(&path, "<mjml><mj-body><mj-text>Welcome back {{name}}</mj-text></mj-body></mjml>")?;
+    wait_for_convert(&app_state, "./auth//welcome.mjml", |result| result.as_deref() == Ok("Welcome back Ada")).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_on_disk_is_visible_to_next_convert() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("watch-delete");
+    let path = template_dir.join("gone.mjml");
+    std::fs::write(&path, "<mjml><mj-body><mj-text>Hello {{name}}</mj-text></mj-body></mjml>")?;
+    let app_state = initialize_state(template_dir.to_str().unwrap(), ServerConfig::default()).await.unwrap();
+    assert_eq!(convert_text(&app_state, "gone.mjml", json!({"name": "Ada"})).await?, "Hello Ada");
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    std::fs::remove_file(&path)?;
+    wait_for_convert(&app_state, "gone.mjml", |result| result.is_err()).await;
+    Ok(())
+}
+
+fn batch_lines(body: &str) -> Vec<serde_json::Value> {
+    body.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+}
+
+#[tokio::test]
+async fn test_convert_batch_array() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = PathBuf::from("templates");
+    let body = json!({
+        "mjml": "<mjml><mj-body><mj-text>Hello {{name}} {{{extra}}}</mj-text></mj-body></mjml>",
+        "payloads": [
+            {"name": "Ada"},
+            {"name": "Bob", "extra": "<mj-text>"},
+            {"name": "Cy"}
+        ]
+    });
+    let request = Request::builder().body(Body::from(body.to_string()))?;
+
+    let response = convert_batch(State(AppState::new(100, template_dir)), request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/x-ndjson");
+    let lines = batch_lines(&body_string(response).await);
+
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0]["index"], 0);
+    assert_eq!(lines[0]["ok"], true);
+    assert_eq!(lines[0]["result"]["text"], "Hello Ada");
+    assert_eq!(lines[1]["ok"], false);
+    assert!(lines[1]["error"].as_str().unwrap().starts_with("Invalid MJML input"));
+    assert_eq!(lines[2]["result"]["text"], "Hello Cy");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_convert_batch_ndjson_stream() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = PathBuf::from("templates");
+    let body = concat!(
+        "{\"template\": \"test.mjml\"}\n",
+        "{\"name\": \"Ada\", \"orderId\": 1}\n",
+        "not json\n",
+        "\n",
+        "{\"name\": \"Cy\", \"orderId\": 3}",
+    );
+    let request = Request::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::from(body))?;
+
+    let response = convert_batch(State(AppState::new(100, template_dir)), request).await.unwrap();
+    let lines = batch_lines(&body_string(response).await);
+
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0]["result"]["template"], "test.mjml");
+    assert!(lines[0]["result"]["text"].as_str().unwrap().contains("Hello Ada !"));
+    assert_eq!(lines[1]["ok"], false);
+    assert!(lines[1]["error"].as_str().unwrap().starts_with("Invalid payload"));
+    assert_eq!(lines[2]["index"], 2);
+    assert!(lines[2]["result"]["text"].as_str().unwrap().contains("Your order ID is: 3"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_convert_batch_limits_its_body() -> Result<(), Box<dyn std::error::Error>> {
+    let config = ServerConfig { max_batch_bytes: Some(200), ..Default::default() };
+    let app_state = AppState::with_config(100, PathBuf::from("templates"), config);
+    let mjml = "<mjml><mj-body><mj-text>Hello {{name}}</mj-text></mj-body></mjml>";
+
+    // A body sent without a Content-Length is counted as it arrives.
+    let payloads: Vec<Value> = (0..20).map(|_| json!({"name": "Ada"})).collect();
+    let body = json!({"mjml": mjml, "payloads": payloads}).to_string();
+    let chunks: Vec<Result<String, std::io::Error>> = vec![Ok(body[..100].to_string()), Ok(body[100..].to_string())];
+    let request = Request::builder().body(Body::wrap_stream(futures_util::stream::iter(chunks)))?;
+    let (status, _) = convert_batch(State(app_state.clone()), request).await.unwrap_err();
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    let request = Request::builder().header(header::CONTENT_LENGTH, body.len()).body(Body::from(body))?;
+    let (status, _) = convert_batch(State(app_state.clone()), request).await.unwrap_err();
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+    // NDJSON streams can be as long as they like, but not their lines.
+    let ndjson = |body: String| Request::builder().header(header::CONTENT_TYPE, "application/x-ndjson").body(Body::from(body)).unwrap();
+    let header_line = json!({"mjml": mjml}).to_string();
+    let long = json!({"name": "x".repeat(300)}).to_string();
+    let body = format!("{}\n{}\n{}\n{}\n", header_line, "{\"name\": \"Ada\"}\n".repeat(20), long, "{\"name\": \"Bob\"}");
+    let lines = batch_lines(&body_string(convert_batch(State(app_state.clone()), ndjson(body)).await.unwrap()).await);
+    assert_eq!(lines.len(), 21);
+    assert_eq!(lines[19]["result"]["text"], "Hello Ada");
+    assert_eq!(lines[20]["ok"], false);
+    assert!(lines[20]["error"].as_str().unwrap().contains("larger than the limit of 200 bytes"));
+    // A header line without an end.
+    let (status, _) = convert_batch(State(app_state.clone()), ndjson("{\"mjml\": \"".to_string() + &"x".repeat(300))).await.unwrap_err();
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+    // Inline MJML that doesn't compile is the client's mistake, as in `/convert`.
+    let request = Request::builder().body(Body::from(json!({"mjml": "{{#if a}}", "payloads": [{}]}).to_string()))?;
+    let (status, _) = convert_batch(State(app_state), request).await.unwrap_err();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[test]
+fn test_encode_quoted_printable() {
+    assert_eq!(encode_quoted_printable("a=b caf\u{e9} \nend\t"), "a=3Db caf=C3=A9=20\r\nend=09");
+
+    let long = "x".repeat(200);
+    let encoded = encode_quoted_printable(&long);
+    let lines: Vec<&str> = encoded.split("\r\n").collect();
+    assert!(lines.iter().all(|line| line.len() <= 76));
+    assert_eq!(lines.iter().map(|line| line.trim_end_matches('=')).collect::<String>(), long);
+}
+
+#[test]
+fn test_html_to_text_lists_and_headings() {
+    let html = r#"<html><body>
+        <h2>Your order</h2>
+        <ul><li>Coffee</li><li>Tea <ol><li>Green</li><li>Black</li></ol></li></ul>
+        <p>Questions? <a href="mailto:help@example.com">help@example.com</a><br>Thanks</p>
+        <div style="display: none">do not show</div>
+    </body></html>"#;
+
+    assert_eq!(
+        html_to_text(html),
+        "Your order\n----------\n\n* Coffee\n* Tea\n  1. Green\n  2. Black\n\nQuestions? help@example.com\nThanks"
+    );
+}
+
+/// Payload strings that used to make mrml reject the expanded MJML.
+const HOSTILE_STRINGS: [&str; 12] = [
+    "a<b", "<mj-text>", "]]>", "&nbsp;", "x\u{1}y", "x\u{b}y", "a\u{0}b", "x\u{fffe}y", "-- -->", "\"q\" 'a' `b` =c", "<!--", "&#0;",
+];
+
+#[test]
+fn test_escape_mjml() {
+    assert_eq!(escape_mjml("a<b & \"c\" 'd' x=`1`"), "a&lt;b &amp; &quot;c&quot; &#39;d&#39; x=`1`");
+    assert_eq!(escape_mjml("a\u{1}b\u{fffe}\tc\n"), "ab\tc\n");
+    assert_eq!(escape_mjml("x -- y --->"), "x -&#45; y -&#45;&#45;&gt;");
+}
+
+#[tokio::test]
+async fn test_payload_strings_cannot_break_mjml() -> Result<(), Box<dyn std::error::Error>> {
+    let app_state = AppState::new(100, PathBuf::from("templates"));
+    let contexts = [
+        "<mjml><mj-head><mj-title>{{v}}</mj-title></mj-head><mj-body><mj-text>{{v}}</mj-text></mj-body></mjml>",
+        "<mjml><mj-body><mj-button href=\"https://example.com/?q={{v}}\" title='{{v}}'>Go</mj-button></mj-body></mjml>",
+        "<mjml><mj-body><!-- note: {{v}} --><mj-text>x</mj-text></mj-body></mjml>",
+        "<mjml><mj-body><mj-text>{{safe_html v}}</mj-text></mj-body></mjml>",
+        // Characters XML forbids are dropped even from raw output.
+        "<mjml><mj-body><mj-text>{{{strip}}}</mj-text></mj-body></mjml>",
+    ];
+    for context in contexts {
+        for value in HOSTILE_STRINGS {
+            let strip = value.replace(['<', '>', '&'], "");
+            let mjml_input = MjmlInput {
+                mjml: Some(context.to_string()),
+                payload: json!({"v": value, "strip": strip}),
+                ..Default::default()
+            };
+            let result = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await;
+            assert!(result.is_ok(), "{:?} in {}: {:?}", value, context, result.err());
+        }
+    }
+
+    let mjml_input = MjmlInput {
+        mjml: Some("<mjml><mj-body><mj-text>{{v}}</mj-text></mj-body></mjml>".to_string()),
+        payload: json!({"v": "Tom & Jerry <3"}),
+        output: Some(OutputFormat::Text),
+        ..Default::default()
+    };
+    let response = convert_mjml(State(app_state), HeaderMap::new(), Json(mjml_input)).await.unwrap();
+    assert_eq!(body_string(response).await, "Tom & Jerry <3");
+    Ok(())
+}
+
+#[test]
+fn test_sanitize_html() {
+    let cases = [
+        ("<b>bold</b> and <i>italic", "<b>bold</b> and <i>italic</i>"),
+        ("<p>one<p>two</b></p>", "<p>one<p>two</p></p>"),
+        ("<strong><em>x</strong>y</em>", "<strong><em>x</em></strong>y"),
+        ("hi<script>alert(1)</script>!", "hi!"),
+        ("<STYLE>p{}</STYLE><p>ok</p>", "<p>ok</p>"),
+        ("<a href=\"https://example.com/?a=1&amp;b=2\" onclick=\"x()\" class=c>link</a>", "<a href=\"https://example.com/?a=1&amp;b=2\">link</a>"),
+        ("<a href=\"java\tscript:alert(1)\">x</a>", "<a>x</a>"),
+        ("<a href='mailto:a@example.com' title=\"Mail 'me'\">m</a>", "<a href=\"mailto:a@example.com\" title=\"Mail &#39;me&#39;\">m</a>"),
+        ("<img src=\"data:image/png;base64,xx\" alt=\"a\"><br>", "<img alt=\"a\" /><br />"),
+        ("<div><blink>text</blink><!-- hidden --></div>", "<div>text</div>"),
+        ("1 < 2 > 0 &nbsp;&copy; &bogus; &#x41;&#0;", "1 &lt; 2 &gt; 0 &#160;&#169; &amp;bogus; &#65;"),
+        ("<a href=\"https://x\"", "&lt;a href=\"https://x\""),
+        ("bad\u{1}char", "badchar"),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(sanitize_html(input), expected, "{:?}", input);
+    }
+}
+
+/// Body of a failed `/convert` as JSON.
+async fn convert_error(app_state: &AppState, mjml_input: MjmlInput) -> Value {
+    let response = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    serde_json::from_str(&body_string(response).await).unwrap()
+}
+
+#[tokio::test]
+async fn test_mjml_errors_point_into_the_template() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("source-map");
+    std::fs::create_dir_all(template_dir.join("partials"))?;
+    std::fs::write(
+        template_dir.join("broken.mjml"),
+        "<mjml>\n  <mj-body>\n    {{#each items}}\n    <mj-text>{{this}}</mj-text>\n    {{/each}}\n    <mj-text align=center>Hi {{name}}</mj-text>\n  </mj-body>\n</mjml>\n",
+    )?;
+    std::fs::write(template_dir.join("partials/footer.mjml"), "<mj-section>\n  <mj-column width=50%><mj-text>Bye</mj-text></mj-column>\n</mj-section>\n")?;
+    std::fs::write(
+        template_dir.join("footer.mjml"),
+        "<mjml>\n  <mj-body>\n    {{#if name}}\n    {{> footer}}\n    {{/if}}\n  </mj-body>\n</mjml>\n",
+    )?;
+    let app_state = AppState::new(100, template_dir);
+    app_state.register_partials();
+    let payload = json!({"name": "Ada", "items": ["a", "b"]});
+
+    // Lines before the error are repeated by {{#each}}, yet it maps to line 6.
+    let error = convert_error(&app_state, MjmlInput {
+        template: Some("broken.mjml".to_string()),
+        payload: payload.clone(),
+        ..Default::default()
+    })
+    .await;
+    assert!(error["error"].as_str().unwrap().starts_with("Invalid MJML input"), "{}", error);
+    let location = &error["location"];
+    assert_eq!(location["file"], "broken.mjml", "{}", error);
+    assert_eq!(location["line"], 6, "{}", error);
+    assert!(location["snippet"].as_str().unwrap().contains("> 6 |     <mj-text align=center>Hi {{name}}</mj-text>"), "{}", error);
+
+    // Text that came from a partial is reported in the partial's file.
+    let error = convert_error(&app_state, MjmlInput {
+        template: Some("footer.mjml".to_string()),
+        payload: payload.clone(),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(error["location"]["file"], "partials/footer.mjml", "{}", error);
+    assert_eq!(error["location"]["line"], 2, "{}", error);
+    assert_eq!(error["location"]["column"], 13, "{}", error);
+
+    let error = convert_error(&app_state, MjmlInput {
+        mjml: Some("<mjml>\n<mj-body>\n<mj-text>{{name}}</mj-text>\n<mj-text align=left>Bye</mj-text>\n</mj-body>\n</mjml>".to_string()),
+        payload,
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(error["location"]["file"], "inline", "{}", error);
+    assert_eq!(error["location"]["line"], 4, "{}", error);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_lines_carry_mjml_error_locations() -> Result<(), Box<dyn std::error::Error>> {
+    let body = json!({
+        "mjml": "<mjml>\n<mj-body>\n<mj-text>Hello</mj-text>\n{{{extra}}}\n</mj-body>\n</mjml>",
+        "payloads": [{"extra": "<mj-text>Ada</mj-text>"}, {"extra": "<mj-text align=left>Bye</mj-text>"}]
+    });
+    let request = Request::builder().body(Body::from(body.to_string()))?;
+    let response = convert_batch(State(AppState::new(100, temp_template_dir("source-map-batch"))), request).await.unwrap();
+    let lines = batch_lines(&body_string(response).await);
+    assert!(lines[0].get("location").is_none());
+    assert_eq!(lines[1]["location"]["file"], "inline", "{}", lines[1]);
+    // Errors in inserted values point at the expression.
+    assert_eq!(lines[1]["location"]["line"], 4, "{}", lines[1]);
+    assert_eq!(lines[1]["location"]["column"], 1, "{}", lines[1]);
+    Ok(())
+}
+
+async fn debug_convert(app_state: &AppState, token: Option<&str>, mjml_input: MjmlInput) -> Result<(StatusCode, Value), (StatusCode, String)> {
+    let mut headers = HeaderMap::new();
+    if let Some(token) = token {
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+    }
+    let mjml_input = MjmlInput { debug: true, ..mjml_input };
+    let response = convert_mjml(State(app_state.clone()), headers, Json(mjml_input)).await?;
+    let status = response.status();
+    Ok((status, serde_json::from_str(&body_string(response).await).unwrap()))
+}
+
+#[tokio::test]
+async fn test_debug_mode_returns_every_stage() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("debug");
+    std::fs::write(template_dir.join("hello.mjml"), "<mjml><mj-body><mj-text>Hi {{name}}</mj-text></mj-body></mjml>")?;
+    std::fs::write(template_dir.join("static.mjml"), "<mjml><mj-body><mj-text>Hi</mj-text></mj-body></mjml>")?;
+    std::fs::write(template_dir.join("broken.mjml"), "<mjml>\n<mj-body>\n<mj-text align=left>{{name}}</mj-text>\n</mj-body>\n</mjml>")?;
+    let config = ServerConfig { admin_token: Some("secret".to_string()), ..Default::default() };
+    let app_state = AppState::with_config(100, template_dir.clone(), config);
+    let input = |template: &str| MjmlInput {
+        template: Some(template.to_string()),
+        payload: json!({"name": "Ada"}),
+        ..Default::default()
+    };
+
+    let (status, _) = debug_convert(&app_state, None, input("hello.mjml")).await.unwrap_err();
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = debug_convert(&app_state, Some("wrong"), input("hello.mjml")).await.unwrap_err();
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = debug_convert(&AppState::new(100, template_dir), Some("secret"), input("hello.mjml")).await.unwrap_err();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, report) = debug_convert(&app_state, Some("secret"), input("hello.mjml")).await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(report["template"], "hello.mjml");
+    assert_eq!(report["cache"], "disk");
+    assert_eq!(report["prerendered"], false);
+    assert_eq!(report["stages"]["template"], "<mjml><mj-body><mj-text>Hi {{name}}</mj-text></mj-body></mjml>");
+    assert_eq!(report["stages"]["mjml"], "<mjml><mj-body><mj-text>Hi Ada</mj-text></mj-body></mjml>");
+    assert_eq!(report["stages"]["ast"]["type"], "mjml", "{}", report["stages"]["ast"]);
+    assert!(report["stages"]["html"].as_str().unwrap().contains("Hi Ada"));
+    for stage in ["load", "handlebars", "parse", "render", "total"] {
+        assert!(report["timings_ms"][stage].is_number(), "{}", stage);
+    }
+    assert!(report.get("error").is_none());
+
+    let (_, report) = debug_convert(&app_state, Some("secret"), input("hello.mjml")).await.unwrap();
+    assert_eq!(report["cache"], "memory");
+
+    let (_, report) = debug_convert(&app_state, Some("secret"), input("static.mjml")).await.unwrap();
+    assert_eq!(report["prerendered"], true);
+    assert!(report["timings_ms"]["handlebars"].is_null());
+    assert!(report["stages"]["html"].as_str().unwrap().contains("Hi"));
+
+    // A failing stage keeps what came before it.
+    let (status, report) = debug_convert(&app_state, Some("secret"), input("broken.mjml")).await.unwrap();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(report["error"].as_str().unwrap().starts_with("Invalid MJML input"));
+    assert_eq!(report["location"]["line"], 3);
+    assert!(report["stages"]["mjml"].as_str().unwrap().contains("align=left>Ada<"));
+    assert!(report["stages"]["ast"].is_null());
+    assert!(report["timings_ms"]["render"].is_null());
+
+    let (_, report) = debug_convert(&app_state, Some("secret"), MjmlInput {
+        mjml: Some("<mjml><mj-body><mj-text>{{name}}</mj-text></mj-body></mjml>".to_string()),
+        payload: json!({"name": "Ada"}),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    assert!(report["template"].is_null());
+    assert!(report["cache"].is_null());
+    assert!(report["stages"]["html"].as_str().unwrap().contains("Ada"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_template_variables() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("variables");
+    std::fs::create_dir_all(template_dir.join("partials"))?;
+    std::fs::write(template_dir.join("partials/card.mjml"), "<mj-text>{{title}}: {{sku}} {{note}}</mj-text>")?;
+    std::fs::write(
+        template_dir.join("order.mjml"),
+        concat!(
+            "<mjml><mj-body>\n",
+            "<mj-text>Hi {{customer.name}}, {{format_date placed_at \"%d\" tz=customer.timezone}}</mj-text>\n",
+            "{{#if vip}}<mj-text>VIP</mj-text>{{/if}}\n",
+            "{{#each items as |item i|}}\n",
+            "  {{> card title=item.name note=\"new\"}}\n",
+            "  <mj-text>{{i}} {{@index}} {{format_currency item.price ../currency}} {{@root.customer.email}}</mj-text>\n",
+            "{{else}}<mj-text>{{empty_message}}</mj-text>{{/each}}\n",
+            "{{#with shipping}}<mj-text>{{address.city}} {{#each tags}}{{this}}{{/each}}</mj-text>{{/with}}\n",
+            "<mj-text>{{pluralize (lookup counts \"items\") \"item\" \"items\"}}</mj-text>\n",
+            "</mj-body></mjml>\n",
+        ),
+    )?;
+    let app_state = AppState::new(100, template_dir);
+    app_state.register_partials();
+
+    let response = get_file(&app_state, "order.mjml/variables").await.unwrap();
+    let body: Value = serde_json::from_str(&body_string(response).await)?;
+    assert_eq!(
+        body["variables"],
+        json!([
+            "counts", "currency", "customer.email", "customer.name", "customer.timezone", "empty_message",
+            "items", "items[].name", "items[].price", "items[].sku", "placed_at",
+            "shipping", "shipping.address.city", "shipping.tags", "shipping.tags[]", "vip",
+        ])
+    );
+    assert_eq!(body["example"]["items"], json!([{"name": "", "price": "", "sku": ""}]));
+    assert_eq!(body["example"]["vip"], true);
+    assert_eq!(body["schema"]["properties"]["shipping"]["properties"]["tags"]["type"], "array");
+    assert!(!body["schema"]["required"].as_array().unwrap().contains(&json!("vip")));
+
+    // The example payload satisfies the draft schema.
+    let schema = Schema::compile(body["schema"].clone())?;
+    assert_eq!(schema.validate(&body["example"]), Vec::<Violation>::new());
+
+    let (status, _) = get_file(&app_state, "missing.mjml/variables").await.unwrap_err();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    // Anything else below a template is a file that can't exist.
+    let (status, _) = get_file(&app_state, "order.mjml/other").await.unwrap_err();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+async fn get_file(app_state: &AppState, path_and_query: &str) -> Result<Response, (StatusCode, String)> {
+    let uri: Uri = format!("/templates/{}", path_and_query).parse().unwrap();
+    let path = uri.path().strip_prefix("/templates/").unwrap().to_string();
+    get_template_file(State(app_state.clone()), AxumPath(path), uri).await
+}
+
+/// Config enabling the admin endpoints, with the token of [`admin_headers`].
+fn admin_config() -> ServerConfig {
+    ServerConfig { admin_token: Some("secret".to_string()), ..Default::default() }
+}
+
+fn admin_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+    headers
+}
+
+/// Admin headers that also let an upload replace whatever it finds.
+fn upload_headers() -> HeaderMap {
+    let mut headers = admin_headers();
+    headers.insert(header::IF_MATCH, "*".parse().unwrap());
+    headers
+}
+
+async fn put_template(app_state: &AppState, name: &str, source: &str, if_match: Option<&str>) -> Result<Response, (StatusCode, String)> {
+    let mut headers = admin_headers();
+    if let Some(if_match) = if_match {
+        headers.insert(header::IF_MATCH, if_match.parse().unwrap());
+    }
+    put_template_file(State(app_state.clone()), AxumPath(name.to_string()), headers, source.to_string()).await
+}
+
+#[tokio::test]
+async fn test_template_lifecycle() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("lifecycle");
+    let app_state = AppState::with_config(100, template_dir.clone(), admin_config());
+    let hello = "<mjml><mj-body><mj-text>Hello {{name}}</mj-text></mj-body></mjml>";
+
+    let response = put_template(&app_state, "mail/hello.mjml", hello, None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created_etag = response.headers()[header::ETAG].to_str()?.to_string();
+    assert_eq!(convert_text(&app_state, "mail/hello.mjml", json!({"name": "Ada"})).await?, "Hello Ada");
+
+    let response = get_file(&app_state, "mail/hello.mjml").await.unwrap();
+    assert_eq!(response.headers()[header::ETAG].to_str()?, created_etag);
+    assert_eq!(body_string(response).await, hello);
+
+    // Replacing needs the current ETag, and takes effect on the next render
+    // without waiting for the watcher.
+    let bye = "<mjml><mj-body><mj-text>Bye {{name}}</mj-text></mj-body></mjml>";
+    let (status, _) = put_template(&app_state, "mail/hello.mjml", bye, None).await.unwrap_err();
+    assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+    let (status, _) = put_template(&app_state, "mail/hello.mjml", bye, Some("\"outdated\"")).await.unwrap_err();
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    let response = put_template(&app_state, "mail/hello.mjml", bye, Some(&created_etag)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.headers()[header::ETAG].to_str()?, created_etag);
+    assert_eq!(convert_text(&app_state, "mail/hello.mjml", json!({"name": "Ada"})).await?, "Bye Ada");
+    let (status, _) = put_template(&app_state, "mail/hello.mjml", hello, Some(&created_etag)).await.unwrap_err();
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+
+    let (status, _) = put_template(&app_state, "broken.mjml", "{{#if}}", None).await.unwrap_err();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(!template_dir.join("broken.mjml").exists());
+    let (status, _) = put_template(&app_state, "../outside.mjml", hello, None).await.unwrap_err();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    // Names that would read as the endpoints below a template.
+    for name in ["mail/variables", "mail/revisions", "mail/revisions/3", "mail/diff", "mail/rollback"] {
+        let (status, _) = put_template(&app_state, name, hello, None).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", name);
+    }
+    let response = put_template(&app_state, "diff", "plain", None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(body_string(get_file(&app_state, "diff").await.unwrap()).await, "plain");
+
+    // Partials are registered again right away, and templates using them
+    // are dropped from the cache.
+    put_template(&app_state, "partials/sign.mjml", "<mj-text>Acme</mj-text>", None).await.unwrap();
+    put_template(&app_state, "signed.mjml", "<mjml><mj-body>{{> sign}}</mj-body></mjml>", None).await.unwrap();
+    assert_eq!(convert_text(&app_state, "signed.mjml", json!({})).await?, "Acme");
+    put_template(&app_state, "partials/sign.mjml", "<mj-text>Acme Inc.</mj-text>", Some("*")).await.unwrap();
+    assert_eq!(convert_text(&app_state, "signed.mjml", json!({})).await?, "Acme Inc.");
+
+    let delete = |name: &str, if_match: Option<&str>| {
+        let mut headers = admin_headers();
+        if let Some(if_match) = if_match {
+            headers.insert(header::IF_MATCH, if_match.parse().unwrap());
+        }
+        delete_template_file(State(app_state.clone()), AxumPath(name.to_string()), headers)
+    };
+    let (status, _) = delete("mail/hello.mjml", Some(&created_etag)).await.unwrap_err();
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(delete("mail/hello.mjml", None).await.unwrap().status(), StatusCode::NO_CONTENT);
+    assert!(convert_text(&app_state, "mail/hello.mjml", json!({})).await.is_err());
+    let (status, _) = delete("mail/hello.mjml", None).await.unwrap_err();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = get_file(&app_state, "mail/hello.mjml").await.unwrap_err();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_template_writes_need_the_admin_token() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("write-auth");
+    let hello = "<mjml><mj-body><mj-text>Hello</mj-text></mj-body></mjml>";
+    std::fs::write(template_dir.join("hello.mjml"), hello)?;
+    let app_state = AppState::with_config(100, template_dir.clone(), admin_config());
+
+    for authorization in [None, Some("Bearer wrong"), Some("secret")] {
+        let headers = || {
+            let mut headers = HeaderMap::new();
+            if let Some(authorization) = authorization {
+                headers.insert(header::AUTHORIZATION, authorization.parse().unwrap());
+            }
+            headers.insert(header::IF_MATCH, "*".parse().unwrap());
+            headers
+        };
+        let name = || AxumPath("hello.mjml".to_string());
+        let (status, _) = put_template_file(State(app_state.clone()), name(), headers(), "forged".to_string()).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = delete_template_file(State(app_state.clone()), name(), headers()).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let rollback = AxumPath("hello.mjml/rollback".to_string());
+        let (status, _) = post_template_file(State(app_state.clone()), rollback, headers(), json!({"rev": 1}).to_string().into()).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let forged = "<mjml><mj-body><mj-text>forged</mj-text></mj-body></mjml>";
+        let (status, _) = upload_template(State(app_state.clone()), headers(), multipart(&[("hello.mjml", forged)]).await).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(std::fs::read_to_string(template_dir.join("hello.mjml"))?, hello);
+
+    // Uploads replace files on the same terms as `PUT`.
+    let replacement = "<mjml><mj-body><mj-text>Hi</mj-text></mj-body></mjml>";
+    let upload = |if_match: Option<String>| {
+        let app_state = app_state.clone();
+        async move {
+            let mut headers = admin_headers();
+            if let Some(if_match) = if_match {
+                headers.insert(header::IF_MATCH, if_match.parse().unwrap());
+            }
+            upload_template(State(app_state), headers, multipart(&[("hello.mjml", replacement), ("new.mjml", replacement)]).await).await
+        }
+    };
+    let (status, _) = upload(None).await.unwrap_err();
+    assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+    let (status, _) = upload(Some("\"stale\"".to_string())).await.unwrap_err();
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(std::fs::read_to_string(template_dir.join("hello.mjml"))?, hello);
+    assert!(!template_dir.join("new.mjml").exists());
+    let current = crate::utils::content_hash(hello.as_bytes());
+    assert_eq!(upload(Some(format!("\"other\", \"{}\"", current))).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(std::fs::read_to_string(template_dir.join("hello.mjml"))?, replacement);
+
+    // Without an admin token there is no way to write.
+    let app_state = AppState::new(100, template_dir);
+    let (status, _) = put_template(&app_state, "new.mjml", hello, None).await.unwrap_err();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = upload_template(State(app_state), HeaderMap::new(), multipart(&[("other.mjml", hello)]).await).await.unwrap_err();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_put_keeps_the_file_when_its_history_fails() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("put-history-fails");
+    let hello = "<mjml><mj-body><mj-text>Hello</mj-text></mj-body></mjml>";
+    std::fs::write(template_dir.join("hello.mjml"), hello)?;
+    // A file where the history folders should be makes recording fail.
+    std::fs::create_dir_all(template_dir.join(".history"))?;
+    std::fs::write(template_dir.join(".history/hello.mjml"), "")?;
+    std::fs::write(template_dir.join(".history/new.mjml"), "")?;
+    let app_state = AppState::with_config(100, template_dir.clone(), admin_config());
+    assert_eq!(convert_text(&app_state, "hello.mjml", json!({})).await?, "Hello");
+
+    let bye = "<mjml><mj-body><mj-text>Bye</mj-text></mj-body></mjml>";
+    let (status, _) = put_template(&app_state, "hello.mjml", bye, Some("*")).await.unwrap_err();
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(std::fs::read_to_string(template_dir.join("hello.mjml"))?, hello);
+    assert_eq!(convert_text(&app_state, "hello.mjml", json!({})).await?, "Hello");
+
+    let (status, _) = put_template(&app_state, "new.mjml", bye, None).await.unwrap_err();
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(!template_dir.join("new.mjml").exists());
+    Ok(())
+}
+
+async fn list(app_state: &AppState, query: ListQuery) -> Result<Value, (StatusCode, String)> {
+    let response = list_templates(State(app_state.clone()), Query(query)).await?;
+    Ok(serde_json::from_str(&body_string(response).await).unwrap())
+}
+
+#[tokio::test]
+async fn test_list_templates_recursively() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("listing");
+    std::fs::create_dir_all(template_dir.join("billing/eu"))?;
+    std::fs::create_dir_all(template_dir.join("partials"))?;
+    let welcome = "{{!-- tags: onboarding, marketing --}}<mjml><mj-body><mj-text>Hi</mj-text></mj-body></mjml>";
+    std::fs::write(template_dir.join("welcome.mjml"), welcome)?;
+    std::fs::write(template_dir.join("billing/receipt.mjml"), "{{! tags: billing }}<mjml><mj-body></mj-body></mjml>")?;
+    std::fs::write(template_dir.join("billing/eu/invoice.mjml"), "<mjml><mj-body></mj-body></mjml>")?;
+    std::fs::write(template_dir.join("partials/footer.mjml"), "<mj-text>Bye</mj-text>")?;
+    std::fs::write(template_dir.join("welcome.fr.json"), "{}")?;
+    std::fs::write(template_dir.join("notes.txt"), "not a template")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        std::fs::write(template_dir.join(std::ffi::OsStr::from_bytes(b"caf\xe9.mjml")), "<mjml></mjml>")?;
+    }
+    let app_state = AppState::new(100, template_dir.clone());
+    convert_text(&app_state, "welcome.mjml", json!({})).await?;
+
+    let listing = list(&app_state, ListQuery::default()).await.unwrap();
+    let names: Vec<&str> = listing["templates"].as_array().unwrap().iter().map(|entry| entry["name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["billing/eu/invoice.mjml", "billing/receipt.mjml", "partials/footer.mjml", "welcome.mjml"]);
+    assert_eq!(listing["next_cursor"], Value::Null);
+    let welcome_entry = &listing["templates"][3];
+    assert_eq!(welcome_entry["kind"], "template");
+    assert_eq!(welcome_entry["size"], welcome.len());
+    assert_eq!(welcome_entry["hash"], crate::utils::content_hash(welcome.as_bytes()));
+    assert_eq!(welcome_entry["tags"], json!(["onboarding", "marketing"]));
+    assert_eq!(welcome_entry["cached"], true);
+    assert!(welcome_entry["modified"].as_str().unwrap().ends_with('Z'));
+    assert_eq!(listing["templates"][0]["cached"], false);
+    assert_eq!(listing["templates"][2]["kind"], "partial");
+
+    let listing = list(&app_state, ListQuery { prefix: Some("billing/".to_string()), ..Default::default() }).await.unwrap();
+    assert_eq!(listing["templates"].as_array().unwrap().len(), 2);
+    let listing = list(&app_state, ListQuery { tag: Some("billing".to_string()), ..Default::default() }).await.unwrap();
+    assert_eq!(listing["templates"][0]["name"], "billing/receipt.mjml");
+    assert_eq!(listing["templates"].as_array().unwrap().len(), 1);
+
+    // Pages follow each other until the cursor runs out.
+    let mut names = Vec::new();
+    let mut cursor = None;
+    loop {
+        let listing = list(&app_state, ListQuery { limit: Some(3), cursor, ..Default::default() }).await.unwrap();
+        names.extend(listing["templates"].as_array().unwrap().iter().map(|entry| entry["name"].as_str().unwrap().to_string()));
+        match listing["next_cursor"].as_str() {
+            Some(next) => cursor = Some(next.to_string()),
+            None => break,
+        }
+    }
+    assert_eq!(names.len(), 4);
+    assert_eq!(names[3], "welcome.mjml");
+
+    let (status, _) = list(&app_state, ListQuery { limit: Some(0), ..Default::default() }).await.unwrap_err();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+async fn revisions(app_state: &AppState, name: &str) -> Value {
+    let response = get_file(app_state, &format!("{}/revisions", name)).await.unwrap();
+    serde_json::from_str(&body_string(response).await).unwrap()
+}
+
+#[tokio::test]
+async fn test_template_history() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("history");
+    let text = |greeting: &str| format!("<mjml><mj-body><mj-text>{} {{{{name}}}}</mj-text></mj-body></mjml>", greeting);
+    std::fs::write(template_dir.join("welcome.mjml"), text("Hello"))?;
+    let app_state = AppState::with_config(100, template_dir.clone(), admin_config());
+
+    // The first change keeps the content the file had before.
+    let mut headers = admin_headers();
+    headers.insert(header::IF_MATCH, "*".parse()?);
+    headers.insert("x-uploader", "ada".parse()?);
+    put_template_file(State(app_state.clone()), AxumPath("welcome.mjml".to_string()), headers, text("Hi")).await.unwrap();
+    let mut headers = upload_headers();
+    headers.insert("x-uploader", "bob".parse()?);
+    upload_template(State(app_state.clone()), headers, multipart(&[("welcome.mjml", &text("Hey"))]).await).await.unwrap();
+
+    let history = revisions(&app_state, "welcome.mjml").await;
+    assert_eq!(history["template"], "welcome.mjml");
+    let uploaders: Vec<&Value> = history["revisions"].as_array().unwrap().iter().map(|revision| &revision["uploader"]).collect();
+    assert_eq!(uploaders, [&Value::Null, &json!("ada"), &json!("bob")]);
+    assert_eq!(history["revisions"][1]["hash"], crate::utils::content_hash(text("Hi").as_bytes()));
+    assert_eq!(body_string(get_file(&app_state, "welcome.mjml/revisions/2").await.unwrap()).await, text("Hi"));
+
+    // Revisions render through `name@rev`, next to the current template.
+    assert_eq!(convert_text(&app_state, "welcome.mjml", json!({"name": "Ada"})).await?, "Hey Ada");
+    assert_eq!(convert_text(&app_state, "welcome.mjml@1", json!({"name": "Ada"})).await?, "Hello Ada");
+    let mjml_input = MjmlInput { template: Some("welcome.mjml@9".to_string()), ..Default::default() };
+    let (status, _) = convert_mjml(State(app_state.clone()), HeaderMap::new(), Json(mjml_input)).await.unwrap_err();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let diff = body_string(get_file(&app_state, "welcome.mjml/diff?from=1&to=2").await.unwrap()).await;
+    assert_eq!(diff, format!("--- welcome.mjml@1\n+++ welcome.mjml@2\n@@ -1,1 +1,1 @@\n-{}\n+{}\n", text("Hello"), text("Hi")));
+    let diff = body_string(get_file(&app_state, "welcome.mjml/diff?from=3").await.unwrap()).await;
+    assert_eq!(diff, "");
+    let (status, _) = get_file(&app_state, "welcome.mjml/diff?from=1&to=7").await.unwrap_err();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let rollback = |rev: u64, if_match: Option<&str>| {
+        let mut headers = admin_headers();
+        if let Some(if_match) = if_match {
+            headers.insert(header::IF_MATCH, if_match.parse().unwrap());
+        }
+        let body = json!({"rev": rev}).to_string();
+        post_template_file(State(app_state.clone()), AxumPath("welcome.mjml/rollback".to_string()), headers, body.into())
+    };
+    let (status, _) = rollback(1, Some("\"outdated\"")).await.unwrap_err();
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    let (status, _) = rollback(9, None).await.unwrap_err();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let response = rollback(1, None).await.unwrap();
+    let revision: Value = serde_json::from_str(&body_string(response).await)?;
+    assert_eq!((revision["rev"].as_u64(), revision["rollback_of"].as_u64()), (Some(4), Some(1)));
+    assert_eq!(convert_text(&app_state, "welcome.mjml", json!({"name": "Ada"})).await?, "Hello Ada");
+    assert_eq!(revisions(&app_state, "welcome.mjml").await["revisions"].as_array().unwrap().len(), 4);
+
+    // The history can't be reached as templates.
+    let (status, _) = put_template(&app_state, ".history/welcome.mjml/5", "forged", None).await.unwrap_err();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = get_file(&app_state, ".history/welcome.mjml/1").await.unwrap_err();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let listing = list(&app_state, ListQuery::default()).await.unwrap();
+    assert_eq!(listing["templates"].as_array().unwrap().len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_unified_diff_hunks() {
+    let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\nn\n";
+    let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nn\n";
+    let diff = crate::history::unified_diff(old, new, "old", "new").unwrap();
+    assert_eq!(
+        diff,
+        "--- old\n+++ new\n@@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n@@ -10,5 +10,4 @@\n j\n k\n l\n-m\n n\n"
+    );
+}
+
+#[tokio::test]
+async fn test_diff_refuses_too_many_changed_lines() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("diff-limit");
+    let app_state = AppState::new(100, template_dir.clone());
+    let id = TemplateId::new("big.mjml")?;
+    let text = |prefix: &str, changed: usize| {
+        let same: String = (0..5000).map(|line| format!("same {}\n", line)).collect();
+        let middle: String = (0..changed).map(|line| format!("{} {}\n", prefix, line)).collect();
+        format!("{}{}{}", same, middle, same)
+    };
+    crate::history::record(&template_dir, &id, None, &text("old", 3000), None, None)?;
+    crate::history::record(&template_dir, &id, None, &text("new", 3000), None, None)?;
+    crate::history::record(&template_dir, &id, None, &text("new", 4000), None, None)?;
+
+    // Lines both revisions start and end with don't count.
+    let diff = body_string(get_file(&app_state, "big.mjml/diff?from=1&to=2").await.unwrap()).await;
+    assert_eq!(diff.lines().filter(|line| line.starts_with('+') && !line.starts_with("+++")).count(), 3000);
+    let (status, message) = get_file(&app_state, "big.mjml/diff?from=1&to=3").await.unwrap_err();
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(message.contains("3000 old and 4000 new"), "{}", message);
+    Ok(())
+}
+
+/// Names in `dir` other than the history, to spot leftover temporary files.
+fn dir_entries(dir: &std::path::Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name != ".history")
+        .collect();
+    names.sort();
+    names
+}
+
+#[tokio::test]
+async fn test_uploads_are_all_or_nothing() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("upload-limits");
+    let original = "<mjml><mj-body><mj-text>original</mj-text></mj-body></mjml>";
+    std::fs::write(template_dir.join("a.mjml"), original)?;
+    let config = ServerConfig { max_file_bytes: Some(100), max_upload_bytes: Some(150), ..admin_config() };
+    let app_state = AppState::with_config(100, template_dir.clone(), config);
+    let upload = |files: Vec<(&'static str, String)>| {
+        let app_state = app_state.clone();
+        async move {
+            let files: Vec<(&str, &str)> = files.iter().map(|(name, content)| (*name, content.as_str())).collect();
+            upload_template(State(app_state), upload_headers(), multipart(&files).await).await
+        }
+    };
+    let small = |text: &str| format!("<mjml><mj-body><mj-text>{}</mj-text></mj-body></mjml>", text);
+
+    // One bad file keeps the others from being written.
+    let response = upload(vec![("a.mjml", small("new")), ("b.mjml", "<mjml><mj-body><mj-text align=center>b</mj-text></mj-body></mjml>".to_string())]).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let (status, _) = upload(vec![("a.mjml", small("new")), ("./a.mjml", small("again"))]).await.unwrap_err();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = upload(vec![("b.mjml", small("new")), ("c.mjml", "x".repeat(101))]).await.unwrap_err();
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    // Each file fits, together they don't.
+    let (status, _) = upload(vec![("b.mjml", small(&"y".repeat(20))), ("c.mjml", small(&"x".repeat(45)))]).await.unwrap_err();
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(std::fs::read_to_string(template_dir.join("a.mjml"))?, original);
+    assert_eq!(dir_entries(&template_dir), ["a.mjml"]);
+
+    let response = upload(vec![("a.mjml", small("new")), ("b.mjml", small("b"))]).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(convert_text(&app_state, "a.mjml", json!({})).await?, "new");
+    assert_eq!(dir_entries(&template_dir), ["a.mjml", "b.mjml"]);
+
+    let (status, _) = put_template(&app_state, "b.mjml", &small(&"x".repeat(60)), Some("*")).await.unwrap_err();
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_uploads_are_undone_when_their_history_fails() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("upload-history-fails");
+    let text = |greeting: &str| format!("<mjml><mj-body><mj-text>{}</mj-text></mj-body></mjml>", greeting);
+    std::fs::write(template_dir.join("a.mjml"), text("original"))?;
+    // A file where the history folder of b.mjml should be makes recording it fail.
+    std::fs::create_dir_all(template_dir.join(".history"))?;
+    std::fs::write(template_dir.join(".history/b.mjml"), "")?;
+    let app_state = AppState::with_config(100, template_dir.clone(), admin_config());
+    assert_eq!(convert_text(&app_state, "a.mjml", json!({})).await?, "original");
+
+    let files = [("a.mjml", text("new")), ("b.mjml", text("b"))];
+    let files: Vec<(&str, &str)> = files.iter().map(|(name, content)| (*name, content.as_str())).collect();
+    let (status, _) = upload_template(State(app_state.clone()), upload_headers(), multipart(&files).await).await.unwrap_err();
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(dir_entries(&template_dir), ["a.mjml"]);
+    assert_eq!(convert_text(&app_state, "a.mjml", json!({})).await?, "original");
+    // Only the content a.mjml had before is left in its history.
+    let history = revisions(&app_state, "a.mjml").await;
+    assert_eq!(history["revisions"].as_array().unwrap().len(), 1);
+    assert_eq!(body_string(get_file(&app_state, "a.mjml/revisions/1").await.unwrap()).await, text("original"));
+
+    // Once the history can be written, the same upload goes through.
+    std::fs::remove_file(template_dir.join(".history/b.mjml"))?;
+    let response = upload_template(State(app_state.clone()), upload_headers(), multipart(&files).await).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(convert_text(&app_state, "a.mjml", json!({})).await?, "new");
+    assert_eq!(revisions(&app_state, "a.mjml").await["revisions"].as_array().unwrap().len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_staged_files_appear_on_commit() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = temp_template_dir("staged");
+    let path = dir.join("a.mjml");
+    std::fs::write(&path, "old")?;
+
+    let staged = StagedFile::write(&path, b"new").await?;
+    assert_eq!(std::fs::read_to_string(&path)?, "old");
+    assert_eq!(dir_entries(&dir).len(), 2);
+    drop(staged);
+    assert_eq!(dir_entries(&dir), ["a.mjml"]);
+
+    StagedFile::write(&path, b"new").await?.commit().await?;
+    assert_eq!(std::fs::read_to_string(&path)?, "new");
+    assert_eq!(dir_entries(&dir), ["a.mjml"]);
+    Ok(())
+}
+
+async fn upload_report(app_state: &AppState, files: &[(&str, &str)]) -> (StatusCode, Value) {
+    let response = upload_template(State(app_state.clone()), upload_headers(), multipart(files).await).await.unwrap();
+    let status = response.status();
+    (status, serde_json::from_str(&body_string(response).await).unwrap())
+}
+
+#[tokio::test]
+async fn test_uploads_render_their_fixtures() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("upload-fixtures");
+    let app_state = AppState::with_config(100, template_dir.clone(), admin_config());
+
+    // Not MJML until Handlebars has run, and fine once it has.
+    let themed = "<mjml><mj-body><mj-section {{#if dark}}background-color=\"#000\"{{/if}}><mj-column><mj-text>Hi {{name}}</mj-text></mj-column></mj-section></mj-body></mjml>";
+    let (status, report) = upload_report(&app_state, &[("themed.mjml", themed)]).await;
+    assert_eq!(status, StatusCode::OK, "{}", report);
+    assert_eq!(report["uploaded"], json!(["themed.mjml"]));
+    assert_eq!(report["templates"][0]["fixtures_from"], "example");
+
+    let (status, report) = upload_report(&app_state, &[("broken.mjml", "<mjml>{{#if ready}}</mjml>")]).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(report["templates"][0]["error"].as_str().unwrap().starts_with("Handlebars template error in broken.mjml"));
+    assert!(!template_dir.join("broken.mjml").exists());
+
+    // Every fixture is reported; one failing refuses the upload.
+    let letter = "<mjml><mj-body>\n{{{body}}}\n</mj-body></mjml>";
+    let fixtures = r#"{"plain": {"body": "<mj-text>Hi</mj-text>"}, "unquoted": {"body": "<mj-text align=center>Hi</mj-text>"}}"#;
+    let (status, report) = upload_report(&app_state, &[("letter.mjml", letter), ("letter.fixtures.json", fixtures)]).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(report["error"], "1 of 1 templates failed validation");
+    let letter_report = &report["templates"][0];
+    assert_eq!(letter_report["fixtures_from"], "upload");
+    assert_eq!(letter_report["fixtures"][0]["name"], "plain");
+    assert_eq!(letter_report["fixtures"][0]["ok"], true);
+    assert_eq!(letter_report["fixtures"][1]["ok"], false);
+    assert!(letter_report["fixtures"][1]["error"].as_str().unwrap().starts_with("Invalid MJML input"));
+    assert_eq!(letter_report["fixtures"][1]["location"]["line"], 2);
+    assert!(!template_dir.join("letter.mjml").exists());
+    assert!(!template_dir.join("letter.fixtures.json").exists());
+
+    let fixtures = r#"{"plain": {"body": "<mj-text>Hi</mj-text>"}}"#;
+    let (status, _) = upload_report(&app_state, &[("letter.mjml", letter), ("letter.fixtures.json", fixtures)]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(template_dir.join("letter.fixtures.json").exists());
+    // Stored fixtures check later uploads of the template, and uploaded
+    // fixtures the stored template.
+    let (status, report) = upload_report(&app_state, &[("letter.mjml", letter)]).await;
+    assert_eq!((status, &report["templates"][0]["fixtures_from"]), (StatusCode::OK, &json!("stored")));
+    let fixtures = r#"{"unquoted": {"body": "<mj-text align=center>Hi</mj-text>"}}"#;
+    let (status, report) = upload_report(&app_state, &[("letter.fixtures.json", fixtures)]).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(report["templates"][0]["template"], "letter.mjml");
+    let (status, _) = upload_template(State(app_state.clone()), upload_headers(), multipart(&[("letter.fixtures.json", "[]")]).await)
+        .await
+        .map(|_| ())
+        .unwrap_err();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Templates render against the partials uploaded with them.
+    let signed = "<mjml><mj-body>{{> signature}}</mj-body></mjml>";
+    let (status, report) = upload_report(&app_state, &[("signed.mjml", signed), ("partials/signature.mjml", "<mj-text>Acme</mj-text>")]).await;
+    assert_eq!(status, StatusCode::OK, "{}", report);
+    assert_eq!(report["templates"][1]["fixtures_from"], Value::Null);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_uploads_without_fixtures_warn_when_helpers_refuse_the_example() -> Result<(), Box<dyn std::error::Error>> {
+    let template_dir = temp_template_dir("upload-example-helpers");
+    let app_state = AppState::with_config(100, template_dir.clone(), admin_config());
+
+    // The example payload has `""` for `total` and `sent`, which these refuse.
+    let invoice = "<mjml><mj-body><mj-section><mj-column><mj-text>{{format_number total}} {{format_currency total \"EUR\"}} {{format_date sent}}</mj-text></mj-column></mj-section></mj-body></mjml>";
+    let (status, report) = upload_report(&app_state, &[("invoice.mjml", invoice)]).await;
+    assert_eq!(status, StatusCode::OK, "{}", report);
+    let invoice_report = &report["templates"][0];
+    assert_eq!(invoice_report["fixtures_from"], "example");
+    assert_eq!(invoice_report["fixtures"][0]["ok"], true);
+    assert!(invoice_report["fixtures"][0]["warning"].as_str().unwrap().contains("example payload"));
+    assert!(template_dir.join("invoice.mjml").exists());
+
+    // MJML the example payload renders to is still checked.
+    let (status, report) = upload_report(&app_state, &[("bad.mjml", "<mjml><mj-body><mj-text align=center>{{name}}</mj-text></mj-body></mjml>")]).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", report);
+    assert_eq!(report["templates"][0]["fixtures"][0]["ok"], false);
+    Ok(())
+}
@@ -0,0 +1,234 @@
+use select::document::Document;
+use select::node::Node;
+
+/// Elements whose content never belongs in the text part.
+const SKIPPED_ELEMENTS: &[&str] = &["head", "style", "script", "title", "input", "label"];
+
+/// Elements that start and end on their own line.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "div", "section", "article", "header", "footer", "table", "tbody", "thead", "tfoot", "tr",
+    "center", "blockquote", "ul", "ol",
+];
+
+/// Converts the HTML produced by mrml into a readable text/plain alternative.
+///
+/// Links are numbered and listed as footnotes at the end, list items get
+/// bullets (or numbers inside `<ol>`), `<h1>`/`<h2>`... are underlined and
+/// hidden content such as the `<mj-preview>` preheader is skipped.
+pub fn html_to_text(html: &str) -> String {
+    let document = Document::from(html);
+    let mut writer = TextWriter::default();
+    if let Some(root) = document.nth(0) {
+        writer.walk_children(&root);
+    }
+    writer.finish()
+}
+
+#[derive(Default)]
+struct TextWriter {
+    out: String,
+    links: Vec<String>,
+    pending_space: bool,
+    /// One entry per open list: `None` for `<ul>`, the next number for `<ol>`.
+    lists: Vec<Option<usize>>,
+}
+
+impl TextWriter {
+    fn walk_children(&mut self, node: &Node) {
+        for child in node.children() {
+            self.walk(&child);
+        }
+    }
+
+    fn walk(&mut self, node: &Node) {
+        if let Some(text) = node.as_text() {
+            self.push_text(text);
+            return;
+        }
+        let name = match node.name() {
+            Some(name) => name,
+            // Comments (including mso conditionals) and doctypes carry no text.
+            None => return,
+        };
+        if SKIPPED_ELEMENTS.contains(&name) || is_hidden(node) {
+            return;
+        }
+
+        match name {
+            "br" => self.line_break(),
+            "hr" => {
+                self.block_break(2);
+                self.out.push_str(&"-".repeat(40));
+                self.block_break(2);
+            }
+            "p" => {
+                self.block_break(2);
+                self.walk_children(node);
+                self.block_break(2);
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => self.heading(node, name),
+            "ul" => self.list(node, None),
+            "ol" => self.list(node, Some(1)),
+            "li" => self.list_item(node),
+            "a" => self.link(node),
+            "img" => {
+                if let Some(alt) = node.attr("alt").map(str::trim).filter(|alt| !alt.is_empty()) {
+                    self.push_text(alt);
+                }
+            }
+            "td" | "th" => {
+                self.pending_space = true;
+                self.walk_children(node);
+                self.pending_space = true;
+            }
+            _ if BLOCK_ELEMENTS.contains(&name) => {
+                self.block_break(1);
+                self.walk_children(node);
+                self.block_break(1);
+            }
+            _ => self.walk_children(node),
+        }
+    }
+
+    fn heading(&mut self, node: &Node, name: &str) {
+        self.block_break(2);
+        let start = self.out.len();
+        self.walk_children(node);
+        let width = self.out[start..].trim().chars().count();
+        if width > 0 {
+            let underline = if name == "h1" { '=' } else { '-' };
+            self.out.truncate(start + self.out[start..].trim_end().len());
+            self.out.push('\n');
+            self.out.push_str(&underline.to_string().repeat(width));
+        }
+        self.block_break(2);
+    }
+
+    fn list(&mut self, node: &Node, kind: Option<usize>) {
+        self.block_break(if self.lists.is_empty() { 2 } else { 1 });
+        self.lists.push(kind);
+        self.walk_children(node);
+        self.lists.pop();
+        self.block_break(if self.lists.is_empty() { 2 } else { 1 });
+    }
+
+    fn list_item(&mut self, node: &Node) {
+        self.block_break(1);
+        let depth = self.lists.len().saturating_sub(1);
+        let marker = match self.lists.last_mut() {
+            Some(Some(number)) => {
+                *number += 1;
+                format!("{}. ", *number - 1)
+            }
+            _ => "* ".to_string(),
+        };
+        self.out.push_str(&"  ".repeat(depth));
+        self.out.push_str(&marker);
+        self.walk_children(node);
+        self.block_break(1);
+    }
+
+    fn link(&mut self, node: &Node) {
+        let start = self.out.len();
+        self.walk_children(node);
+        let href = match node.attr("href").map(str::trim) {
+            Some(href) if !href.is_empty() && !href.starts_with('#') => href,
+            _ => return,
+        };
+        let label = self.out[start..].trim();
+        let target = href.strip_prefix("mailto:").unwrap_or(href);
+        if label.is_empty() {
+            self.push_text(target);
+        } else if label != target {
+            let index = match self.links.iter().position(|link| link == href) {
+                Some(index) => index,
+                None => {
+                    self.links.push(href.to_string());
+                    self.links.len() - 1
+                }
+            };
+            self.out.push_str(&format!(" [{}]", index + 1));
+        }
+    }
+
+    fn push_text(&mut self, text: &str) {
+        let starts_with_space = text.starts_with(char::is_whitespace);
+        let ends_with_space = text.ends_with(char::is_whitespace);
+        let words: Vec<&str> = text.split_whitespace().collect();
+        if words.is_empty() {
+            self.pending_space |= !text.is_empty();
+            return;
+        }
+        if (self.pending_space || starts_with_space) && !self.at_line_start() {
+            self.out.push(' ');
+        }
+        self.out.push_str(&words.join(" "));
+        self.pending_space = ends_with_space;
+    }
+
+    fn line_break(&mut self) {
+        self.trim_trailing_spaces();
+        self.out.push('\n');
+        self.pending_space = false;
+    }
+
+    /// Ends the current line and makes sure at least `newlines` line breaks
+    /// separate it from what comes next.
+    fn block_break(&mut self, newlines: usize) {
+        self.trim_trailing_spaces();
+        self.pending_space = false;
+        if self.out.is_empty() {
+            return;
+        }
+        let existing = self.out.len() - self.out.trim_end_matches('\n').len();
+        for _ in existing..newlines {
+            self.out.push('\n');
+        }
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.out.is_empty() || self.out.ends_with('\n') || self.out.ends_with(' ')
+    }
+
+    fn trim_trailing_spaces(&mut self) {
+        let trimmed = self.out.trim_end_matches(' ').len();
+        self.out.truncate(trimmed);
+    }
+
+    fn finish(self) -> String {
+        let mut text = String::with_capacity(self.out.len());
+        let mut blank_lines = 0;
+        for line in self.out.lines().map(str::trim_end) {
+            if line.is_empty() {
+                blank_lines += 1;
+                if blank_lines > 1 {
+                    continue;
+                }
+            } else {
+                blank_lines = 0;
+            }
+            text.push_str(line);
+            text.push('\n');
+        }
+        let mut text = text.trim().to_string();
+        if !self.links.is_empty() {
+            text.push_str("\n\n");
+            for (index, href) in self.links.iter().enumerate() {
+                text.push_str(&format!("[{}] {}\n", index + 1, href));
+            }
+            text.truncate(text.trim_end().len());
+        }
+        text
+    }
+}
+
+/// True for elements hidden from readers, such as mrml's preheader `<div>`.
+fn is_hidden(node: &Node) -> bool {
+    if node.attr("hidden").is_some() || node.attr("aria-hidden") == Some("true") {
+        return true;
+    }
+    node.attr("style").is_some_and(|style| {
+        let style: String = style.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_ascii_lowercase();
+        style.split(';').any(|rule| rule == "display:none" || rule == "display:none!important" || rule == "mso-hide:all")
+    })
+}
@@ -1,16 +1,44 @@
-use std::{env, path::PathBuf};
-use tracing::{info, error, debug, Level};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::helpers::timezone::civil_from_days;
 
 // Helper function to get the relative path
-pub fn get_relative_path(template_dir: &PathBuf, path: &PathBuf) -> Result<String, Box<dyn std::error::Error>> {
+// `path` may be relative to the working directory (as notify reports paths
+// under a relative watch root) or absolute, possibly through a symlinked
+// template directory.
+pub fn get_relative_path(template_dir: &Path, path: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if let Ok(relative_path) = path.strip_prefix(template_dir) {
+        return Ok(relative_path.to_path_buf());
+    }
+
     let cwd = env::current_dir()?;
     let absolute_template_dir = if template_dir.is_relative() {
         cwd.join(template_dir)
     } else {
-        template_dir.clone()
+        template_dir.to_path_buf()
     };
+    if let Ok(relative_path) = path.strip_prefix(&absolute_template_dir) {
+        return Ok(relative_path.to_path_buf());
+    }
+
+    let canonical_template_dir = fs::canonicalize(&absolute_template_dir)?;
+    let relative_path = path.strip_prefix(&canonical_template_dir)?;
+    Ok(relative_path.to_path_buf())
+}
+
+/// Hex SHA-256 of a file's content, used for ETags.
+pub fn content_hash(content: &[u8]) -> String {
+    Sha256::digest(content).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
 
-    let relative_path = path.strip_prefix(&absolute_template_dir)?;
-    let relative_path_str = relative_path.to_string_lossy().to_string();
-    Ok(relative_path_str)
+/// `2024-05-01T09:30:00Z` for Unix seconds.
+pub fn format_utc(seconds: i64) -> String {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    let time = seconds.rem_euclid(86_400);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time / 60 % 60, time % 60)
 }
@@ -0,0 +1,183 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+use handlebars::{Handlebars, Template};
+use mrml::prelude::render::RenderOptions;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::app_state::{compile_template, AppState, CompiledTemplate};
+use crate::handlers::render_mjml;
+use crate::partials::partial_name;
+use crate::render_options::resolve_render_options;
+use crate::source_map::SourceLocation;
+use crate::template_id::TemplateId;
+
+/// Suffix of the sidecar payloads a template is checked against on upload:
+/// `welcome.mjml` renders each entry of `welcome.fixtures.json`.
+pub const FIXTURES_EXTENSION: &str = ".fixtures.json";
+
+/// How one uploaded template fared. It passes when it compiles and every
+/// fixture renders to valid MJML.
+#[derive(Debug, Serialize)]
+pub struct TemplateReport {
+    pub template: String,
+    pub ok: bool,
+    /// Why the Handlebars template didn't compile. Its fixtures aren't
+    /// rendered then.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// `upload`, `stored` or `example`: the fixtures sent with the template,
+    /// those beside it in the template directory, or the example payload
+    /// of `/variables` when there are neither. `None` for partials and
+    /// layouts, which only render inside templates. The example payload
+    /// holds blanks rather than real values, so a Handlebars error with it
+    /// is a `warning` and doesn't fail the template.
+    pub fixtures_from: Option<&'static str>,
+    pub fixtures: Vec<FixtureReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FixtureReport {
+    pub name: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Why the example payload couldn't be rendered, see
+    /// [`TemplateReport::fixtures_from`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+    /// Where in the template an MJML parse error comes from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<SourceLocation>,
+}
+
+/// Whether `id` holds fixtures rather than a template.
+pub fn is_fixtures(id: &TemplateId) -> bool {
+    id.as_str().ends_with(FIXTURES_EXTENSION)
+}
+
+/// Id of the sidecar fixtures of the template `id`.
+pub fn fixtures_id(id: &TemplateId) -> TemplateId {
+    let stem = id.as_str().strip_suffix(".mjml").unwrap_or(id.as_str());
+    TemplateId::new(&format!("{}{}", stem, FIXTURES_EXTENSION)).expect("a template id with a suffix is a valid id")
+}
+
+/// Parses a fixtures file: an object of payloads by fixture name.
+pub fn parse_fixtures(id: &TemplateId, content: &str) -> Result<Vec<(String, Value)>, String> {
+    match serde_json::from_str(content) {
+        Ok(Value::Object(fixtures)) => Ok(fixtures.into_iter().collect()),
+        Ok(_) => Err(format!("Invalid fixtures {}: expected an object of payloads by name", id)),
+        Err(e) => Err(format!("Invalid JSON in fixtures {}: {}", id, e)),
+    }
+}
+
+/// Loads the fixtures stored beside the template `id`, `None` if it has none.
+fn load_fixtures(template_dir: &Path, id: &TemplateId) -> Result<Option<Vec<(String, Value)>>, String> {
+    let fixtures_id = fixtures_id(id);
+    let path = fixtures_id.resolve_in(template_dir)?;
+    match fs::read_to_string(&path) {
+        Ok(content) => parse_fixtures(&fixtures_id, &content).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Failed to read fixtures {}: {}", fixtures_id, e)),
+    }
+}
+
+/// Checks the files of an upload in two stages: every template, partial
+/// and layout must compile, then every template must render each of its
+/// fixtures to MJML that mrml parses and renders. Templates render against
+/// the partials of the upload, as they will once it is written. Fixtures
+/// uploaded without their template check the stored one.
+pub fn check_upload(app_state: &AppState, files: &[(TemplateId, String)]) -> Result<Vec<TemplateReport>, String> {
+    let mut uploaded_fixtures = HashMap::new();
+    for (id, content) in files.iter().filter(|(id, _)| is_fixtures(id)) {
+        uploaded_fixtures.insert(id.clone(), parse_fixtures(id, content)?);
+    }
+    let mut sources: Vec<(TemplateId, String)> = files.iter().filter(|(id, _)| !is_fixtures(id)).cloned().collect();
+    for fixtures_id in uploaded_fixtures.keys() {
+        let stem = fixtures_id.as_str().strip_suffix(FIXTURES_EXTENSION).unwrap_or_default();
+        let Ok(id) = TemplateId::new(&format!("{}.mjml", stem)) else { continue };
+        if sources.iter().any(|(uploaded, _)| *uploaded == id) {
+            continue;
+        }
+        // Fixtures may come ahead of their template.
+        let Ok(path) = id.resolve_in(&app_state.template_dir) else { continue };
+        if let Ok(source) = fs::read_to_string(path) {
+            sources.push((id, source));
+        }
+    }
+
+    let default_options = resolve_render_options(&app_state.config.render_options, None);
+    let mut handlebars = app_state.handlebars.read().unwrap().clone();
+    let mut reports = Vec::new();
+    let mut compiled = Vec::new();
+    for (id, source) in &sources {
+        let mut report = TemplateReport { template: id.to_string(), ok: true, error: None, fixtures_from: None, fixtures: Vec::new() };
+        let outcome = match partial_name(id) {
+            Some(name) => Template::compile(source)
+                .map(|mut template| {
+                    template.name = Some(id.to_string());
+                    handlebars.register_template(name, template);
+                })
+                .map_err(|e| format!("Handlebars template error in {}: {}", id, e)),
+            None => compile_template(id.as_str(), source, &default_options, app_state.includes.parser_options(None))
+                .map(|template| compiled.push((reports.len(), id, template))),
+        };
+        if let Err(e) = outcome {
+            report.ok = false;
+            report.error = Some(e);
+        }
+        reports.push(report);
+    }
+
+    for (index, id, template) in compiled {
+        let (fixtures_from, fixtures) = match uploaded_fixtures.remove(&fixtures_id(id)) {
+            Some(fixtures) => ("upload", fixtures),
+            None => match load_fixtures(&app_state.template_dir, id)? {
+                Some(fixtures) => ("stored", fixtures),
+                None => ("example", vec![("example".to_string(), template.variables(&handlebars).example)]),
+            },
+        };
+        let report = &mut reports[index];
+        report.fixtures_from = Some(fixtures_from);
+        let example = fixtures_from == "example";
+        for (name, payload) in fixtures {
+            let fixture = render_fixture(app_state, &handlebars, &template, name, &payload, &default_options, example);
+            report.ok &= fixture.ok;
+            report.fixtures.push(fixture);
+        }
+    }
+    Ok(reports)
+}
+
+fn render_fixture(
+    app_state: &AppState,
+    handlebars: &Handlebars<'static>,
+    template: &CompiledTemplate,
+    name: String,
+    payload: &Value,
+    render_options: &RenderOptions,
+    example: bool,
+) -> FixtureReport {
+    let mut report = FixtureReport { name, ok: false, error: None, warning: None, location: None };
+    let mjml = match template.render_handlebars(handlebars, payload) {
+        Ok(mjml) => mjml,
+        // Helpers may well refuse the blanks of the example payload.
+        Err(e) if example => {
+            report.ok = true;
+            report.warning = Some(format!("Not checked, the example payload doesn't render: {}. Upload fixtures to check it", e));
+            return report;
+        }
+        Err(e) => {
+            report.error = Some(format!("Handlebars rendering error: {}", e));
+            return report;
+        }
+    };
+    match render_mjml(&mjml, &template.parser_options, render_options) {
+        Ok(_) => report.ok = true,
+        Err((message, offset)) => {
+            report.error = Some(message);
+            report.location = offset.and_then(|offset| template.locate(handlebars, &app_state.template_dir, payload, offset));
+        }
+    }
+    report
+}
@@ -0,0 +1,359 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use handlebars::{
+    template::{BlockParam, DecoratorTemplate, HelperTemplate, Parameter, TemplateElement},
+    Handlebars, Path, Template,
+};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+/// The payload a template reads, found by walking its Handlebars AST and
+/// the partials it uses.
+#[derive(Serialize)]
+pub struct TemplateVariables {
+    /// Every path referenced, like `user.name` or `items[].sku`, where `[]`
+    /// stands for the elements of an array.
+    pub variables: Vec<String>,
+    /// A payload with every path filled in with a placeholder.
+    pub example: Value,
+    /// A JSON Schema for the payload, to be refined by hand.
+    pub schema: Value,
+}
+
+/// How a path is used. A path used several ways keeps the strongest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Usage {
+    /// Tested by `#if` or `#unless`.
+    Condition,
+    /// Passed to a helper.
+    Argument,
+    /// Made the context of a `#with`.
+    Scope,
+    /// Looped over by `#each`.
+    Each,
+    /// Output by `{{value}}`.
+    Value,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Step {
+    Key(String),
+    /// Any element of an array.
+    Item,
+}
+
+#[derive(Default)]
+struct Node {
+    usage: Option<Usage>,
+    children: BTreeMap<String, Node>,
+    items: Option<Box<Node>>,
+}
+
+/// A context a block renders its content in.
+#[derive(Default)]
+struct Scope {
+    base: Vec<Step>,
+    /// Block params and partial hash arguments, by name. `None` for those
+    /// that aren't payload, like the index of `#each`.
+    names: HashMap<String, Option<Vec<Step>>>,
+}
+
+struct Walker<'a> {
+    handlebars: &'a Handlebars<'static>,
+    root: Node,
+    scopes: Vec<Scope>,
+    /// Partials being walked, so recursive partials end.
+    partials: HashSet<String>,
+}
+
+/// Collects the variables `template` and the partials of `handlebars` it
+/// uses reference.
+pub fn template_variables(handlebars: &Handlebars<'static>, template: &Template) -> TemplateVariables {
+    let mut walker = Walker { handlebars, root: Node::default(), scopes: vec![Scope::default()], partials: HashSet::new() };
+    walker.template(template);
+
+    let mut variables = Vec::new();
+    collect_paths(&walker.root, String::new(), &mut variables);
+    let mut schema = object_schema(&walker.root);
+    if let Value::Object(schema) = &mut schema {
+        schema.insert("$schema".to_string(), json!("https://json-schema.org/draft/2020-12/schema"));
+    }
+    TemplateVariables { variables, example: node_example(&walker.root), schema }
+}
+
+impl Walker<'_> {
+    fn template(&mut self, template: &Template) {
+        for element in &template.elements {
+            self.element(element);
+        }
+    }
+
+    fn element(&mut self, element: &TemplateElement) {
+        match element {
+            TemplateElement::Expression(helper) | TemplateElement::HtmlExpression(helper) => {
+                if helper.params.is_empty() && helper.hash.is_empty() {
+                    self.reference(&helper.name, Usage::Value);
+                } else {
+                    self.arguments(helper.params.iter().chain(helper.hash.values()), Usage::Argument);
+                }
+            }
+            TemplateElement::HelperBlock(helper) => self.block(helper),
+            TemplateElement::PartialExpression(partial) | TemplateElement::PartialBlock(partial) => self.partial(partial),
+            TemplateElement::DecoratorExpression(decorator) | TemplateElement::DecoratorBlock(decorator) => {
+                self.arguments(decorator.params.iter().chain(decorator.hash.values()), Usage::Argument);
+                if let Some(inner) = &decorator.template {
+                    self.template(inner);
+                }
+            }
+            TemplateElement::RawString(_) | TemplateElement::Comment(_) => {}
+        }
+    }
+
+    fn block(&mut self, helper: &HelperTemplate) {
+        let name = helper.name.as_name().unwrap_or_default();
+        let (usage, step) = match name {
+            "each" => (Usage::Each, Some(Step::Item)),
+            "with" => (Usage::Scope, None),
+            "if" | "unless" => (Usage::Condition, None),
+            _ => (Usage::Argument, None),
+        };
+        let context = match name {
+            "each" | "with" => helper.params.first().and_then(|param| self.reference(param, usage)),
+            _ => {
+                self.arguments(helper.params.iter(), usage);
+                None
+            }
+        };
+        self.arguments(helper.hash.values(), Usage::Argument);
+
+        match context {
+            Some(mut base) => {
+                base.extend(step);
+                let mut scope = Scope { base: base.clone(), names: HashMap::new() };
+                // `as |item index|` names the element; the index isn't payload.
+                let (first, second) = match &helper.block_param {
+                    Some(BlockParam::Single(param)) => (param.as_name(), None),
+                    Some(BlockParam::Pair((param, index))) => (param.as_name(), index.as_name()),
+                    None => (None, None),
+                };
+                if let Some(first) = first {
+                    scope.names.insert(first.to_string(), Some(base));
+                }
+                if let Some(second) = second {
+                    scope.names.insert(second.to_string(), None);
+                }
+                if let Some(inner) = &helper.template {
+                    self.scopes.push(scope);
+                    self.template(inner);
+                    self.scopes.pop();
+                }
+            }
+            None => {
+                if let Some(inner) = &helper.template {
+                    self.template(inner);
+                }
+            }
+        }
+        // `{{else}}` renders in the outer context.
+        if let Some(inverse) = &helper.inverse {
+            self.template(inverse);
+        }
+    }
+
+    fn partial(&mut self, partial: &DecoratorTemplate) {
+        // The content of a partial block renders where the partial calls
+        // `{{> @partial-block}}`, close enough to here.
+        if let Some(inner) = &partial.template {
+            self.template(inner);
+        }
+        let context = partial.params.first().map(|param| self.reference(param, Usage::Scope));
+        let mut scope = Scope {
+            base: match context {
+                Some(Some(base)) => base,
+                // A literal context has no payload paths.
+                Some(None) => return,
+                None => self.scopes.last().map(|scope| scope.base.clone()).unwrap_or_default(),
+            },
+            names: HashMap::new(),
+        };
+        for (key, value) in &partial.hash {
+            let path = self.reference(value, Usage::Argument);
+            scope.names.insert(key.clone(), path);
+        }
+
+        let Some(name) = partial.name.as_name().filter(|name| !name.starts_with('@')) else { return };
+        let Some(template) = self.handlebars.get_template(name) else { return };
+        if !self.partials.insert(name.to_string()) {
+            return;
+        }
+        self.scopes.push(scope);
+        self.template(template);
+        self.scopes.pop();
+        self.partials.remove(name);
+    }
+
+    fn arguments<'p>(&mut self, params: impl Iterator<Item = &'p Parameter>, usage: Usage) {
+        for param in params {
+            match param {
+                Parameter::Subexpression(subexpression) => self.element(&subexpression.element),
+                _ => {
+                    self.reference(param, usage);
+                }
+            }
+        }
+    }
+
+    /// Records the payload path `param` refers to, returning it.
+    fn reference(&mut self, param: &Parameter, usage: Usage) -> Option<Vec<Step>> {
+        let raw = match param {
+            Parameter::Name(name) => name.as_str(),
+            Parameter::Path(Path::Relative((_, raw))) => raw.as_str(),
+            Parameter::Subexpression(subexpression) => {
+                self.element(&subexpression.element);
+                return None;
+            }
+            // `@index`, `@key` and the like.
+            Parameter::Path(Path::Local(_)) | Parameter::Literal(_) => return None,
+        };
+        let path = self.resolve(raw)?;
+        if !path.is_empty() {
+            self.root.touch(&path, usage);
+        }
+        Some(path)
+    }
+
+    /// The payload path of a Handlebars path in the current scope, `None`
+    /// for data variables.
+    fn resolve(&self, raw: &str) -> Option<Vec<Step>> {
+        let mut segments = split_path(raw).into_iter().peekable();
+        let mut level = self.scopes.len() - 1;
+        let mut path = match segments.peek().map(String::as_str) {
+            Some("@root") => {
+                segments.next();
+                Vec::new()
+            }
+            Some(data) if data.starts_with('@') => return None,
+            Some("..") => {
+                while segments.next_if(|segment| segment == "..").is_some() {
+                    level = level.saturating_sub(1);
+                }
+                self.scopes[level].base.clone()
+            }
+            Some("this") | Some(".") => {
+                segments.next();
+                self.scopes[level].base.clone()
+            }
+            Some(first) => match self.scopes[..=level].iter().rev().find_map(|scope| scope.names.get(first)) {
+                Some(named) => {
+                    let named = named.clone()?;
+                    segments.next();
+                    named
+                }
+                None => self.scopes[level].base.clone(),
+            },
+            None => return None,
+        };
+        for segment in segments {
+            if segment.parse::<usize>().is_ok() {
+                path.push(Step::Item);
+            } else if segment != "this" && segment != "." {
+                path.push(Step::Key(segment));
+            }
+        }
+        Some(path)
+    }
+}
+
+/// Splits `a.b/[c d].0` into `a`, `b`, `c d` and `0`, keeping `..` and
+/// `@root` as segments.
+fn split_path(raw: &str) -> Vec<String> {
+    let mut segments = Vec::new();
+    let mut rest = raw;
+    while !rest.is_empty() {
+        if let Some(inner) = rest.strip_prefix('[') {
+            let end = inner.find(']').unwrap_or(inner.len());
+            segments.push(inner[..end].to_string());
+            rest = inner.get(end + 1..).unwrap_or_default();
+        } else if let Some(after) = rest.strip_prefix("..") {
+            segments.push("..".to_string());
+            rest = after;
+        } else {
+            let end = rest.find(['.', '/']).unwrap_or(rest.len());
+            if end > 0 {
+                segments.push(rest[..end].to_string());
+            }
+            rest = &rest[end..];
+        }
+        rest = rest.strip_prefix(['.', '/']).unwrap_or(rest);
+    }
+    segments
+}
+
+impl Node {
+    fn touch(&mut self, path: &[Step], usage: Usage) {
+        let Some((step, rest)) = path.split_first() else {
+            self.usage = self.usage.max(Some(usage));
+            return;
+        };
+        let child = match step {
+            Step::Key(key) => self.children.entry(key.clone()).or_default(),
+            Step::Item => self.items.get_or_insert_with(Default::default),
+        };
+        child.touch(rest, usage);
+    }
+}
+
+fn collect_paths(node: &Node, path: String, paths: &mut Vec<String>) {
+    if node.usage.is_some() {
+        paths.push(path.clone());
+    }
+    if let Some(items) = &node.items {
+        collect_paths(items, format!("{}[]", path), paths);
+    }
+    for (key, child) in &node.children {
+        let child_path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+        collect_paths(child, child_path, paths);
+    }
+}
+
+fn node_example(node: &Node) -> Value {
+    if let Some(items) = &node.items {
+        return Value::Array(vec![node_example(items)]);
+    }
+    if !node.children.is_empty() {
+        return Value::Object(node.children.iter().map(|(key, child)| (key.clone(), node_example(child))).collect());
+    }
+    match node.usage {
+        Some(Usage::Condition) => Value::Bool(true),
+        Some(Usage::Each) => Value::Array(Vec::new()),
+        Some(Usage::Scope) => Value::Object(Map::new()),
+        Some(Usage::Argument) | Some(Usage::Value) | None => Value::String(String::new()),
+    }
+}
+
+fn node_schema(node: &Node) -> Value {
+    if node.items.is_some() || node.usage == Some(Usage::Each) {
+        let items = node.items.as_deref().map_or_else(|| json!({}), node_schema);
+        return json!({"type": "array", "items": items});
+    }
+    if !node.children.is_empty() || node.usage == Some(Usage::Scope) {
+        return object_schema(node);
+    }
+    match node.usage {
+        Some(Usage::Condition) => json!({"type": "boolean"}),
+        Some(Usage::Value) => json!({"type": ["string", "number"]}),
+        _ => json!({}),
+    }
+}
+
+fn object_schema(node: &Node) -> Value {
+    let properties: Map<String, Value> = node.children.iter().map(|(key, child)| (key.clone(), node_schema(child))).collect();
+    // Only values that are merely tested may be left out.
+    let required: Vec<&String> = node
+        .children
+        .iter()
+        .filter(|(_, child)| child.usage != Some(Usage::Condition) || !child.children.is_empty() || child.items.is_some())
+        .map(|(key, _)| key)
+        .collect();
+    json!({"type": "object", "properties": properties, "required": required})
+}